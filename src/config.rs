@@ -6,6 +6,7 @@
 //==============================================================================
 // Crates and Mods
 //==============================================================================
+use crate::mcu::power::PowerMode;
 
 
 //==============================================================================
@@ -19,5 +20,10 @@
 // Clocks
 pub const EXTERNAL_HIGH_SPEED: bool = true;
 pub const HIGH_SPEED_CLOCK: u32 = 8_000_000;	// Fixed at 8MHz - ST-Link MCU
+pub const HIGH_SPEED_BYPASS: bool = true;		// MCO drives OSC_IN directly, no crystal
+pub const SYSTEM_CLOCK: u32 = 72_000_000;
 pub const EXTERNAL_LOW_SPEED: bool = true;
 pub const LOW_SPEED_CLOCK: u32 = 32_768;		// Fixed at 8MHz - ST-Link MCU
+
+// Power
+pub const LOW_POWER_MODE: PowerMode = PowerMode::Run;	// Deepest mode idle() may enter
@@ -0,0 +1,294 @@
+//==============================================================================
+// Notes
+//==============================================================================
+// drivers/log.rs
+
+/*
+ * Log lines over a USART. The macros format a whole line, tick timestamp,
+ * level and module tag included, on the stack and queue it in one go, so
+ * they can be used from interrupts and lines never interleave. Nothing
+ * blocks: a line that doesn't fit in the queue is dropped and counted, and a
+ * line past LINE_SIZE is cut short. task_handler() moves the queue into the
+ * TX buffer of the output USART, which has to have been started.
+ *
+ *     crate::info!("clock at {} Hz", hz);
+ *     crate::log!(LogLevel::Debug, "raw {:02x}", byte);
+ *
+ * The tag is the module path without the crate name, e.g. "mcu::uart". Lines
+ * are filtered on the level set for the longest matching tag prefix, or the
+ * default level. Logger writes unformatted text into the same queue.
+ */
+
+//==============================================================================
+// Crates and Mods
+//==============================================================================
+use core::cell::{Cell, RefCell};
+use core::fmt::{self, Write};
+use cortex_m::interrupt::{free, Mutex};
+
+use crate::mcu::power::{self, PowerClient, PowerMode};
+use crate::mcu::timer;
+use crate::mcu::uart;
+
+//==============================================================================
+// Enums, Structs, and Types
+//==============================================================================
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum LogLevel {
+	Error,
+	Warn,
+	Info,
+	Debug,
+	Trace
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogError {
+	FilterTableFull
+}
+
+// Raw text into the log queue, e.g. write!(Logger, ...)
+#[allow(dead_code)]
+pub struct Logger;
+
+impl Write for Logger {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		if free(|cs| QUEUE.borrow(cs).borrow_mut().push(s.as_bytes())) { Ok(()) } else {
+			count_dropped();
+			Err(fmt::Error)
+		}
+	}
+}
+
+#[derive(Clone, Copy)]
+struct Filter {
+	prefix: &'static str,
+	level: LogLevel
+}
+
+// One line being formatted, anything past the end is dropped, on a character
+// boundary so the line stays valid UTF-8
+struct Line {
+	data: [u8; LINE_SIZE],
+	len: usize,
+	cut: bool
+}
+
+impl Write for Line {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		if self.cut {
+			return Ok(());
+		}
+
+		let mut count = s.len().min(LINE_SIZE - LINE_END.len() - self.len);
+		while !s.is_char_boundary(count) {
+			count -= 1;
+		}
+		self.data[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+		self.len += count;
+		self.cut = count < s.len();
+		Ok(())
+	}
+}
+
+struct Queue {
+	data: [u8; QUEUE_SIZE],
+	head: usize,
+	len: usize
+}
+
+impl Queue {
+	const fn new() -> Self {
+		Queue { data: [0; QUEUE_SIZE], head: 0, len: 0 }
+	}
+
+	// All of it or nothing
+	fn push(&mut self, bytes: &[u8]) -> bool {
+		if QUEUE_SIZE - self.len < bytes.len() {
+			return false;
+		}
+
+		for &byte in bytes {
+			self.data[(self.head + self.len) % QUEUE_SIZE] = byte;
+			self.len += 1;
+		}
+		power::set_mode_limit(PowerClient::Log, PowerMode::Sleep);
+		true
+	}
+
+	// The run up to the end of the buffer, so it can go out in one piece
+	fn peek(&self) -> &[u8] {
+		let end = (self.head + self.len).min(QUEUE_SIZE);
+		&self.data[self.head..end]
+	}
+
+	fn consume(&mut self, count: usize) {
+		self.head = (self.head + count) % QUEUE_SIZE;
+		self.len -= count;
+	}
+}
+
+//==============================================================================
+// Variables
+//==============================================================================
+pub const LINE_SIZE: usize = 128;
+const QUEUE_SIZE: usize = 1024;
+const FILTER_COUNT: usize = 8;
+const LINE_END: &[u8] = b"\r\n";
+
+static QUEUE: Mutex<RefCell<Queue>> = Mutex::new(RefCell::new(Queue::new()));
+static OUTPUT: Mutex<Cell<Option<uart::Usart>>> = Mutex::new(Cell::new(None));
+static DEFAULT_LEVEL: Mutex<Cell<LogLevel>> = Mutex::new(Cell::new(LogLevel::Info));
+static FILTERS: Mutex<Cell<[Option<Filter>; FILTER_COUNT]>> = Mutex::new(Cell::new([None; FILTER_COUNT]));
+static DROPPED: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+//==============================================================================
+// Public Functions
+//==============================================================================
+#[macro_export]
+macro_rules! log {
+	($level:expr, $($arg:tt)+) => {
+		$crate::drivers::log::write_line($level, module_path!(), format_args!($($arg)+))
+	};
+}
+
+#[macro_export]
+macro_rules! error {
+	($($arg:tt)+) => { $crate::log!($crate::drivers::log::LogLevel::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+	($($arg:tt)+) => { $crate::log!($crate::drivers::log::LogLevel::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+	($($arg:tt)+) => { $crate::log!($crate::drivers::log::LogLevel::Info, $($arg)+) };
+}
+
+// Queued lines wait until there is an output
+#[allow(dead_code)]
+pub fn set_output(usart: Option<uart::Usart>) {
+	free(|cs| OUTPUT.borrow(cs).set(usart));
+}
+
+// For tags no filter matches
+#[allow(dead_code)]
+pub fn set_level(level: LogLevel) {
+	free(|cs| DEFAULT_LEVEL.borrow(cs).set(level));
+}
+
+// Replaces the level of an existing prefix
+#[allow(dead_code)]
+pub fn set_module_level(prefix: &'static str, level: LogLevel) -> Result<(), LogError> {
+	free(|cs| {
+		let mut filters = FILTERS.borrow(cs).get();
+		let slot = filters.iter().position(|filter| filter.is_some_and(|filter| filter.prefix == prefix))
+			.or_else(|| filters.iter().position(|filter| filter.is_none()))
+			.ok_or(LogError::FilterTableFull)?;
+
+		filters[slot] = Some(Filter { prefix, level });
+		FILTERS.borrow(cs).set(filters);
+		Ok(())
+	})
+}
+
+#[allow(dead_code)]
+pub fn clear_module_level(prefix: &'static str) {
+	free(|cs| {
+		let mut filters = FILTERS.borrow(cs).get();
+		for filter in filters.iter_mut().filter(|filter| filter.is_some_and(|filter| filter.prefix == prefix)) {
+			*filter = None;
+		}
+		FILTERS.borrow(cs).set(filters);
+	});
+}
+
+#[allow(dead_code)]
+pub fn is_enabled(level: LogLevel, tag: &str) -> bool {
+	free(|cs| {
+		let filters = FILTERS.borrow(cs).get();
+		let filter = filters.iter().flatten()
+			.filter(|filter| tag.starts_with(filter.prefix))
+			.max_by_key(|filter| filter.prefix.len());
+		level <= filter.map_or(DEFAULT_LEVEL.borrow(cs).get(), |filter| filter.level)
+	})
+}
+
+// Lines lost to a full queue since the last call. Logger has no lines, it
+// counts each write_str() that didn't fit, and write!() stops at the first.
+#[allow(dead_code)]
+pub fn take_dropped() -> u32 {
+	free(|cs| DROPPED.borrow(cs).replace(0))
+}
+
+// Behind the macros, `module` is module_path!()
+#[allow(dead_code)]
+pub fn write_line(level: LogLevel, module: &'static str, args: fmt::Arguments) {
+	let tag = module.split_once("::").map_or(module, |(_, tag)| tag);
+	if !is_enabled(level, tag) {
+		return;
+	}
+
+	let tick = timer::get_tick();
+	let mut line = Line { data: [0; LINE_SIZE], len: 0, cut: false };
+	let _ = write!(line, "[{:>6}.{:03}] {} {}: ", tick / 1000, tick % 1000, level_name(level), tag);
+	let _ = line.write_fmt(args);
+	line.data[line.len..line.len + LINE_END.len()].copy_from_slice(LINE_END);
+	line.len += LINE_END.len();
+
+	if !free(|cs| QUEUE.borrow(cs).borrow_mut().push(&line.data[..line.len])) {
+		count_dropped();
+	}
+}
+
+//==============================================================================
+// Private Functions
+//==============================================================================
+fn level_name(level: LogLevel) -> &'static str {
+	match level {
+		LogLevel::Error => "ERROR",
+		LogLevel::Warn => "WARN ",
+		LogLevel::Info => "INFO ",
+		LogLevel::Debug => "DEBUG",
+		LogLevel::Trace => "TRACE",
+	}
+}
+
+fn count_dropped() {
+	free(|cs| {
+		let dropped = DROPPED.borrow(cs).get();
+		DROPPED.borrow(cs).set(dropped.saturating_add(1));
+	});
+}
+
+//==============================================================================
+// Task Handler
+//==============================================================================
+// As much of the queue as the output's TX buffer takes, the power vote goes
+// back once the queue is empty
+pub fn task_handler() {
+	free(|cs| if QUEUE.borrow(cs).borrow().len == 0 {
+		power::set_mode_limit(PowerClient::Log, PowerMode::Standby);
+	});
+
+	let usart = match free(|cs| OUTPUT.borrow(cs).get()) {
+		Some(usart) if uart::is_started(usart) => usart,
+		_ => return,
+	};
+
+	loop {
+		let written = free(|cs| {
+			let mut queue = QUEUE.borrow(cs).borrow_mut();
+			let written = uart::write(usart, queue.peek());
+			queue.consume(written);
+			written
+		});
+		if written == 0 {
+			break;
+		}
+	}
+}
@@ -6,6 +6,9 @@
 //==============================================================================
 // Crates and Mods
 //==============================================================================
+use crate::mcu::gpio;
+
+pub mod log;
 
 
 //==============================================================================
@@ -21,8 +24,12 @@
 //==============================================================================
 // Public Functions
 //==============================================================================
-pub fn init() {
+// Board level pin assignments are made here, unclaimed pins are dropped and
+// stay in their reset state
+pub fn init(
+	pins: gpio::Pins) {
 
+	let _ = pins;
 }
 
 //==============================================================================
@@ -34,5 +41,5 @@ pub fn init() {
 // Task Handler
 //==============================================================================
 pub fn task_handler() {
-
+	log::task_handler();
 }
@@ -36,6 +36,7 @@ fn main() -> ! {
 	
 	loop {
 		task_handler();
+		mcu::power::idle();
 	};
 }
 
@@ -43,8 +44,8 @@ fn main() -> ! {
 // Private Functions
 //==============================================================================
 fn init() {
-	mcu::init();
-	drivers::init();
+	let pins = mcu::init();
+	drivers::init(pins);
 }
 
 //==============================================================================
@@ -3,18 +3,43 @@
 //==============================================================================
 // mcu/adc.rs
 
+/*
+ * One-shot conversions only: each read_channel() runs a single regular
+ * conversion and polls EOC. An ADC is brought up (regulator, calibration,
+ * ADEN) on its first read. Without an ADC prescaler set in RCC_CFGR2 the
+ * pairs run synchronously off HCLK/2.
+ */
+
 //==============================================================================
 // Crates and Mods
 //==============================================================================
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 // use core::ops::DerefMut;
 use cortex_m::interrupt::{free, Mutex};
 use stm32f3::stm32f303;
+use stm32f3::stm32f303::adc1::RegisterBlock;
+
+use crate::mcu::clocks;
+use crate::mcu::timer;
 
 //==============================================================================
 // Enums, Structs, and Types
 //==============================================================================
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Adc {
+	Adc1,
+	Adc2,
+	Adc3,
+	Adc4
+}
 
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AdcError {
+	InvalidChannel,
+	Timeout
+}
 
 //==============================================================================
 // Variables
@@ -27,6 +52,20 @@ static ADC3_HANDLE: Mutex<RefCell<Option<stm32f303::ADC3>>> =
 	Mutex::new(RefCell::new(None));
 static ADC4_HANDLE: Mutex<RefCell<Option<stm32f303::ADC4>>> = 
 	Mutex::new(RefCell::new(None));
+static ADC12_COMMON_HANDLE: Mutex<RefCell<Option<stm32f303::ADC1_2>>> = 
+	Mutex::new(RefCell::new(None));
+static ADC34_COMMON_HANDLE: Mutex<RefCell<Option<stm32f303::ADC3_4>>> = 
+	Mutex::new(RefCell::new(None));
+
+// Bit per Adc, set once it has been calibrated and enabled
+static ADC_READY: Mutex<Cell<u8>> = Mutex::new(Cell::new(0));
+
+// Channels 1-18, 0 is not a regular channel on the F303
+const MAX_CHANNEL: u8 = 18;
+// 601.5 cycles, long enough for any source impedance on the board
+const SAMPLE_TIME: u32 = 0b111;
+const REGULATOR_STARTUP_US: u32 = 10;
+const ADC_TIMEOUT: u32 = 100_000;
 
 //==============================================================================
 // Public Functions
@@ -35,17 +74,112 @@ pub fn init(
 	adc1: stm32f303::ADC1,
 	adc2: stm32f303::ADC2,
 	adc3: stm32f303::ADC3,
-	adc4: stm32f303::ADC4) {
+	adc4: stm32f303::ADC4,
+	adc12_common: stm32f303::ADC1_2,
+	adc34_common: stm32f303::ADC3_4) {
 	
 	free(|cs| ADC1_HANDLE.borrow(cs).replace(Some(adc1)));
 	free(|cs| ADC2_HANDLE.borrow(cs).replace(Some(adc2)));
 	free(|cs| ADC3_HANDLE.borrow(cs).replace(Some(adc3)));
 	free(|cs| ADC4_HANDLE.borrow(cs).replace(Some(adc4)));
+	free(|cs| ADC12_COMMON_HANDLE.borrow(cs).replace(Some(adc12_common)));
+	free(|cs| ADC34_COMMON_HANDLE.borrow(cs).replace(Some(adc34_common)));
+}
+
+// Runs a single 12 bit conversion of `channel` and waits for it
+#[allow(dead_code)]
+pub fn read_channel(adc: Adc, channel: u8) -> Result<u16, AdcError> {
+	if channel == 0 || channel > MAX_CHANNEL {
+		return Err(AdcError::InvalidChannel);
+	}
+
+	if free(|cs| ADC_READY.borrow(cs).get()) & (1 << adc as u8) == 0 {
+		start(adc)?;
+	}
+
+	with_adc(adc, |regs| {
+		let shift = (channel as u32 % 10) * 3;
+		if channel < 10 {
+			regs.smpr1.modify(|r, w| unsafe { w.bits((r.bits() & !(0b111 << shift)) | (SAMPLE_TIME << shift)) });
+		}
+		else {
+			regs.smpr2.modify(|r, w| unsafe { w.bits((r.bits() & !(0b111 << shift)) | (SAMPLE_TIME << shift)) });
+		}
+		regs.sqr1.write(|w| unsafe { w.l().bits(0).sq1().bits(channel) });
+		regs.isr.write(|w| w.eoc().set_bit());
+		regs.cr.modify(|_, w| w.adstart().start());
+	}).ok_or(AdcError::Timeout)?;
+
+	for _ in 0..ADC_TIMEOUT {
+		if let Some(Some(value)) = with_adc(adc, |regs| if regs.isr.read().eoc().bit_is_set() {
+			Some(regs.dr.read().rdata().bits())
+		} else { None }) {
+			return Ok(value);
+		}
+	}
+
+	Err(AdcError::Timeout)
 }
 
 //==============================================================================
 // Private Functions
 //==============================================================================
+// Regulator, calibration then ADEN, RM0316 15.3.6-15.3.9
+fn start(adc: Adc) -> Result<(), AdcError> {
+	let (peripheral, asynchronous) = match adc {
+		Adc::Adc1 | Adc::Adc2 => (clocks::AhbPeripherals::ADC12, clocks::get_clocks().adc12clk() != 0),
+		Adc::Adc3 | Adc::Adc4 => (clocks::AhbPeripherals::ADC34, clocks::get_clocks().adc34clk() != 0),
+	};
+
+	clocks::set_ahb_peripheral_clock_enable(peripheral, true);
+	free(|cs| {
+		let set_clock = |common: &stm32f303::adc1_2::RegisterBlock| common.ccr.modify(|_, w| {
+			if asynchronous { w.ckmode().asynchronous() } else { w.ckmode().sync_div2() }
+		});
+		match adc {
+			Adc::Adc1 | Adc::Adc2 => if let Some(common) = ADC12_COMMON_HANDLE.borrow(cs).borrow().as_ref() { set_clock(common) },
+			Adc::Adc3 | Adc::Adc4 => if let Some(common) = ADC34_COMMON_HANDLE.borrow(cs).borrow().as_ref() { set_clock(common) },
+		}
+	});
+
+	with_adc(adc, |regs| {
+		regs.cr.modify(|_, w| w.advregen().intermediate());
+		regs.cr.modify(|_, w| w.advregen().enabled());
+	}).ok_or(AdcError::Timeout)?;
+	timer::delay_us(REGULATOR_STARTUP_US);
+
+	with_adc(adc, |regs| regs.cr.modify(|_, w| w.adcal().calibration()));
+	wait(adc, |regs| regs.cr.read().adcal().bit_is_clear())?;
+
+	with_adc(adc, |regs| regs.cr.modify(|_, w| w.aden().enable()));
+	wait(adc, |regs| regs.isr.read().adrdy().bit_is_set())?;
+
+	free(|cs| {
+		let ready = ADC_READY.borrow(cs);
+		ready.set(ready.get() | (1 << adc as u8));
+	});
+	Ok(())
+}
+
+fn wait(adc: Adc, done: impl Fn(&RegisterBlock) -> bool) -> Result<(), AdcError> {
+	for _ in 0..ADC_TIMEOUT {
+		if with_adc(adc, &done).unwrap_or(false) {
+			return Ok(());
+		}
+	}
+
+	Err(AdcError::Timeout)
+}
+
+// All four share the ADC1 register layout
+fn with_adc<R>(adc: Adc, f: impl FnOnce(&RegisterBlock) -> R) -> Option<R> {
+	free(|cs| match adc {
+		Adc::Adc1 => ADC1_HANDLE.borrow(cs).borrow().as_ref().map(|regs| f(regs)),
+		Adc::Adc2 => ADC2_HANDLE.borrow(cs).borrow().as_ref().map(|regs| f(regs)),
+		Adc::Adc3 => ADC3_HANDLE.borrow(cs).borrow().as_ref().map(|regs| f(regs)),
+		Adc::Adc4 => ADC4_HANDLE.borrow(cs).borrow().as_ref().map(|regs| f(regs)),
+	})
+}
 
 
 //==============================================================================
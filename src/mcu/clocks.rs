@@ -8,15 +8,24 @@
 //==============================================================================
 use core::cell::{Cell, RefCell};
 use core::ops::DerefMut;
+use core::sync::atomic::{AtomicBool, Ordering};
 use cortex_m::interrupt::{free, Mutex};
+use cortex_m_rt::exception;
 use stm32f3::stm32f303;
 
 use crate::config;
+use crate::mcu::flash;
+use crate::mcu::gpio;
+use crate::mcu::pinmux::{self, PinmuxError, Signal};
+use crate::mcu::timer;
+use crate::mcu::uart;
 
 //==============================================================================
 // Enums, Structs, and Types
 //==============================================================================
 #[allow(dead_code)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum AhbPeripherals {
 	DMA1 = 		0x0000_0001,
 	DMA2 = 		0x0000_0002,
@@ -38,6 +47,8 @@ pub enum AhbPeripherals {
 }
 
 #[allow(dead_code)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum Apb1Peripherals {
 	TIM2 = 		0x0000_0001,
 	TIM3 = 		0x0000_0002,
@@ -49,10 +60,10 @@ pub enum Apb1Peripherals {
 	SPI3 = 		0x0000_8000,
 	USART2 = 	0x0002_0000,
 	USART3 = 	0x0004_0000,
-	USART4 = 	0x0008_0000,
-	USART5 = 	0x0010_0000,
+	UART4 = 	0x0008_0000,
+	UART5 = 	0x0010_0000,
 	I2C1 = 		0x0020_0000,
-	I1C2 = 		0x0040_0000,
+	I2C2 = 		0x0040_0000,
 	USB = 		0x0080_0000,
 	CAN = 		0x0200_0000,
 	DAC2 = 		0x0400_0000,
@@ -62,6 +73,8 @@ pub enum Apb1Peripherals {
 }
 
 #[allow(dead_code)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum Apb2Peripherals {
 	SYSCFG = 	0x0000_0001,
 	TIM1 = 		0x0000_0800,
@@ -75,6 +88,132 @@ pub enum Apb2Peripherals {
 	TIM20 = 	0x0010_0000,
 }
 
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum ClockSource {
+	Hsi,
+	Hse
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockError {
+	HseOutOfRange,
+	SysclkOutOfRange,
+	PllUnreachable,
+	InvalidAhbPrescaler,
+	InvalidApb1Prescaler,
+	InvalidApb2Prescaler,
+	HclkTooHigh,
+	Pclk1TooHigh,
+	Pclk2TooHigh,
+	NoResetLine,
+	HseTimeout,
+	LseTimeout,
+	CallbackTableFull,
+	MeasurementFailed,
+	HsiNotInUse
+}
+
+// PA8, put in AltFunc<0> as Signal::Mco
+pub type McoPin = gpio::Pin<gpio::AltFunc<0>>;
+
+// On the F303xC the PLL reaches MCO divided by 2
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum McoSource {
+	NoClock = 0,
+	Lsi = 2,
+	Lse = 3,
+	Sysclk = 4,
+	Hsi = 5,
+	Hse = 6,
+	Pll = 7
+}
+
+// USARTxSW kernel clock. Only HSI and LSE keep running in Stop mode.
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum UsartClock {
+	Pclk = 0,
+	Sysclk = 1,
+	Lse = 2,
+	Hsi = 3
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum LowSpeedClock {
+	Lsi,
+	Lse
+}
+
+// Requested clock tree. Any bus left unset runs as fast as its limit allows.
+#[derive(Clone, Copy)]
+pub struct ClockConfig {
+	source: ClockSource,
+	hse_freq: u32,
+	hse_bypass: bool,
+	sysclk: u32,
+	hclk: Option<u32>,
+	pclk1: Option<u32>,
+	pclk2: Option<u32>,
+}
+
+#[allow(dead_code)]
+impl ClockConfig {
+	pub const fn new() -> Self {
+		ClockConfig {
+			source: ClockSource::Hsi,
+			hse_freq: 0,
+			hse_bypass: false,
+			sysclk: HSI_FREQ,
+			hclk: None,
+			pclk1: None,
+			pclk2: None,
+		}
+	}
+
+	pub fn use_hsi(mut self) -> Self {
+		self.source = ClockSource::Hsi;
+		self
+	}
+
+	pub fn use_hse(mut self, freq: u32, bypass: bool) -> Self {
+		self.source = ClockSource::Hse;
+		self.hse_freq = freq;
+		self.hse_bypass = bypass;
+		self
+	}
+
+	pub fn sysclk(mut self, freq: u32) -> Self {
+		self.sysclk = freq;
+		self
+	}
+
+	pub fn hclk(mut self, freq: u32) -> Self {
+		self.hclk = Some(freq);
+		self
+	}
+
+	pub fn pclk1(mut self, freq: u32) -> Self {
+		self.pclk1 = Some(freq);
+		self
+	}
+
+	pub fn pclk2(mut self, freq: u32) -> Self {
+		self.pclk2 = Some(freq);
+		self
+	}
+}
+
+impl Default for ClockConfig {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+// Every frequency is in Hz, 0 means the clock is not running
 #[derive(Copy, Clone, PartialEq)]
 pub struct Clocks {
 	sysclk: u32,
@@ -82,20 +221,184 @@ pub struct Clocks {
 	lclk: u32,
 	pclk1: u32,
 	pclk2: u32,
+	pllclk: u32,
+	timclk1: u32,
+	timclk2: u32,
+	tim1clk: u32,
+	tim8clk: u32,
+	adc12clk: u32,
+	adc34clk: u32,
+	usart1clk: u32,
+	usart2clk: u32,
+	usart3clk: u32,
+	i2c1clk: u32,
+	i2c2clk: u32,
+	i2c3clk: u32,
+}
+
+#[allow(dead_code)]
+impl Clocks {
+	pub fn sysclk(&self) -> u32 { self.sysclk }
+	pub fn hclk(&self) -> u32 { self.hclk }
+	pub fn lclk(&self) -> u32 { self.lclk }
+	pub fn pclk1(&self) -> u32 { self.pclk1 }
+	pub fn pclk2(&self) -> u32 { self.pclk2 }
+	pub fn pllclk(&self) -> u32 { self.pllclk }
+
+	// Timers on APB1 (TIM2/3/4/6/7) and APB2 (TIM15/16/17) run at twice the
+	// bus clock whenever the bus prescaler is not 1
+	pub fn timclk1(&self) -> u32 { self.timclk1 }
+	pub fn timclk2(&self) -> u32 { self.timclk2 }
+	pub fn tim1clk(&self) -> u32 { self.tim1clk }
+	pub fn tim8clk(&self) -> u32 { self.tim8clk }
+
+	// 0 when the ADC prescaler is off and the ADC has to run from HCLK
+	pub fn adc12clk(&self) -> u32 { self.adc12clk }
+	pub fn adc34clk(&self) -> u32 { self.adc34clk }
+
+	pub fn usart1clk(&self) -> u32 { self.usart1clk }
+	pub fn usart2clk(&self) -> u32 { self.usart2clk }
+	pub fn usart3clk(&self) -> u32 { self.usart3clk }
+	pub fn i2c1clk(&self) -> u32 { self.i2c1clk }
+	pub fn i2c2clk(&self) -> u32 { self.i2c2clk }
+	pub fn i2c3clk(&self) -> u32 { self.i2c3clk }
+}
+
+pub type ClockChangeCallback = fn(&Clocks);
+
+// Sticky record of oscillator problems since boot
+#[derive(Clone, Copy, PartialEq)]
+pub struct ClockFaults {
+	pub hse_timeout: bool,
+	pub lse_timeout: bool,
+	pub css_triggered: bool,
+}
+
+// Raw enable register contents, one bit per peripheral as in the enums above
+#[derive(Clone, Copy, PartialEq)]
+pub struct PeripheralClockStatus {
+	pub ahb: u32,
+	pub apb1: u32,
+	pub apb2: u32,
+}
+
+#[derive(Clone, Copy)]
+enum Bus {
+	Ahb,
+	Apb1,
+	Apb2
+}
+
+// Register values worked out from a ClockConfig before anything is touched
+struct ClockSettings {
+	source: ClockSource,
+	hse_freq: u32,
+	hse_bypass: bool,
+	pll: Option<PllSettings>,
+	hpre: u8,
+	ppre1: u8,
+	ppre2: u8,
+	latency: flash::FlashLatency,
+}
+
+struct PllSettings {
+	prediv: u8,
+	mul: u8,
 }
 
 //==============================================================================
 // Variables
 //==============================================================================
+pub const HSI_FREQ: u32 = 8_000_000;
+const LSI_FREQ: u32 = 40_000;
+const HSE_FREQ_MIN: u32 = 4_000_000;
+const HSE_FREQ_MAX: u32 = 32_000_000;
+const PLL_IN_MIN: u32 = 1_000_000;
+const PLL_IN_MAX: u32 = 24_000_000;
+const PLL_OUT_MIN: u32 = 16_000_000;
+const SYSCLK_MAX: u32 = 72_000_000;
+const PCLK1_MAX: u32 = 36_000_000;
+const PCLK2_MAX: u32 = 72_000_000;
+const HSI_PLL_MAX: u32 = 64_000_000;			// HSI/2 x16
+
+// Startup timeouts in polling loops. The HSE is polled while running from the
+// HSI, the LSE crystal can legitimately take up to 2s.
+const HSE_STARTUP_TIMEOUT: u32 = 500_000;
+const LSE_STARTUP_TIMEOUT: u32 = 20_000_000;
+const LSI_STARTUP_TIMEOUT: u32 = 10_000;
+
+const MAX_CLOCK_CALLBACKS: usize = 8;
+
+// Low speed clock periods per measurement, a multiple of the TIM16 capture
+// prescaler. 256 LSI periods take ~6ms.
+const MEASURE_PERIODS: u32 = 256;
+const HSI_TRIM_MAX: u8 = 0x1F;
+const HSI_TRIM_STEPS: u32 = 8;
+
+// (divider, register value) pairs for HPRE and PPREx
+const AHB_PRESCALERS: [(u32, u8); 9] = [
+	(1, 0b0000), (2, 0b1000), (4, 0b1001), (8, 0b1010), (16, 0b1011),
+	(64, 0b1100), (128, 0b1101), (256, 0b1110), (512, 0b1111)
+];
+const APB_PRESCALERS: [(u32, u8); 5] = [
+	(1, 0b000), (2, 0b100), (4, 0b101), (8, 0b110), (16, 0b111)
+];
+// ADCxxPRES 0b1xxxx dividers in register order
+const ADC_PRESCALERS: [u32; 12] = [1, 2, 4, 6, 8, 10, 12, 16, 32, 64, 128, 256];
+
+// AHBRSTR only has reset lines for FMC, the GPIO ports, TSC and the ADCs
+const AHB_RESET_MASK: u32 = 0x31FF_0020;
+
 static RCC_HANDLE: Mutex<RefCell<Option<stm32f303::RCC>>> = 
 	Mutex::new(RefCell::new(None));
 
+// Number of drivers currently holding each peripheral clock, indexed by bit
+static CLOCK_REFS: Mutex<RefCell<[[u8; 32]; 3]>> =
+	Mutex::new(RefCell::new([[0; 32]; 3]));
+
+static ACTIVE_CONFIG: Mutex<Cell<ClockConfig>> = Mutex::new(Cell::new(ClockConfig::new()));
+
+static CLOCK_FAULTS: Mutex<Cell<ClockFaults>> = Mutex::new(Cell::new(ClockFaults {
+	hse_timeout: false,
+	lse_timeout: false,
+	css_triggered: false,
+}));
+
+// Called from task_handler context whenever the clock tree changes
+static CLOCK_CALLBACKS: Mutex<RefCell<[Option<ClockChangeCallback>; MAX_CLOCK_CALLBACKS]>> =
+	Mutex::new(RefCell::new([None; MAX_CLOCK_CALLBACKS]));
+
+// Set by the NMI, which cannot be held off by a critical section
+static CSS_TRIGGERED: AtomicBool = AtomicBool::new(false);
+
+// The HSE frequency is the one value that cannot be read back from the RCC
+static HSE_CLOCK: Mutex<Cell<u32>> = Mutex::new(Cell::new(config::HIGH_SPEED_CLOCK));
+
+// Nominal until measure_low_speed_clock() has timed the real LSI
+static LSI_CLOCK: Mutex<Cell<u32>> = Mutex::new(Cell::new(LSI_FREQ));
+
+static MCO_SOURCE: Mutex<Cell<McoSource>> = Mutex::new(Cell::new(McoSource::NoClock));
+static MCO_PIN: Mutex<RefCell<Option<McoPin>>> = Mutex::new(RefCell::new(None));
+
 static CLOCKS: Mutex<Cell<Clocks>> = Mutex::new(Cell::new(Clocks {
 	sysclk: 0,
 	hclk: 0,
 	lclk: 0,
 	pclk1: 0,
 	pclk2: 0,
+	pllclk: 0,
+	timclk1: 0,
+	timclk2: 0,
+	tim1clk: 0,
+	tim8clk: 0,
+	adc12clk: 0,
+	adc34clk: 0,
+	usart1clk: 0,
+	usart2clk: 0,
+	usart3clk: 0,
+	i2c1clk: 0,
+	i2c2clk: 0,
+	i2c3clk: 0,
 }));
 
 //==============================================================================
@@ -109,50 +412,332 @@ pub fn init(
 	free(|cs| RCC_HANDLE.borrow(cs).replace(Some(rcc)));
 }
 
+// BDCR sits in the backup domain and ignores writes until power::init has
+// unlocked it, so the LSE/LSI and RTC clock are started separately
+pub fn init_low_speed_clock() {
+	let result = free(|cs| if let Some(rcc) = RCC_HANDLE.borrow(cs).borrow().as_ref() {
+		start_low_speed_clock(rcc)
+	} else { Ok(()) });
+
+	if result.is_err() {
+		set_fault(|f| f.lse_timeout = true);
+	}
+
+	update_clocks();
+}
+
 #[allow(dead_code)]
 pub fn get_clocks() -> Clocks {
 	free(|cs| CLOCKS.borrow(cs).get())
 }
 
+// Stop mode hands SYSCLK back to the HSI with the PLL and HSE off, this puts
+// the last configuration back
 #[allow(dead_code)]
-pub fn set_ahb_peripheral_clock_enable(peripheral: AhbPeripherals, enable: bool) {
-	free(|cs| if let Some(rcc) = RCC_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-		if enable {
-			rcc.ahbenr.modify(|r, w| unsafe { w.bits( r.bits() | peripheral as u32) });
+pub fn restore_clock_config() -> Result<Clocks, ClockError> {
+	let config = free(|cs| ACTIVE_CONFIG.borrow(cs).get());
+	set_clock_config(&config)
+}
+
+#[allow(dead_code)]
+pub fn set_usart_clock_source(usart: uart::Usart, source: UsartClock) {
+	free(|cs| if let Some(rcc) = RCC_HANDLE.borrow(cs).borrow().as_ref() {
+		let source = source as u8;
+		rcc.cfgr3.modify(|_, w| match usart {
+			uart::Usart::Usart1 => w.usart1sw().bits(source),
+			uart::Usart::Usart2 => w.usart2sw().bits(source),
+			uart::Usart::Usart3 => w.usart3sw().bits(source),
+		});
+	});
+
+	update_clocks();
+}
+
+// Raw RCC_CSR reset flags, cleared afterwards so the next boot only sees its
+// own cause. Decoded by the power module.
+pub fn take_reset_flags() -> u32 {
+	free(|cs| if let Some(rcc) = RCC_HANDLE.borrow(cs).borrow().as_ref() {
+		let flags = rcc.csr.read().bits();
+		rcc.csr.modify(|_, w| w.rmvf().clear());
+		flags
+	} else { 0 })
+}
+
+#[allow(dead_code)]
+pub fn get_clock_faults() -> ClockFaults {
+	free(|cs| CLOCK_FAULTS.borrow(cs).get())
+}
+
+#[allow(dead_code)]
+pub fn register_clock_change_callback(callback: ClockChangeCallback) -> Result<(), ClockError> {
+	free(|cs| {
+		let mut callbacks = CLOCK_CALLBACKS.borrow(cs).borrow_mut();
+		match callbacks.iter_mut().find(|c| c.is_none()) {
+			Some(slot) => {
+				*slot = Some(callback);
+				Ok(())
+			},
+			None => Err(ClockError::CallbackTableFull)
 		}
-		else {
-			rcc.ahbenr.modify(|r, w| unsafe { w.bits( r.bits() & !(peripheral as u32)) });
+	})
+}
+
+// Validates the whole configuration first so a rejected request leaves the
+// running clock tree untouched. If the HSE then fails to start the tree is
+// left on the HSI and the registered callbacks still see the change.
+#[allow(dead_code)]
+pub fn set_clock_config(config: &ClockConfig) -> Result<Clocks, ClockError> {
+	let settings = compute_settings(config)?;
+
+	let result = free(|cs| if let Some(rcc) = RCC_HANDLE.borrow(cs).borrow_mut().deref_mut() {
+		apply_settings(rcc, &settings)
+	} else { Ok(()) });
+
+	match result {
+		Ok(()) => free(|cs| ACTIVE_CONFIG.borrow(cs).set(*config)),
+		Err(_) => set_fault(|f| f.hse_timeout = true)
+	}
+
+	let clocks = update_clocks();
+	notify_clock_change(&clocks);
+
+	result.map(|_| clocks)
+}
+
+// Re-reads the RCC so CLOCKS reflects the hardware even after another module
+// has changed a prescaler or kernel clock mux behind our back
+#[allow(dead_code)]
+pub fn update_clocks() -> Clocks {
+	free(|cs| {
+		if let Some(rcc) = RCC_HANDLE.borrow(cs).borrow().as_ref() {
+			CLOCKS.borrow(cs).set(decode_clocks(rcc, HSE_CLOCK.borrow(cs).get(), LSI_CLOCK.borrow(cs).get()));
 		}
-	});
+		CLOCKS.borrow(cs).get()
+	})
 }
 
 #[allow(dead_code)]
-pub fn set_apb1_peripheral_clock_enable(peripheral: Apb1Peripherals, enable: bool) {
-	free(|cs| if let Some(rcc) = RCC_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-		if enable {
-			rcc.ahbenr.modify(|r, w| unsafe { w.bits( r.bits() | peripheral as u32) });
+pub fn get_lsi_freq() -> u32 {
+	free(|cs| LSI_CLOCK.borrow(cs).get())
+}
+
+// Routes a clock to PA8 for probing, the pin is held until release_mco().
+// A pin that is not claimed as Signal::Mco is handed back with the conflict.
+#[allow(dead_code)]
+pub fn set_mco(mut pin: McoPin, source: McoSource) -> Result<(), (McoPin, PinmuxError)> {
+	if let Err(error) = pinmux::check(pin.port(), pin.pin(), Signal::Mco) {
+		return Err((pin, error));
+	}
+
+	let _ = pin.set_speed(gpio::OutputSpeed::High);
+	free(|cs| MCO_PIN.borrow(cs).replace(Some(pin)));
+	select_mco(source);
+	Ok(())
+}
+
+// Turns MCO off and hands PA8 back
+#[allow(dead_code)]
+pub fn release_mco() -> Option<McoPin> {
+	select_mco(McoSource::NoClock);
+	free(|cs| MCO_PIN.borrow(cs).borrow_mut().take())
+}
+
+// Times the LSI or LSE against the TIM16 clock by routing it through MCO
+// into TIM16 channel 1. A measured LSI replaces the nominal 40kHz used by
+// the watchdog and RTC. The MCO setting is restored afterwards.
+#[allow(dead_code)]
+pub fn measure_low_speed_clock(clock: LowSpeedClock) -> Result<u32, ClockError> {
+	let source = match clock {
+		LowSpeedClock::Lsi => {
+			free(|cs| if let Some(rcc) = RCC_HANDLE.borrow(cs).borrow().as_ref() {
+				rcc.csr.modify(|_, w| w.lsion().on());
+				wait_for(|| rcc.csr.read().lsirdy().is_ready(), LSI_STARTUP_TIMEOUT);
+			});
+			McoSource::Lsi
+		},
+		LowSpeedClock::Lse => McoSource::Lse
+	};
+
+	let mco = free(|cs| MCO_SOURCE.borrow(cs).get());
+	select_mco(source);
+	let ticks = timer::capture_tim16_period(timer::Tim16Input::Mco, MEASURE_PERIODS);
+	select_mco(mco);
+
+	let ticks = match ticks {
+		Some(ticks) if ticks > 0 => ticks,
+		_ => return Err(ClockError::MeasurementFailed)
+	};
+
+	let timclk = get_clocks().timclk2() as u64;
+	let freq = (timclk * MEASURE_PERIODS as u64 / ticks as u64) as u32;
+
+	if clock == LowSpeedClock::Lsi {
+		free(|cs| LSI_CLOCK.borrow(cs).set(freq));
+		update_clocks();
+	}
+
+	Ok(freq)
+}
+
+// Steps HSITRIM until the LSE crystal, timed from the HSI derived TIM16
+// clock, reads as close as possible to its nominal frequency. Returns the
+// trim value left in place.
+#[allow(dead_code)]
+pub fn calibrate_hsi() -> Result<u8, ClockError> {
+	if free(|cs| ACTIVE_CONFIG.borrow(cs).get()).source != ClockSource::Hsi {
+		return Err(ClockError::HsiNotInUse);
+	}
+
+	let mut trim = get_hsi_trim();
+	let mut best = (trim, u32::MAX);
+
+	for _ in 0..HSI_TRIM_STEPS {
+		let measured = measure_low_speed_clock(LowSpeedClock::Lse)?;
+		let error = measured.abs_diff(config::LOW_SPEED_CLOCK);
+		if error < best.1 {
+			best = (trim, error);
 		}
-		else {
-			rcc.ahbenr.modify(|r, w| unsafe { w.bits( r.bits() & !(peripheral as u32)) });
+
+		// A fast HSI makes the LSE look slow
+		trim = if measured < config::LOW_SPEED_CLOCK && trim > 0 { trim - 1 }
+			else if measured > config::LOW_SPEED_CLOCK && trim < HSI_TRIM_MAX { trim + 1 }
+			else { break };
+		set_hsi_trim(trim);
+	}
+
+	set_hsi_trim(best.0);
+	Ok(best.0)
+}
+
+#[allow(dead_code)]
+pub fn get_peripheral_clock_status() -> PeripheralClockStatus {
+	free(|cs| if let Some(rcc) = RCC_HANDLE.borrow(cs).borrow().as_ref() {
+		PeripheralClockStatus {
+			ahb: rcc.ahbenr.read().bits(),
+			apb1: rcc.apb1enr.read().bits(),
+			apb2: rcc.apb2enr.read().bits(),
 		}
-	});
+	} else {
+		PeripheralClockStatus { ahb: 0, apb1: 0, apb2: 0 }
+	})
+}
+
+#[allow(dead_code)]
+pub fn get_ahb_peripheral_clock_enabled(peripheral: AhbPeripherals) -> bool {
+	get_peripheral_clock_status().ahb & peripheral as u32 != 0
+}
+
+#[allow(dead_code)]
+pub fn get_apb1_peripheral_clock_enabled(peripheral: Apb1Peripherals) -> bool {
+	get_peripheral_clock_status().apb1 & peripheral as u32 != 0
+}
+
+#[allow(dead_code)]
+pub fn get_apb2_peripheral_clock_enabled(peripheral: Apb2Peripherals) -> bool {
+	get_peripheral_clock_status().apb2 & peripheral as u32 != 0
+}
+
+#[allow(dead_code)]
+pub fn reset_ahb_peripheral(peripheral: AhbPeripherals) -> Result<(), ClockError> {
+	if peripheral as u32 & AHB_RESET_MASK == 0 {
+		return Err(ClockError::NoResetLine);
+	}
+
+	pulse_reset(Bus::Ahb, peripheral as u32);
+	Ok(())
+}
+
+#[allow(dead_code)]
+pub fn reset_apb1_peripheral(peripheral: Apb1Peripherals) {
+	pulse_reset(Bus::Apb1, peripheral as u32);
+}
+
+#[allow(dead_code)]
+pub fn reset_apb2_peripheral(peripheral: Apb2Peripherals) {
+	pulse_reset(Bus::Apb2, peripheral as u32);
+}
+
+// Clock enables are reference counted: the peripheral is only gated once
+// every driver that enabled it has disabled it again
+#[allow(dead_code)]
+pub fn set_ahb_peripheral_clock_enable(peripheral: AhbPeripherals, enable: bool) {
+	set_peripheral_clock_enable(Bus::Ahb, peripheral as u32, enable);
+}
+
+#[allow(dead_code)]
+pub fn set_apb1_peripheral_clock_enable(peripheral: Apb1Peripherals, enable: bool) {
+	set_peripheral_clock_enable(Bus::Apb1, peripheral as u32, enable);
 }
 
 #[allow(dead_code)]
 pub fn set_apb2_peripheral_clock_enable(peripheral: Apb2Peripherals, enable: bool) {
+	set_peripheral_clock_enable(Bus::Apb2, peripheral as u32, enable);
+}
+
+//==============================================================================
+// Private Functions
+//==============================================================================
+// TIM16 sees the MCO clock whether or not PA8 is held. MCOPRE is reserved on
+// the F303xC, the clock always goes out undivided.
+fn select_mco(source: McoSource) {
+	free(|cs| MCO_SOURCE.borrow(cs).set(source));
+
+	free(|cs| if let Some(rcc) = RCC_HANDLE.borrow(cs).borrow().as_ref() {
+		rcc.cfgr.modify(|_, w| unsafe { w.mco().bits(source as u8) });
+	});
+}
+
+fn set_peripheral_clock_enable(bus: Bus, mask: u32, enable: bool) {
+	let bit = mask.trailing_zeros() as usize;
+
 	free(|cs| if let Some(rcc) = RCC_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-		if enable {
-			rcc.ahbenr.modify(|r, w| unsafe { w.bits( r.bits() | peripheral as u32) });
+		let mut refs = CLOCK_REFS.borrow(cs).borrow_mut();
+		let count = &mut refs[bus as usize][bit];
+
+		let gate = if enable {
+			*count = count.saturating_add(1);
+			false
 		}
 		else {
-			rcc.ahbenr.modify(|r, w| unsafe { w.bits( r.bits() & !(peripheral as u32)) });
+			*count = count.saturating_sub(1);
+			*count == 0
+		};
+
+		if enable || gate {
+			match bus {
+				Bus::Ahb => rcc.ahbenr.modify(|r, w| unsafe { w.bits(
+					if enable { r.bits() | mask } else { r.bits() & !mask }
+				) }),
+				Bus::Apb1 => rcc.apb1enr.modify(|r, w| unsafe { w.bits(
+					if enable { r.bits() | mask } else { r.bits() & !mask }
+				) }),
+				Bus::Apb2 => rcc.apb2enr.modify(|r, w| unsafe { w.bits(
+					if enable { r.bits() | mask } else { r.bits() & !mask }
+				) }),
+			}
+		}
+	});
+}
+
+fn pulse_reset(bus: Bus, mask: u32) {
+	free(|cs| if let Some(rcc) = RCC_HANDLE.borrow(cs).borrow_mut().deref_mut() {
+		match bus {
+			Bus::Ahb => {
+				rcc.ahbrstr.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+				rcc.ahbrstr.modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+			},
+			Bus::Apb1 => {
+				rcc.apb1rstr.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+				rcc.apb1rstr.modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+			},
+			Bus::Apb2 => {
+				rcc.apb2rstr.modify(|r, w| unsafe { w.bits(r.bits() | mask) });
+				rcc.apb2rstr.modify(|r, w| unsafe { w.bits(r.bits() & !mask) });
+			},
 		}
 	});
 }
 
-//==============================================================================
-// Private Functions
-//==============================================================================
 fn init_clocks(
 	rcc: &stm32f303::RCC) {
 	// The main system clock can be sourced from the following:
@@ -163,66 +748,414 @@ fn init_clocks(
 	//   * LSE (Low Speed External - 35.768kHz) 
 
 	// This board has an 8MHz clock signal from the ST-Link MCU at 8MHz on HSE
-	let (hse_on, hsi_on, sw) = if config::EXTERNAL_HIGH_SPEED { (
-		stm32f303::rcc::cr::HSEON_A::ON,
-		stm32f303::rcc::cr::HSION_A::OFF,
-		stm32f303::rcc::cfgr::SW_A::HSE,
-	) }
-	else { (
-		stm32f303::rcc::cr::HSEON_A::OFF,
-		stm32f303::rcc::cr::HSION_A::ON,
-		stm32f303::rcc::cfgr::SW_A::HSI,
-	) };
-
-	let (rtc_sel, lse_on) = if config::EXTERNAL_LOW_SPEED { (
-		stm32f303::rcc::bdcr::RTCSEL_A::LSE,
-		stm32f303::rcc::bdcr::LSEON_A::ON,
-	) }
-	else { (
-		stm32f303::rcc::bdcr::RTCSEL_A::LSI,
-		stm32f303::rcc::bdcr::LSEON_A::OFF,
-	) };
-
-	rcc.cr.write(|w| w
-		.pllon().off()
-		.hseon().variant(hse_on)
-		.hsion().variant(hsi_on)
-	);
-
-	if config::EXTERNAL_HIGH_SPEED {
-		while rcc.cr.read().hserdy().is_not_ready() {};
+	let config = if config::EXTERNAL_HIGH_SPEED {
+		ClockConfig::new()
+			.use_hse(config::HIGH_SPEED_CLOCK, config::HIGH_SPEED_BYPASS)
+			.sysclk(config::SYSTEM_CLOCK)
 	}
 	else {
-		while rcc.cr.read().hsirdy().is_not_ready() {};
-	}
+		ClockConfig::new()
+			.use_hsi()
+			.sysclk(config::SYSTEM_CLOCK)
+	};
 
-	rcc.cfgr.write(|w| w
-		.sw().variant(sw)
-		.hpre().div4()
-		.ppre1().div2()
-		.ppre2().div2()
-	);
+	// An unreachable request falls back to the reset clock rather than
+	// leaving the tree half configured
+	let config = match compute_settings(&config) {
+		Ok(_) => config,
+		Err(_) => ClockConfig::new()
+	};
+
+	// No ST-Link MCO (e.g. the board is powered from an external supply): run
+	// the same tree from the HSI as closely as it allows
+	let config = match apply_settings(rcc, &compute_settings(&config).unwrap()) {
+		Ok(()) => config,
+		Err(_) => {
+			set_fault(|f| f.hse_timeout = true);
+			let fallback = get_hsi_fallback(&config);
+			let _ = apply_settings(rcc, &compute_settings(&fallback).unwrap());
+			fallback
+		}
+	};
+
+	free(|cs| {
+		ACTIVE_CONFIG.borrow(cs).set(config);
+		CLOCKS.borrow(cs).set(decode_clocks(rcc, HSE_CLOCK.borrow(cs).get(), LSI_CLOCK.borrow(cs).get()));
+	});
+}
+
+fn start_low_speed_clock(rcc: &stm32f303::RCC) -> Result<(), ClockError> {
+	let lse_ready = config::EXTERNAL_LOW_SPEED && {
+		rcc.bdcr.modify(|_, w| w.lseon().on());
+		wait_for(|| rcc.bdcr.read().lserdy().is_ready(), LSE_STARTUP_TIMEOUT)
+	};
+
+	let rtc_sel = if lse_ready {
+		stm32f303::rcc::bdcr::RTCSEL_A::LSE
+	}
+	else {
+		rcc.bdcr.modify(|_, w| w.lseon().off());
+		rcc.csr.modify(|_, w| w.lsion().on());
+		wait_for(|| rcc.csr.read().lsirdy().is_ready(), LSI_STARTUP_TIMEOUT);
+		stm32f303::rcc::bdcr::RTCSEL_A::LSI
+	};
 
-	rcc.bdcr.write(|w| w
+	rcc.bdcr.modify(|_, w| w
 		.rtcen().enabled()
 		.rtcsel().variant(rtc_sel)
-		.lseon().variant(lse_on)
 	);
 
-	let clocks: Clocks = Clocks {
-		sysclk: config::HIGH_SPEED_CLOCK,
-		hclk: config::HIGH_SPEED_CLOCK / 4,
-		lclk: config::LOW_SPEED_CLOCK,
-		pclk1: config::HIGH_SPEED_CLOCK / 8,
-		pclk2: config::HIGH_SPEED_CLOCK / 8,
+	if config::EXTERNAL_LOW_SPEED && !lse_ready {
+		Err(ClockError::LseTimeout)
+	}
+	else {
+		Ok(())
+	}
+}
+
+// Closest HSI based tree to a configuration: the HSI reaches the PLL as 4MHz
+// so SYSCLK is rounded down to a multiple of that, capped at 64MHz
+fn get_hsi_fallback(config: &ClockConfig) -> ClockConfig {
+	let step = HSI_FREQ / 2;
+	let sysclk = (config.sysclk.min(HSI_PLL_MAX) / step) * step;
+	let sysclk = if sysclk < PLL_OUT_MIN { HSI_FREQ } else { sysclk };
+
+	ClockConfig::new()
+		.use_hsi()
+		.sysclk(sysclk)
+}
+
+fn handle_hse_failure() {
+	// The hardware has already moved SYSCLK to the HSI and stopped the PLL
+	set_fault(|f| f.css_triggered = true);
+
+	let fallback = get_hsi_fallback(&free(|cs| ACTIVE_CONFIG.borrow(cs).get()));
+	if set_clock_config(&fallback).is_err() {
+		let clocks = update_clocks();
+		notify_clock_change(&clocks);
+	}
+}
+
+fn get_hsi_trim() -> u8 {
+	free(|cs| if let Some(rcc) = RCC_HANDLE.borrow(cs).borrow().as_ref() {
+		rcc.cr.read().hsitrim().bits()
+	} else { 0 })
+}
+
+fn set_hsi_trim(trim: u8) {
+	free(|cs| if let Some(rcc) = RCC_HANDLE.borrow(cs).borrow().as_ref() {
+		rcc.cr.modify(|_, w| w.hsitrim().bits(trim));
+	});
+}
+
+fn notify_clock_change(clocks: &Clocks) {
+	// Copied out so a callback is free to use the clocks API itself
+	let callbacks = free(|cs| *CLOCK_CALLBACKS.borrow(cs).borrow());
+
+	for callback in callbacks.iter().flatten() {
+		callback(clocks);
+	}
+}
+
+fn set_fault<F: FnOnce(&mut ClockFaults)>(update: F) {
+	free(|cs| {
+		let mut faults = CLOCK_FAULTS.borrow(cs).get();
+		update(&mut faults);
+		CLOCK_FAULTS.borrow(cs).set(faults);
+	});
+}
+
+fn wait_for<F: Fn() -> bool>(ready: F, timeout: u32) -> bool {
+	(0..timeout).any(|_| ready())
+}
+
+fn compute_settings(config: &ClockConfig) -> Result<ClockSettings, ClockError> {
+	let source_freq = match config.source {
+		ClockSource::Hsi => HSI_FREQ,
+		ClockSource::Hse => {
+			if config.hse_freq < HSE_FREQ_MIN || config.hse_freq > HSE_FREQ_MAX {
+				return Err(ClockError::HseOutOfRange);
+			}
+			config.hse_freq
+		}
+	};
+
+	let sysclk = config.sysclk;
+	if sysclk == 0 || sysclk > SYSCLK_MAX {
+		return Err(ClockError::SysclkOutOfRange);
+	}
+
+	let pll = if sysclk == source_freq {
+		None
+	}
+	else {
+		Some(compute_pll(config.source, source_freq, sysclk)?)
+	};
+
+	let hclk = config.hclk.unwrap_or(sysclk);
+	if hclk > SYSCLK_MAX {
+		return Err(ClockError::HclkTooHigh);
+	}
+	let hpre = find_prescaler(&AHB_PRESCALERS, sysclk, hclk)
+		.ok_or(ClockError::InvalidAhbPrescaler)?;
+
+	// APB1 is limited to 36MHz so default to the fastest legal division
+	let pclk1 = match config.pclk1 {
+		Some(pclk1) => pclk1,
+		None => APB_PRESCALERS.iter()
+			.map(|&(div, _)| hclk / div)
+			.find(|&pclk1| pclk1 <= PCLK1_MAX)
+			.unwrap_or(hclk / 16)
+	};
+	if pclk1 > PCLK1_MAX {
+		return Err(ClockError::Pclk1TooHigh);
+	}
+	let ppre1 = find_prescaler(&APB_PRESCALERS, hclk, pclk1)
+		.ok_or(ClockError::InvalidApb1Prescaler)?;
+
+	let pclk2 = config.pclk2.unwrap_or(hclk);
+	if pclk2 > PCLK2_MAX {
+		return Err(ClockError::Pclk2TooHigh);
+	}
+	let ppre2 = find_prescaler(&APB_PRESCALERS, hclk, pclk2)
+		.ok_or(ClockError::InvalidApb2Prescaler)?;
+
+	Ok(ClockSettings {
+		source: config.source,
+		hse_freq: config.hse_freq,
+		hse_bypass: config.hse_bypass,
+		pll,
+		hpre,
+		ppre1,
+		ppre2,
+		latency: flash::get_latency_for_sysclk(sysclk),
+	})
+}
+
+fn compute_pll(source: ClockSource, source_freq: u32, sysclk: u32) -> Result<PllSettings, ClockError> {
+	if sysclk < PLL_OUT_MIN {
+		return Err(ClockError::PllUnreachable);
+	}
+
+	// On the F303xC the HSI always enters the PLL divided by 2, only the HSE
+	// can go through PREDIV
+	let max_prediv = match source {
+		ClockSource::Hsi => 1,
+		ClockSource::Hse => 16
+	};
+	let pll_source = match source {
+		ClockSource::Hsi => source_freq / 2,
+		ClockSource::Hse => source_freq
+	};
+
+	for prediv in 1..=max_prediv {
+		if !pll_source.is_multiple_of(prediv) {
+			continue;
+		}
+
+		let pll_in = pll_source / prediv;
+		if !(PLL_IN_MIN..=PLL_IN_MAX).contains(&pll_in) || !sysclk.is_multiple_of(pll_in) {
+			continue;
+		}
+
+		let mul = sysclk / pll_in;
+		if (2..=16).contains(&mul) {
+			return Ok(PllSettings {
+				prediv: prediv as u8,
+				mul: mul as u8,
+			});
+		}
+	}
+
+	Err(ClockError::PllUnreachable)
+}
+
+fn find_prescaler(prescalers: &[(u32, u8)], input: u32, output: u32) -> Option<u8> {
+	if output == 0 || !input.is_multiple_of(output) {
+		return None;
+	}
+
+	let div = input / output;
+	prescalers.iter()
+		.find(|&&(d, _)| d == div)
+		.map(|&(_, bits)| bits)
+}
+
+fn apply_settings(rcc: &stm32f303::RCC, settings: &ClockSettings) -> Result<(), ClockError> {
+	// Park SYSCLK on the HSI while the PLL and prescalers are reprogrammed
+	rcc.cr.modify(|_, w| w.hsion().on());
+	while rcc.cr.read().hsirdy().is_not_ready() {};
+
+	rcc.cfgr.modify(|_, w| w.sw().hsi());
+	while !rcc.cfgr.read().sws().is_hsi() {};
+
+	// Running from HSI at 8MHz: bump the wait states now if the new SYSCLK
+	// needs more, drop them only once the switch is complete
+	if settings.latency > flash::get_latency() {
+		flash::set_latency(settings.latency);
+	}
+
+	rcc.cr.modify(|_, w| w.pllon().off());
+	while rcc.cr.read().pllrdy().is_ready() {};
+
+	if settings.source == ClockSource::Hse {
+		free(|cs| HSE_CLOCK.borrow(cs).set(settings.hse_freq));
+
+		// HSEBYP can only be changed while the HSE is off
+		rcc.cr.modify(|_, w| w.csson().off().hseon().off());
+		while rcc.cr.read().hserdy().is_ready() {};
+
+		rcc.cr.modify(|_, w| w.hsebyp().bit(settings.hse_bypass));
+		rcc.cr.modify(|_, w| w.hseon().on());
+		if !wait_for(|| rcc.cr.read().hserdy().is_ready(), HSE_STARTUP_TIMEOUT) {
+			rcc.cr.modify(|_, w| w.hseon().off());
+			return Err(ClockError::HseTimeout);
+		}
+
+		// From here a lost HSE raises the NMI instead of stalling the core
+		rcc.cr.modify(|_, w| w.csson().on());
+	}
+	else {
+		rcc.cr.modify(|_, w| w.csson().off().hseon().off());
+	}
+
+	rcc.cfgr.modify(|_, w| unsafe { w
+		.hpre().bits(settings.hpre)
+		.ppre1().bits(settings.ppre1)
+		.ppre2().bits(settings.ppre2)
+	});
+
+	if let Some(pll) = &settings.pll {
+		rcc.cfgr2.modify(|_, w| w.prediv().bits(pll.prediv - 1));
+		rcc.cfgr.modify(|_, w| {
+			match settings.source {
+				ClockSource::Hsi => w.pllsrc().hsi_div2(),
+				ClockSource::Hse => w.pllsrc().hse_div_prediv()
+			};
+			w.pllmul().bits(pll.mul - 2)
+		});
+
+		rcc.cr.modify(|_, w| w.pllon().on());
+		while rcc.cr.read().pllrdy().is_not_ready() {};
+
+		rcc.cfgr.modify(|_, w| w.sw().pll());
+		while !rcc.cfgr.read().sws().is_pll() {};
+	}
+	else if settings.source == ClockSource::Hse {
+		rcc.cfgr.modify(|_, w| w.sw().hse());
+		while !rcc.cfgr.read().sws().is_hse() {};
+	}
+
+	if settings.latency < flash::get_latency() {
+		flash::set_latency(settings.latency);
+	}
+
+	// Only needed while in use, the CSS turns it back on by itself
+	if settings.source == ClockSource::Hse {
+		rcc.cr.modify(|_, w| w.hsion().off());
+	}
+
+	Ok(())
+}
+
+fn decode_clocks(rcc: &stm32f303::RCC, hse: u32, lsi: u32) -> Clocks {
+	let cfgr = rcc.cfgr.read();
+	let cfgr2 = rcc.cfgr2.read();
+	let cfgr3 = rcc.cfgr3.read();
+
+	let prediv = cfgr2.prediv().bits() as u32 + 1;
+	let pllclk = if rcc.cr.read().pllrdy().is_ready() {
+		let pll_in = match cfgr.pllsrc().bits() {
+			0 => HSI_FREQ / 2,
+			1 => HSI_FREQ / prediv,
+			_ => hse / prediv
+		};
+		// PLLMUL 0b1111 is x16 just like 0b1110
+		pll_in * (cfgr.pllmul().bits() as u32 + 2).min(16)
+	} else { 0 };
+
+	let sysclk = if cfgr.sws().is_pll() { pllclk }
+		else if cfgr.sws().is_hse() { hse }
+		else { HSI_FREQ };
+
+	let hclk = sysclk / decode_prescaler(&AHB_PRESCALERS, cfgr.hpre().bits());
+	let ppre1 = decode_prescaler(&APB_PRESCALERS, cfgr.ppre1().bits());
+	let ppre2 = decode_prescaler(&APB_PRESCALERS, cfgr.ppre2().bits());
+	let pclk1 = hclk / ppre1;
+	let pclk2 = hclk / ppre2;
+	let timclk1 = if ppre1 == 1 { pclk1 } else { pclk1 * 2 };
+	let timclk2 = if ppre2 == 1 { pclk2 } else { pclk2 * 2 };
+
+	let lse_ready = rcc.bdcr.read().lserdy().is_ready();
+	let lclk = if lse_ready { config::LOW_SPEED_CLOCK } else { lsi };
+	let lse = if lse_ready { config::LOW_SPEED_CLOCK } else { 0 };
+	let hsi = if rcc.cr.read().hsirdy().is_ready() { HSI_FREQ } else { 0 };
+
+	let adc_clock = |pres: u8| if pres & 0x10 == 0 { 0 }
+		else { pllclk / ADC_PRESCALERS[((pres & 0x0F) as usize).min(ADC_PRESCALERS.len() - 1)] };
+
+	// TIM1/TIM8 can run straight from PLL x2
+	let tim_clock = |pll: bool| if pll { pllclk * 2 } else { timclk2 };
+
+	let usart_clock = |sw: u8, pclk: u32| match sw {
+		0 => pclk,
+		1 => sysclk,
+		2 => lse,
+		_ => hsi
 	};
 
-	free(|cs| CLOCKS.borrow(cs).set(clocks));
+	let i2c_clock = |sysclk_sel: bool| if sysclk_sel { sysclk } else { hsi };
+
+	Clocks {
+		sysclk,
+		hclk,
+		lclk,
+		pclk1,
+		pclk2,
+		pllclk,
+		timclk1,
+		timclk2,
+		tim1clk: tim_clock(cfgr3.tim1sw().bit_is_set()),
+		tim8clk: tim_clock(cfgr3.tim8sw().bit_is_set()),
+		adc12clk: adc_clock(cfgr2.adc12pres().bits()),
+		adc34clk: adc_clock(cfgr2.adc34pres().bits()),
+		usart1clk: usart_clock(cfgr3.usart1sw().bits(), pclk2),
+		usart2clk: usart_clock(cfgr3.usart2sw().bits(), pclk1),
+		usart3clk: usart_clock(cfgr3.usart3sw().bits(), pclk1),
+		i2c1clk: i2c_clock(cfgr3.i2c1sw().bit_is_set()),
+		i2c2clk: i2c_clock(cfgr3.i2c2sw().bit_is_set()),
+		i2c3clk: i2c_clock(cfgr3.i2c3sw().bit_is_set()),
+	}
+}
+
+fn decode_prescaler(prescalers: &[(u32, u8)], bits: u8) -> u32 {
+	prescalers.iter()
+		.find(|&&(_, b)| b == bits)
+		.map(|&(div, _)| div)
+		.unwrap_or(1)
+}
+
+//==============================================================================
+// Interrupt Handler
+//==============================================================================
+// The CSS is wired to the NMI. It cannot be masked by a critical section, so
+// the RCC is only touched through its raw pointer here and the recovery is
+// left to the task handler.
+#[exception]
+unsafe fn NonMaskableInt() {
+	let rcc = &*stm32f303::RCC::ptr();
+
+	if rcc.cir.read().cssf().bit_is_set() {
+		rcc.cir.modify(|_, w| w.cssc().clear());
+		CSS_TRIGGERED.store(true, Ordering::Relaxed);
+	}
 }
 
 //==============================================================================
 // Task Handler
 //==============================================================================
 pub fn task_handler() {
-
+	if CSS_TRIGGERED.swap(false, Ordering::Relaxed) {
+		handle_hse_failure();
+	}
 }
\ No newline at end of file
@@ -0,0 +1,316 @@
+//==============================================================================
+// Notes
+//==============================================================================
+// mcu/dma.rs
+
+/*
+ * DMA1 channel plumbing for the peripheral drivers. Requests are hard wired on
+ * the F303, each peripheral request has exactly one DMA1 channel, so a driver
+ * claims the channel its peripheral needs together with a callback for the
+ * channel's events. Callbacks run in the channel interrupt, outside any
+ * critical section. DMA2 is only held on to, nothing here drives it yet.
+ */
+
+//==============================================================================
+// Crates and Mods
+//==============================================================================
+use core::cell::{Cell, RefCell};
+// use core::ops::DerefMut;
+use cortex_m::interrupt::{free, Mutex};
+use cortex_m::peripheral::NVIC;
+use stm32f3::stm32f303;
+use stm32f3::stm32f303::interrupt;
+
+use crate::mcu::clocks;
+use crate::mcu::power::{self, PowerClient, PowerMode};
+
+//==============================================================================
+// Enums, Structs, and Types
+//==============================================================================
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DmaChannel {
+	Ch1 = 0,
+	Ch2,
+	Ch3,
+	Ch4,
+	Ch5,
+	Ch6,
+	Ch7
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DmaDirection {
+	PeripheralToMemory,
+	MemoryToPeripheral
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DmaEvent {
+	HalfTransfer,
+	TransferComplete,
+	TransferError
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DmaError {
+	ChannelInUse,
+	NotClaimed
+}
+
+// Byte transfers between a peripheral data register and memory
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub struct DmaTransfer {
+	pub direction: DmaDirection,
+	pub peripheral: u32,
+	pub memory: u32,
+	pub len: u16,
+	pub circular: bool,
+	pub half_transfer: bool
+}
+
+pub type DmaCallback = fn(DmaChannel, DmaEvent);
+
+//==============================================================================
+// Variables
+//==============================================================================
+static DMA1_HANDLE: Mutex<RefCell<Option<stm32f303::DMA1>>> =
+	Mutex::new(RefCell::new(None));
+static DMA2_HANDLE: Mutex<RefCell<Option<stm32f303::DMA2>>> =
+	Mutex::new(RefCell::new(None));
+
+const CHANNEL_COUNT: usize = 7;
+
+static CHANNEL_CALLBACKS: Mutex<Cell<[Option<DmaCallback>; CHANNEL_COUNT]>> =
+	Mutex::new(Cell::new([None; CHANNEL_COUNT]));
+
+// ISR/IFCR hold four bits per channel: global, complete, half, error
+const FLAG_COMPLETE: u32 = 0b0010;
+const FLAG_HALF: u32 = 0b0100;
+const FLAG_ERROR: u32 = 0b1000;
+const FLAG_ALL: u32 = 0b1111;
+
+//==============================================================================
+// Public Functions
+//==============================================================================
+pub fn init(
+	dma1: stm32f303::DMA1,
+	dma2: stm32f303::DMA2) {
+
+	free(|cs| DMA1_HANDLE.borrow(cs).replace(Some(dma1)));
+	free(|cs| DMA2_HANDLE.borrow(cs).replace(Some(dma2)));
+}
+
+#[allow(dead_code)]
+pub fn claim(channel: DmaChannel, callback: DmaCallback) -> Result<(), DmaError> {
+	free(|cs| {
+		let mut callbacks = CHANNEL_CALLBACKS.borrow(cs).get();
+		if callbacks[channel as usize].is_some() {
+			return Err(DmaError::ChannelInUse);
+		}
+
+		callbacks[channel as usize] = Some(callback);
+		CHANNEL_CALLBACKS.borrow(cs).set(callbacks);
+		Ok(())
+	})?;
+
+	clocks::set_ahb_peripheral_clock_enable(clocks::AhbPeripherals::DMA1, true);
+	unsafe { NVIC::unmask(interrupt(channel)) };
+	Ok(())
+}
+
+// Stops the channel and frees it for the next claim, giving back the DMA1
+// clock the claim took. Nothing happens for a channel that isn't claimed.
+#[allow(dead_code)]
+pub fn release(channel: DmaChannel) {
+	let claimed = free(|cs| {
+		let mut callbacks = CHANNEL_CALLBACKS.borrow(cs).get();
+		let claimed = callbacks[channel as usize].take().is_some();
+		CHANNEL_CALLBACKS.borrow(cs).set(callbacks);
+		claimed
+	});
+	if !claimed {
+		return;
+	}
+
+	stop(channel);
+	NVIC::mask(interrupt(channel));
+	clocks::set_ahb_peripheral_clock_enable(clocks::AhbPeripherals::DMA1, false);
+}
+
+/// # Safety
+/// `transfer.memory` has to stay valid, and untouched by anything else for a
+/// memory to peripheral transfer, until the channel is stopped or completes.
+#[allow(dead_code)]
+pub unsafe fn start(channel: DmaChannel, transfer: &DmaTransfer) -> Result<(), DmaError> {
+	if free(|cs| CHANNEL_CALLBACKS.borrow(cs).get()[channel as usize].is_none()) {
+		return Err(DmaError::NotClaimed);
+	}
+
+	// The DMA halts in Stop, task_handler gives the vote back once idle
+	power::set_mode_limit(PowerClient::Dma, PowerMode::Sleep);
+	with_dma(|dma| {
+		let ch = registers(dma, channel);
+		ch.cr.write(|w| w.en().clear_bit());
+		dma.ifcr.write(|w| w.bits(FLAG_ALL << (channel as u32 * 4)));
+		ch.par.write(|w| w.bits(transfer.peripheral));
+		ch.mar.write(|w| w.bits(transfer.memory));
+		ch.ndtr.write(|w| w.ndt().bits(transfer.len));
+		ch.cr.write(|w| w
+			.dir().bit(transfer.direction == DmaDirection::MemoryToPeripheral)
+			.circ().bit(transfer.circular)
+			.minc().set_bit()
+			.tcie().set_bit()
+			.htie().bit(transfer.half_transfer)
+			.teie().set_bit()
+			.en().set_bit()
+		);
+	});
+
+	Ok(())
+}
+
+#[allow(dead_code)]
+pub fn stop(channel: DmaChannel) {
+	with_dma(|dma| {
+		registers(dma, channel).cr.write(|w| w.en().clear_bit());
+		dma.ifcr.write(|w| unsafe { w.bits(FLAG_ALL << (channel as u32 * 4)) });
+	});
+}
+
+// Transfers still to go, counts down from DmaTransfer::len and reloads in
+// circular mode
+#[allow(dead_code)]
+pub fn get_remaining(channel: DmaChannel) -> u16 {
+	with_dma(|dma| registers(dma, channel).ndtr.read().ndt().bits()).unwrap_or(0)
+}
+
+//==============================================================================
+// Private Functions
+//==============================================================================
+fn registers(dma: &stm32f303::dma1::RegisterBlock, channel: DmaChannel) -> &stm32f303::dma1::CH {
+	match channel {
+		DmaChannel::Ch1 => &dma.ch1,
+		DmaChannel::Ch2 => &dma.ch2,
+		DmaChannel::Ch3 => &dma.ch3,
+		DmaChannel::Ch4 => &dma.ch4,
+		DmaChannel::Ch5 => &dma.ch5,
+		DmaChannel::Ch6 => &dma.ch6,
+		DmaChannel::Ch7 => &dma.ch7,
+	}
+}
+
+// EN stays set after a one-shot transfer completes, NDTR tells it apart
+fn is_active(dma: &stm32f303::dma1::RegisterBlock, channel: DmaChannel) -> bool {
+	let ch = registers(dma, channel);
+	let cr = ch.cr.read();
+	cr.en().bit_is_set() && (cr.circ().bit_is_set() || ch.ndtr.read().ndt().bits() != 0)
+}
+
+fn with_dma<R>(f: impl FnOnce(&stm32f303::dma1::RegisterBlock) -> R) -> Option<R> {
+	free(|cs| DMA1_HANDLE.borrow(cs).borrow().as_ref().map(|dma| f(dma)))
+}
+
+fn interrupt(channel: DmaChannel) -> stm32f303::Interrupt {
+	match channel {
+		DmaChannel::Ch1 => stm32f303::Interrupt::DMA1_CH1,
+		DmaChannel::Ch2 => stm32f303::Interrupt::DMA1_CH2,
+		DmaChannel::Ch3 => stm32f303::Interrupt::DMA1_CH3,
+		DmaChannel::Ch4 => stm32f303::Interrupt::DMA1_CH4,
+		DmaChannel::Ch5 => stm32f303::Interrupt::DMA1_CH5,
+		DmaChannel::Ch6 => stm32f303::Interrupt::DMA1_CH6,
+		DmaChannel::Ch7 => stm32f303::Interrupt::DMA1_CH7,
+	}
+}
+
+// Flags are cleared first so an event raised from the callback is not lost.
+// Only enabled events are reported, the half flag is set regardless of HTIE.
+fn handle_channel(channel: DmaChannel) {
+	let shift = channel as u32 * 4;
+	let (flags, enabled) = with_dma(|dma| {
+		let flags = (dma.isr.read().bits() >> shift) & FLAG_ALL;
+		let cr = registers(dma, channel).cr.read();
+		dma.ifcr.write(|w| unsafe { w.bits(flags << shift) });
+
+		let mut enabled = FLAG_COMPLETE | FLAG_ERROR;
+		if cr.htie().bit_is_set() {
+			enabled |= FLAG_HALF;
+		}
+		(flags, enabled)
+	}).unwrap_or((0, 0));
+
+	let callback = match free(|cs| CHANNEL_CALLBACKS.borrow(cs).get()[channel as usize]) {
+		Some(callback) => callback,
+		None => return,
+	};
+
+	let flags = flags & enabled;
+	if flags & FLAG_ERROR != 0 {
+		callback(channel, DmaEvent::TransferError);
+	}
+	if flags & FLAG_HALF != 0 {
+		callback(channel, DmaEvent::HalfTransfer);
+	}
+	if flags & FLAG_COMPLETE != 0 {
+		callback(channel, DmaEvent::TransferComplete);
+	}
+}
+
+//==============================================================================
+// Interrupt Handlers
+//==============================================================================
+#[interrupt]
+fn DMA1_CH1() {
+	handle_channel(DmaChannel::Ch1);
+}
+
+#[interrupt]
+fn DMA1_CH2() {
+	handle_channel(DmaChannel::Ch2);
+}
+
+#[interrupt]
+fn DMA1_CH3() {
+	handle_channel(DmaChannel::Ch3);
+}
+
+#[interrupt]
+fn DMA1_CH4() {
+	handle_channel(DmaChannel::Ch4);
+}
+
+#[interrupt]
+fn DMA1_CH5() {
+	handle_channel(DmaChannel::Ch5);
+}
+
+#[interrupt]
+fn DMA1_CH6() {
+	handle_channel(DmaChannel::Ch6);
+}
+
+#[interrupt]
+fn DMA1_CH7() {
+	handle_channel(DmaChannel::Ch7);
+}
+
+//==============================================================================
+// Task Handler
+//==============================================================================
+pub fn task_handler() {
+	let channels = [
+		DmaChannel::Ch1, DmaChannel::Ch2, DmaChannel::Ch3, DmaChannel::Ch4,
+		DmaChannel::Ch5, DmaChannel::Ch6, DmaChannel::Ch7
+	];
+
+	free(|_| {
+		let active = with_dma(|dma| channels.iter().any(|&channel| is_active(dma, channel))).unwrap_or(false);
+		if !active {
+			power::set_mode_limit(PowerClient::Dma, PowerMode::Standby);
+		}
+	});
+}
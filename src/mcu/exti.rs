@@ -0,0 +1,158 @@
+//==============================================================================
+// Notes
+//==============================================================================
+// mcu/exti.rs
+
+/*
+ * Lines 0-15 follow the GPIO pins selected in SYSCFG_EXTICRx. The rest are
+ * wired to internal sources, the ones used here being:
+ *   16 - PVD output
+ *   20 - RTC wakeup timer
+ *   25 - USART1 wakeup
+ *   26 - USART2 wakeup
+ *   28 - USART3 wakeup
+ * Lines 23-28 and 34-35 are "direct" lines: they have no edge selection and
+ * clear themselves when the peripheral flag is cleared.
+ */
+
+//==============================================================================
+// Crates and Mods
+//==============================================================================
+use core::cell::RefCell;
+use cortex_m::interrupt::{free, Mutex};
+use stm32f3::stm32f303;
+
+//==============================================================================
+// Enums, Structs, and Types
+//==============================================================================
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum Trigger {
+	Rising,
+	Falling,
+	Both
+}
+
+//==============================================================================
+// Variables
+//==============================================================================
+pub const LINE_PVD: u8 = 16;
+pub const LINE_RTC_WAKEUP: u8 = 20;
+pub const LINE_USART1: u8 = 25;
+pub const LINE_USART2: u8 = 26;
+pub const LINE_USART3: u8 = 28;
+
+const LINE_COUNT: u8 = 36;
+
+static EXTI_HANDLE: Mutex<RefCell<Option<stm32f303::EXTI>>> =
+	Mutex::new(RefCell::new(None));
+
+//==============================================================================
+// Public Functions
+//==============================================================================
+pub fn init(
+	exti: stm32f303::EXTI) {
+
+	free(|cs| EXTI_HANDLE.borrow(cs).replace(Some(exti)));
+}
+
+#[allow(dead_code)]
+pub fn set_trigger(line: u8, trigger: Trigger) {
+	let rising = trigger != Trigger::Falling;
+	let falling = trigger != Trigger::Rising;
+
+	free(|cs| if let Some(exti) = EXTI_HANDLE.borrow(cs).borrow().as_ref() {
+		if line < 32 {
+			let mask = 1 << line;
+			exti.rtsr1.modify(|r, w| unsafe { w.bits(set_mask(r.bits(), mask, rising)) });
+			exti.ftsr1.modify(|r, w| unsafe { w.bits(set_mask(r.bits(), mask, falling)) });
+		}
+		else if line < LINE_COUNT {
+			let mask = 1 << (line - 32);
+			exti.rtsr2.modify(|r, w| unsafe { w.bits(set_mask(r.bits(), mask, rising)) });
+			exti.ftsr2.modify(|r, w| unsafe { w.bits(set_mask(r.bits(), mask, falling)) });
+		}
+	});
+}
+
+#[allow(dead_code)]
+pub fn set_interrupt_enable(line: u8, enable: bool) {
+	free(|cs| if let Some(exti) = EXTI_HANDLE.borrow(cs).borrow().as_ref() {
+		if line < 32 {
+			exti.imr1.modify(|r, w| unsafe { w.bits(set_mask(r.bits(), 1 << line, enable)) });
+		}
+		else if line < LINE_COUNT {
+			exti.imr2.modify(|r, w| unsafe { w.bits(set_mask(r.bits(), 1 << (line - 32), enable)) });
+		}
+	});
+}
+
+// Events only wake the core from WFE/Stop, no interrupt handler runs
+#[allow(dead_code)]
+pub fn set_event_enable(line: u8, enable: bool) {
+	free(|cs| if let Some(exti) = EXTI_HANDLE.borrow(cs).borrow().as_ref() {
+		if line < 32 {
+			exti.emr1.modify(|r, w| unsafe { w.bits(set_mask(r.bits(), 1 << line, enable)) });
+		}
+		else if line < LINE_COUNT {
+			exti.emr2.modify(|r, w| unsafe { w.bits(set_mask(r.bits(), 1 << (line - 32), enable)) });
+		}
+	});
+}
+
+#[allow(dead_code)]
+pub fn get_pending(line: u8) -> bool {
+	free(|cs| if let Some(exti) = EXTI_HANDLE.borrow(cs).borrow().as_ref() {
+		if line < 32 { exti.pr1.read().bits() & (1 << line) != 0 }
+		else if line < LINE_COUNT { exti.pr2.read().bits() & (1 << (line - 32)) != 0 }
+		else { false }
+	} else { false })
+}
+
+#[allow(dead_code)]
+pub fn clear_pending(line: u8) {
+	free(|cs| if let Some(exti) = EXTI_HANDLE.borrow(cs).borrow().as_ref() {
+		// Write 1 to clear
+		if line < 32 {
+			exti.pr1.write(|w| unsafe { w.bits(1 << line) });
+		}
+		else if line < LINE_COUNT {
+			exti.pr2.write(|w| unsafe { w.bits(1 << (line - 32)) });
+		}
+	});
+}
+
+// Returns and clears the pending bits of lines 0-31 within `mask`
+pub fn take_pending(mask: u32) -> u32 {
+	free(|cs| if let Some(exti) = EXTI_HANDLE.borrow(cs).borrow().as_ref() {
+		let pending = exti.pr1.read().bits() & mask;
+		exti.pr1.write(|w| unsafe { w.bits(pending) });
+		pending
+	} else { 0 })
+}
+
+#[allow(dead_code)]
+pub fn software_trigger(line: u8) {
+	free(|cs| if let Some(exti) = EXTI_HANDLE.borrow(cs).borrow().as_ref() {
+		if line < 32 {
+			exti.swier1.write(|w| unsafe { w.bits(1 << line) });
+		}
+		else if line < LINE_COUNT {
+			exti.swier2.write(|w| unsafe { w.bits(1 << (line - 32)) });
+		}
+	});
+}
+
+//==============================================================================
+// Private Functions
+//==============================================================================
+fn set_mask(bits: u32, mask: u32, set: bool) -> u32 {
+	if set { bits | mask } else { bits & !mask }
+}
+
+//==============================================================================
+// Task Handler
+//==============================================================================
+pub fn task_handler() {
+
+}
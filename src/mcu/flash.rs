@@ -20,7 +20,13 @@ use stm32f3::stm32f303;
 //==============================================================================
 // Enums, Structs, and Types
 //==============================================================================
-
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum FlashLatency {
+	WaitState0,
+	WaitState1,
+	WaitState2
+}
 
 //==============================================================================
 // Variables
@@ -32,9 +38,41 @@ static FLASH_HANDLE: Mutex<RefCell<Option<stm32f303::FLASH>>> =
 // Public Functions
 //==============================================================================
 pub fn init(flash: stm32f303::FLASH) {
+	flash.acr.modify(|_, w| w.prftbe().enabled());
+
 	free(|cs| FLASH_HANDLE.borrow(cs).replace(Some(flash)));
 }
 
+#[allow(dead_code)]
+pub fn get_latency() -> FlashLatency {
+	free(|cs| if let Some(flash) = FLASH_HANDLE.borrow(cs).borrow().as_ref() {
+		let latency = flash.acr.read().latency();
+		if latency.is_ws2() { FlashLatency::WaitState2 }
+		else if latency.is_ws1() { FlashLatency::WaitState1 }
+		else { FlashLatency::WaitState0 }
+	} else { FlashLatency::WaitState0 })
+}
+
+// Wait states required for a given SYSCLK (RM0316 - 3.2.1 Flash access latency)
+pub fn get_latency_for_sysclk(sysclk: u32) -> FlashLatency {
+	if sysclk <= 24_000_000 { FlashLatency::WaitState0 }
+	else if sysclk <= 48_000_000 { FlashLatency::WaitState1 }
+	else { FlashLatency::WaitState2 }
+}
+
+pub fn set_latency(latency: FlashLatency) {
+	let latency = match latency {
+		FlashLatency::WaitState0 => stm32f303::flash::acr::LATENCY_A::WS0,
+		FlashLatency::WaitState1 => stm32f303::flash::acr::LATENCY_A::WS1,
+		FlashLatency::WaitState2 => stm32f303::flash::acr::LATENCY_A::WS2,
+	};
+
+	free(|cs| if let Some(flash) = FLASH_HANDLE.borrow(cs).borrow().as_ref() {
+		flash.acr.modify(|_, w| w.latency().variant(latency));
+		while flash.acr.read().latency().variant() != Some(latency) {};
+	});
+}
+
 //==============================================================================
 // Private Functions
 //==============================================================================
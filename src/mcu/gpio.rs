@@ -1,21 +1,61 @@
 //==============================================================================
 // Notes
 //==============================================================================
-// mcu/mod.rs
+// mcu/gpio.rs
+
+/*
+ * All eight ports share one register layout, so every port is addressed
+ * through the GPIOC..H register block type at its own base address. gpio owns
+ * the port handles, which is what makes reaching the registers by address
+ * sound.
+ *
+ * Configuration (MODER, OTYPER, PUPDR, AFRx) is read-modify-write and runs in
+ * a critical section. Output changes go through BSRR and are a single store,
+ * so set_pin_state() and toggle_pin() need no locking and are fast enough to
+ * bit-bang with.
+ *
+ * init() hands out every bonded pin of the F303VC once, as a Pin typed by its
+ * mode. Only the holder of a pin can reconfigure it, and drivers ask for the
+ * mode they need, e.g. uart takes Pin<AltFunc<7>>. Pins held by the clock
+ * tree (OSC_IN, OSC32_IN/OUT) are not handed out. Alternate functions are
+ * entered with the signal they carry, which pinmux checks against the AF
+ * table and records, so one signal cannot end up on two pins.
+ *
+ * lock_pins() freezes the configuration of pins until the next reset, after
+ * which every configuration call on them returns GpioError::PinLocked.
+ * Output levels can still be changed.
+ *
+ * Buttons handed to add_button() are sampled every task_handler() pass and
+ * debounced against the 1ms timer tick. Their events go to an optional
+ * callback, outside any critical section, and are kept for take_button_event().
+ *
+ * EXTI lines 0-15 follow the pin number, SYSCFG_EXTICRx picks which port
+ * drives each line, so PA0 and PB0 cannot both have one. Callbacks run in the
+ * interrupt, the per-line flags are for polling from a task handler.
+ */
 
 //==============================================================================
 // Crates and Mods
 //==============================================================================
-use core::cell::RefCell;
-use core::ops::DerefMut;
+use core::cell::{Cell, RefCell};
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU32, Ordering};
 use cortex_m::interrupt::{free, Mutex};
+use cortex_m::peripheral::{DWT, NVIC};
 use stm32f3::stm32f303;
+use stm32f3::stm32f303::gpioc::RegisterBlock;
+use stm32f3::stm32f303::interrupt;
+
+use crate::mcu::clocks;
+use crate::mcu::exti;
+use crate::mcu::pinmux;
+use crate::mcu::timer;
 
 //==============================================================================
 // Enums, Structs, and Types
 //==============================================================================
 #[allow(dead_code)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum GpioPort {
 	PortA,
 	PortB,
@@ -28,59 +68,520 @@ pub enum GpioPort {
 }
 
 #[allow(dead_code)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum GpioMode {
-	Input,
-	Output,
-	AltFunc,
-	Analog
+	Input = 0,
+	Output = 1,
+	AltFunc = 2,
+	Analog = 3
 }
 
 #[allow(dead_code)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum OutputType {
-	PushPull,
-	OpenDrain
+	PushPull = 0,
+	OpenDrain = 1
 }
 
 #[allow(dead_code)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputSpeed {
+	Low = 0,
+	Medium = 1,
+	High = 3
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum PinPull {
-	NoPull,
-	PullUp,
-	PullDown
+	NoPull = 0,
+	PullUp = 1,
+	PullDown = 2
 }
 
 #[allow(dead_code)]
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum PinState {
 	PinLow = 0,
 	PinHigh = 1
 }
 
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GpioError {
+	ExtiLineInUse,
+	ModeMismatch,
+	NotContiguous,
+	PinLocked,
+	LockFailed,
+	ButtonTableFull,
+	SignalRequired,
+	Pinmux(pinmux::PinmuxError)
+}
+
+// Interrupt runs the callback and sets the line flag, Event only produces a
+// wakeup event for WFE or Stop mode
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum ExtiMode {
+	Interrupt,
+	Event
+}
+
+// Called from the EXTI interrupt with the line number
+pub type ExtiCallback = fn(u8);
+
+// Everything that makes up a pin's setup, written in one go by
+// Pin::into_config() and decoded by get_pin_config()
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PinConfig {
+	pub mode: GpioMode,
+	pub pull: PinPull,
+	pub output_type: OutputType,
+	pub speed: OutputSpeed,
+	pub alt_func: u8,
+	pub signal: Option<pinmux::Signal>,
+	pub state: PinState
+}
+
+#[allow(dead_code)]
+impl PinConfig {
+	const fn new(mode: GpioMode, alt_func: u8) -> Self {
+		PinConfig {
+			mode,
+			pull: PinPull::NoPull,
+			output_type: OutputType::PushPull,
+			speed: OutputSpeed::Low,
+			alt_func,
+			signal: None,
+			state: PinState::PinLow
+		}
+	}
+
+	pub const fn input() -> Self { PinConfig::new(GpioMode::Input, 0) }
+	pub const fn output() -> Self { PinConfig::new(GpioMode::Output, 0) }
+	pub const fn alt_func(func: u8, signal: pinmux::Signal) -> Self {
+		let mut config = PinConfig::new(GpioMode::AltFunc, func & 0xF);
+		config.signal = Some(signal);
+		config
+	}
+	pub const fn analog() -> Self { PinConfig::new(GpioMode::Analog, 0) }
+
+	pub fn pull(mut self, pull: PinPull) -> Self {
+		self.pull = pull;
+		self
+	}
+
+	pub fn output_type(mut self, output_type: OutputType) -> Self {
+		self.output_type = output_type;
+		self
+	}
+
+	pub fn speed(mut self, speed: OutputSpeed) -> Self {
+		self.speed = speed;
+		self
+	}
+
+	pub fn state(mut self, state: PinState) -> Self {
+		self.state = state;
+		self
+	}
+}
+
+// Pin modes
+pub struct Input;
+pub struct Output;
+pub struct AltFunc<const AF: u8>;
+pub struct Analog;
+
+// Ties a pin type to the MODER/AFR setting it stands for
+pub trait PinMode {
+	const MODE: GpioMode;
+	const ALT_FUNC: u8 = 0;
+}
+
+impl PinMode for Input { const MODE: GpioMode = GpioMode::Input; }
+impl PinMode for Output { const MODE: GpioMode = GpioMode::Output; }
+impl PinMode for Analog { const MODE: GpioMode = GpioMode::Analog; }
+impl<const AF: u8> PinMode for AltFunc<AF> {
+	const MODE: GpioMode = GpioMode::AltFunc;
+	const ALT_FUNC: u8 = AF;
+}
+
+// Deliberately neither Clone nor Copy, there is exactly one of each
+pub struct Pin<MODE> {
+	port: GpioPort,
+	pin: u8,
+	_mode: PhantomData<MODE>
+}
+
+#[allow(dead_code)]
+impl<MODE> Pin<MODE> {
+	const fn new(port: GpioPort, pin: u8) -> Self {
+		Pin { port, pin, _mode: PhantomData }
+	}
+
+	pub fn port(&self) -> GpioPort { self.port }
+	pub fn pin(&self) -> u8 { self.pin }
+
+	#[inline]
+	pub fn get_state(&self) -> PinState {
+		get_pin_state(self.port, self.pin).unwrap_or(PinState::PinLow)
+	}
+
+	// On failure, a locked pin or a mismatched config, the pin is handed
+	// back untouched
+	pub fn into_input(self, pull: PinPull) -> Result<Pin<Input>, (Self, GpioError)> {
+		self.reconfigure(&PinConfig::input().pull(pull))
+	}
+
+	pub fn into_output(self, state: PinState) -> Result<Pin<Output>, (Self, GpioError)> {
+		self.reconfigure(&PinConfig::output().state(state))
+	}
+
+	// AF has to carry `signal` on this pin, and the signal must not already
+	// be routed elsewhere
+	pub fn into_alt_func<const AF: u8>(self, signal: pinmux::Signal) -> Result<Pin<AltFunc<AF>>, (Self, GpioError)> {
+		self.reconfigure(&PinConfig::alt_func(AF, signal))
+	}
+
+	pub fn into_analog(self) -> Result<Pin<Analog>, (Self, GpioError)> {
+		self.reconfigure(&PinConfig::analog())
+	}
+
+	// The mode and alternate function in `config` have to match the pin type
+	// asked for
+	pub fn into_config<NEW: PinMode>(self, config: &PinConfig) -> Result<Pin<NEW>, (Self, GpioError)> {
+		if config.mode != NEW::MODE || (NEW::MODE == GpioMode::AltFunc && config.alt_func != NEW::ALT_FUNC) {
+			return Err((self, GpioError::ModeMismatch));
+		}
+
+		self.reconfigure(config)
+	}
+
+	pub fn get_config(&self) -> PinConfig {
+		read_config(self.port, self.pin)
+	}
+
+	pub fn is_locked(&self) -> bool {
+		get_locked_pins(self.port) & (1 << self.pin) != 0
+	}
+
+	fn reconfigure<NEW>(self, config: &PinConfig) -> Result<Pin<NEW>, (Self, GpioError)> {
+		if self.is_locked() {
+			return Err((self, GpioError::PinLocked));
+		}
+
+		if config.mode == GpioMode::AltFunc {
+			let signal = match config.signal {
+				Some(signal) => signal,
+				None => return Err((self, GpioError::SignalRequired)),
+			};
+
+			if let Err(error) = pinmux::reassign(self.port, self.pin, config.alt_func, signal) {
+				return Err((self, GpioError::Pinmux(error)));
+			}
+		}
+		else {
+			pinmux::release(self.port, self.pin);
+		}
+
+		release_exti(self.port, self.pin);
+		apply_config(self.port, self.pin, config);
+		Ok(Pin::new(self.port, self.pin))
+	}
+
+	// Only matters for Output and AltFunc, e.g. open-drain I2C lines
+	pub fn set_output_type(&mut self, out_type: OutputType) -> Result<(), GpioError> {
+		if self.is_locked() {
+			return Err(GpioError::PinLocked);
+		}
+
+		set_output_type(self.port, self.pin, out_type);
+		Ok(())
+	}
+
+	pub fn set_speed(&mut self, speed: OutputSpeed) -> Result<(), GpioError> {
+		if self.is_locked() {
+			return Err(GpioError::PinLocked);
+		}
+
+		let gpio = registers(self.port);
+		free(|_| gpio.ospeedr.modify(|r, w| unsafe { w.bits(set_field_mask(r.bits(), 1 << self.pin, 2, speed as u32)) }));
+		Ok(())
+	}
+
+	pub fn set_pull(&mut self, pull: PinPull) -> Result<(), GpioError> {
+		if self.is_locked() {
+			return Err(GpioError::PinLocked);
+		}
+
+		let gpio = registers(self.port);
+		free(|_| gpio.pupdr.modify(|r, w| unsafe { w.bits(set_field_mask(r.bits(), 1 << self.pin, 2, pull as u32)) }));
+		Ok(())
+	}
+}
+
+#[allow(dead_code)]
+impl Pin<Input> {
+	// Routes this pin to EXTI line `pin`. Fails if another port already has
+	// the line.
+	pub fn enable_exti(&mut self, trigger: exti::Trigger, mode: ExtiMode, callback: Option<ExtiCallback>) -> Result<(), GpioError> {
+		let line = self.pin;
+
+		free(|cs| {
+			let mut owners = EXTI_OWNERS.borrow(cs).get();
+			match owners[line as usize] {
+				Some(port) if port != self.port => return Err(GpioError::ExtiLineInUse),
+				_ => owners[line as usize] = Some(self.port),
+			}
+			EXTI_OWNERS.borrow(cs).set(owners);
+
+			let mut callbacks = EXTI_CALLBACKS.borrow(cs).get();
+			callbacks[line as usize] = callback;
+			EXTI_CALLBACKS.borrow(cs).set(callbacks);
+
+			Ok(())
+		})?;
+
+		set_exti_source(self.port, line);
+		exti::set_trigger(line, trigger);
+		exti::clear_pending(line);
+		exti::set_interrupt_enable(line, mode == ExtiMode::Interrupt);
+		exti::set_event_enable(line, mode == ExtiMode::Event);
+
+		if mode == ExtiMode::Interrupt {
+			unsafe { NVIC::unmask(exti_interrupt(line)) };
+		}
+
+		Ok(())
+	}
+
+	pub fn disable_exti(&mut self) {
+		release_exti(self.port, self.pin);
+	}
+
+	// Raises the line from software, as if the selected edge had occurred
+	pub fn trigger_exti(&self) {
+		exti::software_trigger(self.pin);
+	}
+
+	// True once per edge seen since the last call
+	pub fn take_exti_flag(&self) -> bool {
+		let mask = 1 << self.pin;
+		EXTI_FLAGS.fetch_and(!mask, Ordering::Relaxed) & mask != 0
+	}
+}
+
+#[allow(dead_code)]
+impl Pin<Output> {
+	#[inline]
+	pub fn set_state(&mut self, state: PinState) {
+		set_pin_state(self.port, self.pin, state);
+	}
+
+	#[inline]
+	pub fn set_high(&mut self) {
+		set_pin_state(self.port, self.pin, PinState::PinHigh);
+	}
+
+	#[inline]
+	pub fn set_low(&mut self) {
+		set_pin_state(self.port, self.pin, PinState::PinLow);
+	}
+
+	#[inline]
+	pub fn toggle(&mut self) {
+		toggle_pin(self.port, self.pin);
+	}
+
+	// The driven level from ODR, as opposed to get_state() which reads the pad
+	pub fn get_output_state(&self) -> PinState {
+		if registers(self.port).odr.read().bits() & (1 << self.pin) != 0 { PinState::PinHigh } else { PinState::PinLow }
+	}
+}
+
+// N output pins of one port, consecutive and in ascending order, written as
+// one value with a single BSRR store so every line changes together
+pub struct ParallelBus<const N: usize> {
+	pins: [Pin<Output>; N],
+	port: GpioPort,
+	shift: u8,
+	mask: u16
+}
+
+#[allow(dead_code)]
+impl<const N: usize> ParallelBus<N> {
+	// pins[0] becomes bit 0 of the bus. On failure the pins are handed back.
+	pub fn new(pins: [Pin<Output>; N]) -> Result<Self, ([Pin<Output>; N], GpioError)> {
+		if N == 0 || N > PIN_COUNT as usize {
+			return Err((pins, GpioError::NotContiguous));
+		}
+
+		let port = pins[0].port;
+		let shift = pins[0].pin;
+		let contiguous = pins.iter()
+			.enumerate()
+			.all(|(i, pin)| pin.port == port && pin.pin as usize == shift as usize + i);
+
+		if !contiguous {
+			return Err((pins, GpioError::NotContiguous));
+		}
+
+		let mask = (((1u32 << N) - 1) << shift) as u16;
+		Ok(ParallelBus { pins, port, shift, mask })
+	}
+
+	#[inline]
+	pub fn write(&mut self, value: u16) {
+		let set = (value << self.shift) & self.mask;
+		write_port(self.port, set, !set & self.mask);
+	}
+
+	// The pad levels, not what was last written
+	#[inline]
+	pub fn read(&self) -> u16 {
+		(read_port(self.port) & self.mask) >> self.shift
+	}
+
+	pub fn release(self) -> [Pin<Output>; N] {
+		self.pins
+	}
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ButtonEvent {
+	Press,
+	Release,
+	LongPress,
+	DoubleClick
+}
+
+// In milliseconds. A double click is a press within double_click of the
+// release of a short press, it is reported after the second Press.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub struct ButtonTimings {
+	pub debounce: u32,
+	pub long_press: u32,
+	pub double_click: u32
+}
+
+impl Default for ButtonTimings {
+	fn default() -> Self {
+		ButtonTimings { debounce: 20, long_press: 1000, double_click: 300 }
+	}
+}
+
+// Called from task_handler() with the id add_button() returned
+pub type ButtonCallback = fn(usize, ButtonEvent);
+
+struct Button {
+	pin: Pin<Input>,
+	active: PinState,
+	timings: ButtonTimings,
+	callback: Option<ButtonCallback>,
+	raw: bool,
+	pressed: bool,
+	changed_at: u32,
+	pressed_at: u32,
+	long_sent: bool,
+	double_sent: bool,
+	released_at: Option<u32>,
+	event: Option<ButtonEvent>
+}
+
+// Average cycles per call, loop overhead removed
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub struct GpioBenchmark {
+	pub set: u32,
+	pub toggle: u32,
+	pub read: u32
+}
+
+// Pins in their reset state. The JTAG/SWD pins come up in AF0.
+macro_rules! pins {
+	($($name:ident: $port:ident $pin:literal $mode:ty),* $(,)?) => {
+		#[allow(dead_code)]
+		pub struct Pins {
+			$(pub $name: Pin<$mode>,)*
+		}
+
+		impl Pins {
+			fn new() -> Self {
+				Pins {
+					$($name: Pin::new(GpioPort::$port, $pin),)*
+				}
+			}
+		}
+	};
+}
+
+pins!(
+	pa0: PortA 0 Input, pa1: PortA 1 Input, pa2: PortA 2 Input, pa3: PortA 3 Input,
+	pa4: PortA 4 Input, pa5: PortA 5 Input, pa6: PortA 6 Input, pa7: PortA 7 Input,
+	pa8: PortA 8 Input, pa9: PortA 9 Input, pa10: PortA 10 Input, pa11: PortA 11 Input,
+	pa12: PortA 12 Input, pa13: PortA 13 AltFunc<0>, pa14: PortA 14 AltFunc<0>, pa15: PortA 15 AltFunc<0>,
+
+	pb0: PortB 0 Input, pb1: PortB 1 Input, pb2: PortB 2 Input, pb3: PortB 3 AltFunc<0>,
+	pb4: PortB 4 AltFunc<0>, pb5: PortB 5 Input, pb6: PortB 6 Input, pb7: PortB 7 Input,
+	pb8: PortB 8 Input, pb9: PortB 9 Input, pb10: PortB 10 Input, pb11: PortB 11 Input,
+	pb12: PortB 12 Input, pb13: PortB 13 Input, pb14: PortB 14 Input, pb15: PortB 15 Input,
+
+	pc0: PortC 0 Input, pc1: PortC 1 Input, pc2: PortC 2 Input, pc3: PortC 3 Input,
+	pc4: PortC 4 Input, pc5: PortC 5 Input, pc6: PortC 6 Input, pc7: PortC 7 Input,
+	pc8: PortC 8 Input, pc9: PortC 9 Input, pc10: PortC 10 Input, pc11: PortC 11 Input,
+	pc12: PortC 12 Input, pc13: PortC 13 Input,
+
+	pd0: PortD 0 Input, pd1: PortD 1 Input, pd2: PortD 2 Input, pd3: PortD 3 Input,
+	pd4: PortD 4 Input, pd5: PortD 5 Input, pd6: PortD 6 Input, pd7: PortD 7 Input,
+	pd8: PortD 8 Input, pd9: PortD 9 Input, pd10: PortD 10 Input, pd11: PortD 11 Input,
+	pd12: PortD 12 Input, pd13: PortD 13 Input, pd14: PortD 14 Input, pd15: PortD 15 Input,
+
+	pe0: PortE 0 Input, pe1: PortE 1 Input, pe2: PortE 2 Input, pe3: PortE 3 Input,
+	pe4: PortE 4 Input, pe5: PortE 5 Input, pe6: PortE 6 Input, pe7: PortE 7 Input,
+	pe8: PortE 8 Input, pe9: PortE 9 Input, pe10: PortE 10 Input, pe11: PortE 11 Input,
+	pe12: PortE 12 Input, pe13: PortE 13 Input, pe14: PortE 14 Input, pe15: PortE 15 Input,
+
+	pf1: PortF 1 Input, pf2: PortF 2 Input, pf4: PortF 4 Input, pf6: PortF 6 Input,
+	pf9: PortF 9 Input, pf10: PortF 10 Input,
+);
+
 //==============================================================================
 // Variables
 //==============================================================================
-static GPIOA_HANDLE: Mutex<RefCell<Option<stm32f303::GPIOA>>> = 
-	Mutex::new(RefCell::new(None));
-static GPIOB_HANDLE: Mutex<RefCell<Option<stm32f303::GPIOB>>> = 
-	Mutex::new(RefCell::new(None));
-static GPIOC_HANDLE: Mutex<RefCell<Option<stm32f303::GPIOC>>> = 
-	Mutex::new(RefCell::new(None));
-static GPIOD_HANDLE: Mutex<RefCell<Option<stm32f303::GPIOD>>> = 
-	Mutex::new(RefCell::new(None));
-static GPIOE_HANDLE: Mutex<RefCell<Option<stm32f303::GPIOE>>> = 
-	Mutex::new(RefCell::new(None));
-static GPIOF_HANDLE: Mutex<RefCell<Option<stm32f303::GPIOF>>> = 
-	Mutex::new(RefCell::new(None));
-static GPIOG_HANDLE: Mutex<RefCell<Option<stm32f303::GPIOG>>> = 
-	Mutex::new(RefCell::new(None));
-static GPIOH_HANDLE: Mutex<RefCell<Option<stm32f303::GPIOH>>> = 
+const PIN_COUNT: u8 = 16;
+const BENCHMARK_ITERATIONS: u32 = 1000;
+// DCB DEMCR trace enable, the DWT only counts with it set
+const DEMCR_TRCENA: u32 = 1 << 24;
+
+static EXTI_OWNERS: Mutex<Cell<[Option<GpioPort>; PIN_COUNT as usize]>> =
+	Mutex::new(Cell::new([None; PIN_COUNT as usize]));
+
+static EXTI_CALLBACKS: Mutex<Cell<[Option<ExtiCallback>; PIN_COUNT as usize]>> =
+	Mutex::new(Cell::new([None; PIN_COUNT as usize]));
+
+// One bit per line, set in the interrupt
+static EXTI_FLAGS: AtomicU32 = AtomicU32::new(0);
+
+static SYSCFG_HANDLE: Mutex<RefCell<Option<stm32f303::SYSCFG>>> =
 	Mutex::new(RefCell::new(None));
 
+const MAX_BUTTONS: usize = 8;
+// Press and DoubleClick can both come out of one pass
+const MAX_BUTTON_EVENTS: usize = MAX_BUTTONS * 2;
+
+const NO_BUTTON: Option<Button> = None;
+static BUTTONS: Mutex<RefCell<[Option<Button>; MAX_BUTTONS]>> =
+	Mutex::new(RefCell::new([NO_BUTTON; MAX_BUTTONS]));
+
 //==============================================================================
 // Public Functions
 //==============================================================================
+#[allow(clippy::too_many_arguments)]
 pub fn init(
 	gpioa: stm32f303::GPIOA,
 	gpiob: stm32f303::GPIOB,
@@ -89,1211 +590,473 @@ pub fn init(
 	gpioe: stm32f303::GPIOE,
 	gpiof: stm32f303::GPIOF,
 	gpiog: stm32f303::GPIOG,
-	gpioh: stm32f303::GPIOH) {
-
-	free(|cs| GPIOA_HANDLE.borrow(cs).replace(Some(gpioa)));
-	free(|cs| GPIOB_HANDLE.borrow(cs).replace(Some(gpiob)));
-	free(|cs| GPIOC_HANDLE.borrow(cs).replace(Some(gpioc)));
-	free(|cs| GPIOD_HANDLE.borrow(cs).replace(Some(gpiod)));
-	free(|cs| GPIOE_HANDLE.borrow(cs).replace(Some(gpioe)));
-	free(|cs| GPIOF_HANDLE.borrow(cs).replace(Some(gpiof)));
-	free(|cs| GPIOG_HANDLE.borrow(cs).replace(Some(gpiog)));
-	free(|cs| GPIOH_HANDLE.borrow(cs).replace(Some(gpioh)));
+	gpioh: stm32f303::GPIOH,
+	syscfg: stm32f303::SYSCFG) -> Pins {
+
+	// Taken for ownership only, see registers()
+	let _ = (gpioa, gpiob, gpioc, gpiod, gpioe, gpiof, gpiog, gpioh);
+
+	for port in [
+		clocks::AhbPeripherals::IOPA,
+		clocks::AhbPeripherals::IOPB,
+		clocks::AhbPeripherals::IOPC,
+		clocks::AhbPeripherals::IOPD,
+		clocks::AhbPeripherals::IOPE,
+		clocks::AhbPeripherals::IOPF].iter() {
+		clocks::set_ahb_peripheral_clock_enable(*port, true);
+	}
+
+	clocks::set_apb2_peripheral_clock_enable(clocks::Apb2Peripherals::SYSCFG, true);
+	free(|cs| SYSCFG_HANDLE.borrow(cs).replace(Some(syscfg)));
+
+	Pins::new()
 }
 
 #[allow(dead_code)]
+#[inline]
 pub fn get_pin_state(port: GpioPort, pin: u8) -> Option<PinState> {
-	free(|cs| {
-		match port {
-			GpioPort::PortA => if let Some(ref mut gpio) = GPIOA_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => if gpio.idr.read().idr0().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					1 => if gpio.idr.read().idr1().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					2 => if gpio.idr.read().idr2().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					3 => if gpio.idr.read().idr3().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					4 => if gpio.idr.read().idr4().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					5 => if gpio.idr.read().idr5().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					6 => if gpio.idr.read().idr6().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					7 => if gpio.idr.read().idr7().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					8 => if gpio.idr.read().idr8().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					9 => if gpio.idr.read().idr9().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					10 => if gpio.idr.read().idr10().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					11 => if gpio.idr.read().idr11().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					12 => if gpio.idr.read().idr12().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					13 => if gpio.idr.read().idr13().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					14 => if gpio.idr.read().idr14().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					15 => if gpio.idr.read().idr15().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					_ => None
-				}
-			} else { None },
-			GpioPort::PortB => if let Some(ref mut gpio) = GPIOB_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => if gpio.idr.read().idr0().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					1 => if gpio.idr.read().idr1().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					2 => if gpio.idr.read().idr2().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					3 => if gpio.idr.read().idr3().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					4 => if gpio.idr.read().idr4().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					5 => if gpio.idr.read().idr5().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					6 => if gpio.idr.read().idr6().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					7 => if gpio.idr.read().idr7().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					8 => if gpio.idr.read().idr8().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					9 => if gpio.idr.read().idr9().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					10 => if gpio.idr.read().idr10().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					11 => if gpio.idr.read().idr11().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					12 => if gpio.idr.read().idr12().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					13 => if gpio.idr.read().idr13().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					14 => if gpio.idr.read().idr14().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					15 => if gpio.idr.read().idr15().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					_ => None
-				}
-			} else { None },
-			GpioPort::PortC => if let Some(ref mut gpio) = GPIOC_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => if gpio.idr.read().idr0().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					1 => if gpio.idr.read().idr1().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					2 => if gpio.idr.read().idr2().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					3 => if gpio.idr.read().idr3().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					4 => if gpio.idr.read().idr4().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					5 => if gpio.idr.read().idr5().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					6 => if gpio.idr.read().idr6().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					7 => if gpio.idr.read().idr7().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					8 => if gpio.idr.read().idr8().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					9 => if gpio.idr.read().idr9().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					10 => if gpio.idr.read().idr10().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					11 => if gpio.idr.read().idr11().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					12 => if gpio.idr.read().idr12().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					13 => if gpio.idr.read().idr13().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					14 => if gpio.idr.read().idr14().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					15 => if gpio.idr.read().idr15().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					_ => None
-				}
-			} else { None },
-			GpioPort::PortD => if let Some(ref mut gpio) = GPIOD_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => if gpio.idr.read().idr0().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					1 => if gpio.idr.read().idr1().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					2 => if gpio.idr.read().idr2().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					3 => if gpio.idr.read().idr3().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					4 => if gpio.idr.read().idr4().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					5 => if gpio.idr.read().idr5().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					6 => if gpio.idr.read().idr6().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					7 => if gpio.idr.read().idr7().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					8 => if gpio.idr.read().idr8().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					9 => if gpio.idr.read().idr9().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					10 => if gpio.idr.read().idr10().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					11 => if gpio.idr.read().idr11().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					12 => if gpio.idr.read().idr12().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					13 => if gpio.idr.read().idr13().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					14 => if gpio.idr.read().idr14().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					15 => if gpio.idr.read().idr15().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					_ => None
-				}
-			} else { None },
-			GpioPort::PortE => if let Some(ref mut gpio) = GPIOE_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => if gpio.idr.read().idr0().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					1 => if gpio.idr.read().idr1().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					2 => if gpio.idr.read().idr2().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					3 => if gpio.idr.read().idr3().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					4 => if gpio.idr.read().idr4().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					5 => if gpio.idr.read().idr5().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					6 => if gpio.idr.read().idr6().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					7 => if gpio.idr.read().idr7().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					8 => if gpio.idr.read().idr8().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					9 => if gpio.idr.read().idr9().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					10 => if gpio.idr.read().idr10().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					11 => if gpio.idr.read().idr11().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					12 => if gpio.idr.read().idr12().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					13 => if gpio.idr.read().idr13().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					14 => if gpio.idr.read().idr14().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					15 => if gpio.idr.read().idr15().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					_ => None
-				}
-			} else { None },
-			GpioPort::PortF => if let Some(ref mut gpio) = GPIOF_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => if gpio.idr.read().idr0().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					1 => if gpio.idr.read().idr1().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					2 => if gpio.idr.read().idr2().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					3 => if gpio.idr.read().idr3().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					4 => if gpio.idr.read().idr4().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					5 => if gpio.idr.read().idr5().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					6 => if gpio.idr.read().idr6().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					7 => if gpio.idr.read().idr7().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					8 => if gpio.idr.read().idr8().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					9 => if gpio.idr.read().idr9().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					10 => if gpio.idr.read().idr10().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					11 => if gpio.idr.read().idr11().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					12 => if gpio.idr.read().idr12().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					13 => if gpio.idr.read().idr13().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					14 => if gpio.idr.read().idr14().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					15 => if gpio.idr.read().idr15().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					_ => None
-				}
-			} else { None },
-			GpioPort::PortG => if let Some(ref mut gpio) = GPIOG_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => if gpio.idr.read().idr0().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					1 => if gpio.idr.read().idr1().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					2 => if gpio.idr.read().idr2().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					3 => if gpio.idr.read().idr3().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					4 => if gpio.idr.read().idr4().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					5 => if gpio.idr.read().idr5().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					6 => if gpio.idr.read().idr6().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					7 => if gpio.idr.read().idr7().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					8 => if gpio.idr.read().idr8().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					9 => if gpio.idr.read().idr9().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					10 => if gpio.idr.read().idr10().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					11 => if gpio.idr.read().idr11().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					12 => if gpio.idr.read().idr12().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					13 => if gpio.idr.read().idr13().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					14 => if gpio.idr.read().idr14().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					15 => if gpio.idr.read().idr15().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					_ => None
-				}
-			} else { None },
-			GpioPort::PortH => if let Some(ref mut gpio) = GPIOH_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => if gpio.idr.read().idr0().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					1 => if gpio.idr.read().idr1().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					2 => if gpio.idr.read().idr2().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					3 => if gpio.idr.read().idr3().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					4 => if gpio.idr.read().idr4().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					5 => if gpio.idr.read().idr5().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					6 => if gpio.idr.read().idr6().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					7 => if gpio.idr.read().idr7().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					8 => if gpio.idr.read().idr8().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					9 => if gpio.idr.read().idr9().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					10 => if gpio.idr.read().idr10().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					11 => if gpio.idr.read().idr11().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					12 => if gpio.idr.read().idr12().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					13 => if gpio.idr.read().idr13().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					14 => if gpio.idr.read().idr14().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					15 => if gpio.idr.read().idr15().is_high() { Some(PinState::PinHigh) } else { Some(PinState::PinLow) },
-					_ => None
-				}
-			} else { None },
-		}
-	})
+	if pin >= PIN_COUNT {
+		return None;
+	}
+
+	if registers(port).idr.read().bits() & (1 << pin) != 0 { Some(PinState::PinHigh) } else { Some(PinState::PinLow) }
 }
 
+// All 16 IDR bits in one read
 #[allow(dead_code)]
-pub fn pin_setup(port: GpioPort, pin: u8, mode: GpioMode, pull: PinPull, state: PinState) {
-	set_pin_mode(port, pin, mode);
-	set_pin_pull(port, pin, pull);
-	set_pin_state(port, pin, state);
+#[inline]
+pub fn read_port(port: GpioPort) -> u16 {
+	registers(port).idr.read().bits() as u16
 }
 
+// Sets and clears any mix of pins in one BSRR store, a pin in both masks ends
+// up set. This bypasses pin ownership, prefer a ParallelBus where possible.
 #[allow(dead_code)]
-pub fn set_alt_func(port: GpioPort, pin: u8, func: u8) {
-	let func = func & 0xF;
+#[inline]
+pub fn write_port(port: GpioPort, set: u16, reset: u16) {
+	registers(port).bsrr.write(|w| unsafe { w.bits((reset as u32) << 16 | set as u32) });
+}
 
-	free(|cs| {
-		match port {
-			GpioPort::PortA => if let Some(ref mut gpio) = GPIOA_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => gpio.afrl.modify(|_, w| w.afrl0().bits(func)),
-					1 => gpio.afrl.modify(|_, w| w.afrl1().bits(func)),
-					2 => gpio.afrl.modify(|_, w| w.afrl2().bits(func)),
-					3 => gpio.afrl.modify(|_, w| w.afrl3().bits(func)),
-					4 => gpio.afrl.modify(|_, w| w.afrl4().bits(func)),
-					5 => gpio.afrl.modify(|_, w| w.afrl5().bits(func)),
-					6 => gpio.afrl.modify(|_, w| w.afrl6().bits(func)),
-					7 => gpio.afrl.modify(|_, w| w.afrl7().bits(func)),
-					8 => gpio.afrh.modify(|_, w| w.afrh8().bits(func)),
-					9 => gpio.afrh.modify(|_, w| w.afrh9().bits(func)),
-					10 => gpio.afrh.modify(|_, w| w.afrh10().bits(func)),
-					11 => gpio.afrh.modify(|_, w| w.afrh11().bits(func)),
-					12 => gpio.afrh.modify(|_, w| w.afrh12().bits(func)),
-					13 => gpio.afrh.modify(|_, w| w.afrh13().bits(func)),
-					14 => gpio.afrh.modify(|_, w| w.afrh14().bits(func)),
-					15 => gpio.afrh.modify(|_, w| w.afrh15().bits(func)),
-					_ => ()
-				};
-			},
-			GpioPort::PortB => if let Some(ref mut gpio) = GPIOB_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => gpio.afrl.modify(|_, w| w.afrl0().bits(func)),
-					1 => gpio.afrl.modify(|_, w| w.afrl1().bits(func)),
-					2 => gpio.afrl.modify(|_, w| w.afrl2().bits(func)),
-					3 => gpio.afrl.modify(|_, w| w.afrl3().bits(func)),
-					4 => gpio.afrl.modify(|_, w| w.afrl4().bits(func)),
-					5 => gpio.afrl.modify(|_, w| w.afrl5().bits(func)),
-					6 => gpio.afrl.modify(|_, w| w.afrl6().bits(func)),
-					7 => gpio.afrl.modify(|_, w| w.afrl7().bits(func)),
-					8 => gpio.afrh.modify(|_, w| w.afrh8().bits(func)),
-					9 => gpio.afrh.modify(|_, w| w.afrh9().bits(func)),
-					10 => gpio.afrh.modify(|_, w| w.afrh10().bits(func)),
-					11 => gpio.afrh.modify(|_, w| w.afrh11().bits(func)),
-					12 => gpio.afrh.modify(|_, w| w.afrh12().bits(func)),
-					13 => gpio.afrh.modify(|_, w| w.afrh13().bits(func)),
-					14 => gpio.afrh.modify(|_, w| w.afrh14().bits(func)),
-					15 => gpio.afrh.modify(|_, w| w.afrh15().bits(func)),
-					_ => ()
-				};
-			},
-			GpioPort::PortC => if let Some(ref mut gpio) = GPIOC_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => gpio.afrl.modify(|_, w| w.afrl0().bits(func)),
-					1 => gpio.afrl.modify(|_, w| w.afrl1().bits(func)),
-					2 => gpio.afrl.modify(|_, w| w.afrl2().bits(func)),
-					3 => gpio.afrl.modify(|_, w| w.afrl3().bits(func)),
-					4 => gpio.afrl.modify(|_, w| w.afrl4().bits(func)),
-					5 => gpio.afrl.modify(|_, w| w.afrl5().bits(func)),
-					6 => gpio.afrl.modify(|_, w| w.afrl6().bits(func)),
-					7 => gpio.afrl.modify(|_, w| w.afrl7().bits(func)),
-					8 => gpio.afrh.modify(|_, w| w.afrh8().bits(func)),
-					9 => gpio.afrh.modify(|_, w| w.afrh9().bits(func)),
-					10 => gpio.afrh.modify(|_, w| w.afrh10().bits(func)),
-					11 => gpio.afrh.modify(|_, w| w.afrh11().bits(func)),
-					12 => gpio.afrh.modify(|_, w| w.afrh12().bits(func)),
-					13 => gpio.afrh.modify(|_, w| w.afrh13().bits(func)),
-					14 => gpio.afrh.modify(|_, w| w.afrh14().bits(func)),
-					15 => gpio.afrh.modify(|_, w| w.afrh15().bits(func)),
-					_ => ()
-				};
-			},
-			GpioPort::PortD => if let Some(ref mut gpio) = GPIOD_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => gpio.afrl.modify(|_, w| w.afrl0().bits(func)),
-					1 => gpio.afrl.modify(|_, w| w.afrl1().bits(func)),
-					2 => gpio.afrl.modify(|_, w| w.afrl2().bits(func)),
-					3 => gpio.afrl.modify(|_, w| w.afrl3().bits(func)),
-					4 => gpio.afrl.modify(|_, w| w.afrl4().bits(func)),
-					5 => gpio.afrl.modify(|_, w| w.afrl5().bits(func)),
-					6 => gpio.afrl.modify(|_, w| w.afrl6().bits(func)),
-					7 => gpio.afrl.modify(|_, w| w.afrl7().bits(func)),
-					8 => gpio.afrh.modify(|_, w| w.afrh8().bits(func)),
-					9 => gpio.afrh.modify(|_, w| w.afrh9().bits(func)),
-					10 => gpio.afrh.modify(|_, w| w.afrh10().bits(func)),
-					11 => gpio.afrh.modify(|_, w| w.afrh11().bits(func)),
-					12 => gpio.afrh.modify(|_, w| w.afrh12().bits(func)),
-					13 => gpio.afrh.modify(|_, w| w.afrh13().bits(func)),
-					14 => gpio.afrh.modify(|_, w| w.afrh14().bits(func)),
-					15 => gpio.afrh.modify(|_, w| w.afrh15().bits(func)),
-					_ => ()
-				};
-			},
-			GpioPort::PortE => if let Some(ref mut gpio) = GPIOE_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => gpio.afrl.modify(|_, w| w.afrl0().bits(func)),
-					1 => gpio.afrl.modify(|_, w| w.afrl1().bits(func)),
-					2 => gpio.afrl.modify(|_, w| w.afrl2().bits(func)),
-					3 => gpio.afrl.modify(|_, w| w.afrl3().bits(func)),
-					4 => gpio.afrl.modify(|_, w| w.afrl4().bits(func)),
-					5 => gpio.afrl.modify(|_, w| w.afrl5().bits(func)),
-					6 => gpio.afrl.modify(|_, w| w.afrl6().bits(func)),
-					7 => gpio.afrl.modify(|_, w| w.afrl7().bits(func)),
-					8 => gpio.afrh.modify(|_, w| w.afrh8().bits(func)),
-					9 => gpio.afrh.modify(|_, w| w.afrh9().bits(func)),
-					10 => gpio.afrh.modify(|_, w| w.afrh10().bits(func)),
-					11 => gpio.afrh.modify(|_, w| w.afrh11().bits(func)),
-					12 => gpio.afrh.modify(|_, w| w.afrh12().bits(func)),
-					13 => gpio.afrh.modify(|_, w| w.afrh13().bits(func)),
-					14 => gpio.afrh.modify(|_, w| w.afrh14().bits(func)),
-					15 => gpio.afrh.modify(|_, w| w.afrh15().bits(func)),
-					_ => ()
-				};
-			},
-			GpioPort::PortF => if let Some(ref mut gpio) = GPIOF_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => gpio.afrl.modify(|_, w| w.afrl0().bits(func)),
-					1 => gpio.afrl.modify(|_, w| w.afrl1().bits(func)),
-					2 => gpio.afrl.modify(|_, w| w.afrl2().bits(func)),
-					3 => gpio.afrl.modify(|_, w| w.afrl3().bits(func)),
-					4 => gpio.afrl.modify(|_, w| w.afrl4().bits(func)),
-					5 => gpio.afrl.modify(|_, w| w.afrl5().bits(func)),
-					6 => gpio.afrl.modify(|_, w| w.afrl6().bits(func)),
-					7 => gpio.afrl.modify(|_, w| w.afrl7().bits(func)),
-					8 => gpio.afrh.modify(|_, w| w.afrh8().bits(func)),
-					9 => gpio.afrh.modify(|_, w| w.afrh9().bits(func)),
-					10 => gpio.afrh.modify(|_, w| w.afrh10().bits(func)),
-					11 => gpio.afrh.modify(|_, w| w.afrh11().bits(func)),
-					12 => gpio.afrh.modify(|_, w| w.afrh12().bits(func)),
-					13 => gpio.afrh.modify(|_, w| w.afrh13().bits(func)),
-					14 => gpio.afrh.modify(|_, w| w.afrh14().bits(func)),
-					15 => gpio.afrh.modify(|_, w| w.afrh15().bits(func)),
-					_ => ()
-				};
-			},
-			GpioPort::PortG => if let Some(ref mut gpio) = GPIOG_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => gpio.afrl.modify(|_, w| w.afrl0().bits(func)),
-					1 => gpio.afrl.modify(|_, w| w.afrl1().bits(func)),
-					2 => gpio.afrl.modify(|_, w| w.afrl2().bits(func)),
-					3 => gpio.afrl.modify(|_, w| w.afrl3().bits(func)),
-					4 => gpio.afrl.modify(|_, w| w.afrl4().bits(func)),
-					5 => gpio.afrl.modify(|_, w| w.afrl5().bits(func)),
-					6 => gpio.afrl.modify(|_, w| w.afrl6().bits(func)),
-					7 => gpio.afrl.modify(|_, w| w.afrl7().bits(func)),
-					8 => gpio.afrh.modify(|_, w| w.afrh8().bits(func)),
-					9 => gpio.afrh.modify(|_, w| w.afrh9().bits(func)),
-					10 => gpio.afrh.modify(|_, w| w.afrh10().bits(func)),
-					11 => gpio.afrh.modify(|_, w| w.afrh11().bits(func)),
-					12 => gpio.afrh.modify(|_, w| w.afrh12().bits(func)),
-					13 => gpio.afrh.modify(|_, w| w.afrh13().bits(func)),
-					14 => gpio.afrh.modify(|_, w| w.afrh14().bits(func)),
-					15 => gpio.afrh.modify(|_, w| w.afrh15().bits(func)),
-					_ => ()
-				};
-			},
-			GpioPort::PortH => if let Some(ref mut gpio) = GPIOH_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => gpio.afrl.modify(|_, w| w.afrl0().bits(func)),
-					1 => gpio.afrl.modify(|_, w| w.afrl1().bits(func)),
-					2 => gpio.afrl.modify(|_, w| w.afrl2().bits(func)),
-					3 => gpio.afrl.modify(|_, w| w.afrl3().bits(func)),
-					4 => gpio.afrl.modify(|_, w| w.afrl4().bits(func)),
-					5 => gpio.afrl.modify(|_, w| w.afrl5().bits(func)),
-					6 => gpio.afrl.modify(|_, w| w.afrl6().bits(func)),
-					7 => gpio.afrl.modify(|_, w| w.afrl7().bits(func)),
-					8 => gpio.afrh.modify(|_, w| w.afrh8().bits(func)),
-					9 => gpio.afrh.modify(|_, w| w.afrh9().bits(func)),
-					10 => gpio.afrh.modify(|_, w| w.afrh10().bits(func)),
-					11 => gpio.afrh.modify(|_, w| w.afrh11().bits(func)),
-					12 => gpio.afrh.modify(|_, w| w.afrh12().bits(func)),
-					13 => gpio.afrh.modify(|_, w| w.afrh13().bits(func)),
-					14 => gpio.afrh.modify(|_, w| w.afrh14().bits(func)),
-					15 => gpio.afrh.modify(|_, w| w.afrh15().bits(func)),
-					_ => ()
-				};
-			},
-		}
+// Runs the LCKR key sequence for the pins in `mask`, freezing MODER, OTYPER,
+// OSPEEDR, PUPDR and AFR for them until reset. Returns every locked pin of
+// the port.
+#[allow(dead_code)]
+pub fn lock_pins(port: GpioPort, mask: u16) -> Result<u16, GpioError> {
+	const LCKK: u32 = 1 << 16;
+
+	// The first lock freezes LCKR itself, no pins can be added afterwards
+	let already = get_locked_pins(port);
+	if already != 0 {
+		return if mask & !already == 0 { Ok(already) } else { Err(GpioError::LockFailed) };
+	}
+
+	let gpio = registers(port);
+	let mask = mask as u32;
+
+	// The sequence is aborted by any other access in between
+	let locked = free(|_| unsafe {
+		gpio.lckr.write(|w| w.bits(LCKK | mask));
+		gpio.lckr.write(|w| w.bits(mask));
+		gpio.lckr.write(|w| w.bits(LCKK | mask));
+		let _ = gpio.lckr.read().bits();
+		gpio.lckr.read().bits() & LCKK != 0
 	});
+
+	if locked { Ok(get_locked_pins(port)) } else { Err(GpioError::LockFailed) }
 }
 
 #[allow(dead_code)]
-pub fn set_pin_state(port: GpioPort, pin: u8, state: PinState) {
-	let state = state == PinState::PinHigh;
+pub fn get_locked_pins(port: GpioPort) -> u16 {
+	let lckr = registers(port).lckr.read().bits();
+	if lckr & (1 << 16) != 0 { lckr as u16 } else { 0 }
+}
 
+// `active` is the level of a pressed button, the pin should already have
+// whatever pull it needs. Returns the button id, or the pin if the table is
+// full.
+#[allow(dead_code)]
+pub fn add_button(pin: Pin<Input>, active: PinState, timings: ButtonTimings, callback: Option<ButtonCallback>) -> Result<usize, (Pin<Input>, GpioError)> {
 	free(|cs| {
-		match port {
-			GpioPort::PortA => if let Some(ref mut gpio) = GPIOA_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => gpio.bsrr.write(| w | w.br0().bit(!state).bs0().bit(state)),
-					1 => gpio.bsrr.write(| w | w.br1().bit(!state).bs1().bit(state)),
-					2 => gpio.bsrr.write(| w | w.br2().bit(!state).bs2().bit(state)),
-					3 => gpio.bsrr.write(| w | w.br3().bit(!state).bs3().bit(state)),
-					4 => gpio.bsrr.write(| w | w.br4().bit(!state).bs4().bit(state)),
-					5 => gpio.bsrr.write(| w | w.br5().bit(!state).bs5().bit(state)),
-					6 => gpio.bsrr.write(| w | w.br6().bit(!state).bs6().bit(state)),
-					7 => gpio.bsrr.write(| w | w.br7().bit(!state).bs7().bit(state)),
-					8 => gpio.bsrr.write(| w | w.br8().bit(!state).bs8().bit(state)),
-					9 => gpio.bsrr.write(| w | w.br9().bit(!state).bs9().bit(state)),
-					10 => gpio.bsrr.write(| w | w.br10().bit(!state).bs10().bit(state)),
-					11 => gpio.bsrr.write(| w | w.br11().bit(!state).bs11().bit(state)),
-					12 => gpio.bsrr.write(| w | w.br12().bit(!state).bs12().bit(state)),
-					13 => gpio.bsrr.write(| w | w.br13().bit(!state).bs13().bit(state)),
-					14 => gpio.bsrr.write(| w | w.br14().bit(!state).bs14().bit(state)),
-					15 => gpio.bsrr.write(| w | w.br15().bit(!state).bs15().bit(state)),
-					_ => ()
-				}
-			},
-			GpioPort::PortB => if let Some(ref mut gpio) = GPIOB_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => gpio.bsrr.write(| w | w.br0().bit(!state).bs0().bit(state)),
-					1 => gpio.bsrr.write(| w | w.br1().bit(!state).bs1().bit(state)),
-					2 => gpio.bsrr.write(| w | w.br2().bit(!state).bs2().bit(state)),
-					3 => gpio.bsrr.write(| w | w.br3().bit(!state).bs3().bit(state)),
-					4 => gpio.bsrr.write(| w | w.br4().bit(!state).bs4().bit(state)),
-					5 => gpio.bsrr.write(| w | w.br5().bit(!state).bs5().bit(state)),
-					6 => gpio.bsrr.write(| w | w.br6().bit(!state).bs6().bit(state)),
-					7 => gpio.bsrr.write(| w | w.br7().bit(!state).bs7().bit(state)),
-					8 => gpio.bsrr.write(| w | w.br8().bit(!state).bs8().bit(state)),
-					9 => gpio.bsrr.write(| w | w.br9().bit(!state).bs9().bit(state)),
-					10 => gpio.bsrr.write(| w | w.br10().bit(!state).bs10().bit(state)),
-					11 => gpio.bsrr.write(| w | w.br11().bit(!state).bs11().bit(state)),
-					12 => gpio.bsrr.write(| w | w.br12().bit(!state).bs12().bit(state)),
-					13 => gpio.bsrr.write(| w | w.br13().bit(!state).bs13().bit(state)),
-					14 => gpio.bsrr.write(| w | w.br14().bit(!state).bs14().bit(state)),
-					15 => gpio.bsrr.write(| w | w.br15().bit(!state).bs15().bit(state)),
-					_ => ()
-				}
-			},
-			GpioPort::PortC => if let Some(ref mut gpio) = GPIOC_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => gpio.bsrr.write(| w | w.br0().bit(!state).bs0().bit(state)),
-					1 => gpio.bsrr.write(| w | w.br1().bit(!state).bs1().bit(state)),
-					2 => gpio.bsrr.write(| w | w.br2().bit(!state).bs2().bit(state)),
-					3 => gpio.bsrr.write(| w | w.br3().bit(!state).bs3().bit(state)),
-					4 => gpio.bsrr.write(| w | w.br4().bit(!state).bs4().bit(state)),
-					5 => gpio.bsrr.write(| w | w.br5().bit(!state).bs5().bit(state)),
-					6 => gpio.bsrr.write(| w | w.br6().bit(!state).bs6().bit(state)),
-					7 => gpio.bsrr.write(| w | w.br7().bit(!state).bs7().bit(state)),
-					8 => gpio.bsrr.write(| w | w.br8().bit(!state).bs8().bit(state)),
-					9 => gpio.bsrr.write(| w | w.br9().bit(!state).bs9().bit(state)),
-					10 => gpio.bsrr.write(| w | w.br10().bit(!state).bs10().bit(state)),
-					11 => gpio.bsrr.write(| w | w.br11().bit(!state).bs11().bit(state)),
-					12 => gpio.bsrr.write(| w | w.br12().bit(!state).bs12().bit(state)),
-					13 => gpio.bsrr.write(| w | w.br13().bit(!state).bs13().bit(state)),
-					14 => gpio.bsrr.write(| w | w.br14().bit(!state).bs14().bit(state)),
-					15 => gpio.bsrr.write(| w | w.br15().bit(!state).bs15().bit(state)),
-					_ => ()
-				}
-			},
-			GpioPort::PortD => if let Some(ref mut gpio) = GPIOD_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => gpio.bsrr.write(| w | w.br0().bit(!state).bs0().bit(state)),
-					1 => gpio.bsrr.write(| w | w.br1().bit(!state).bs1().bit(state)),
-					2 => gpio.bsrr.write(| w | w.br2().bit(!state).bs2().bit(state)),
-					3 => gpio.bsrr.write(| w | w.br3().bit(!state).bs3().bit(state)),
-					4 => gpio.bsrr.write(| w | w.br4().bit(!state).bs4().bit(state)),
-					5 => gpio.bsrr.write(| w | w.br5().bit(!state).bs5().bit(state)),
-					6 => gpio.bsrr.write(| w | w.br6().bit(!state).bs6().bit(state)),
-					7 => gpio.bsrr.write(| w | w.br7().bit(!state).bs7().bit(state)),
-					8 => gpio.bsrr.write(| w | w.br8().bit(!state).bs8().bit(state)),
-					9 => gpio.bsrr.write(| w | w.br9().bit(!state).bs9().bit(state)),
-					10 => gpio.bsrr.write(| w | w.br10().bit(!state).bs10().bit(state)),
-					11 => gpio.bsrr.write(| w | w.br11().bit(!state).bs11().bit(state)),
-					12 => gpio.bsrr.write(| w | w.br12().bit(!state).bs12().bit(state)),
-					13 => gpio.bsrr.write(| w | w.br13().bit(!state).bs13().bit(state)),
-					14 => gpio.bsrr.write(| w | w.br14().bit(!state).bs14().bit(state)),
-					15 => gpio.bsrr.write(| w | w.br15().bit(!state).bs15().bit(state)),
-					_ => ()
-				}
-			},
-			GpioPort::PortE => if let Some(ref mut gpio) = GPIOE_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => gpio.bsrr.write(| w | w.br0().bit(!state).bs0().bit(state)),
-					1 => gpio.bsrr.write(| w | w.br1().bit(!state).bs1().bit(state)),
-					2 => gpio.bsrr.write(| w | w.br2().bit(!state).bs2().bit(state)),
-					3 => gpio.bsrr.write(| w | w.br3().bit(!state).bs3().bit(state)),
-					4 => gpio.bsrr.write(| w | w.br4().bit(!state).bs4().bit(state)),
-					5 => gpio.bsrr.write(| w | w.br5().bit(!state).bs5().bit(state)),
-					6 => gpio.bsrr.write(| w | w.br6().bit(!state).bs6().bit(state)),
-					7 => gpio.bsrr.write(| w | w.br7().bit(!state).bs7().bit(state)),
-					8 => gpio.bsrr.write(| w | w.br8().bit(!state).bs8().bit(state)),
-					9 => gpio.bsrr.write(| w | w.br9().bit(!state).bs9().bit(state)),
-					10 => gpio.bsrr.write(| w | w.br10().bit(!state).bs10().bit(state)),
-					11 => gpio.bsrr.write(| w | w.br11().bit(!state).bs11().bit(state)),
-					12 => gpio.bsrr.write(| w | w.br12().bit(!state).bs12().bit(state)),
-					13 => gpio.bsrr.write(| w | w.br13().bit(!state).bs13().bit(state)),
-					14 => gpio.bsrr.write(| w | w.br14().bit(!state).bs14().bit(state)),
-					15 => gpio.bsrr.write(| w | w.br15().bit(!state).bs15().bit(state)),
-					_ => ()
-				}
-			},
-			GpioPort::PortF => if let Some(ref mut gpio) = GPIOF_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => gpio.bsrr.write(| w | w.br0().bit(!state).bs0().bit(state)),
-					1 => gpio.bsrr.write(| w | w.br1().bit(!state).bs1().bit(state)),
-					2 => gpio.bsrr.write(| w | w.br2().bit(!state).bs2().bit(state)),
-					3 => gpio.bsrr.write(| w | w.br3().bit(!state).bs3().bit(state)),
-					4 => gpio.bsrr.write(| w | w.br4().bit(!state).bs4().bit(state)),
-					5 => gpio.bsrr.write(| w | w.br5().bit(!state).bs5().bit(state)),
-					6 => gpio.bsrr.write(| w | w.br6().bit(!state).bs6().bit(state)),
-					7 => gpio.bsrr.write(| w | w.br7().bit(!state).bs7().bit(state)),
-					8 => gpio.bsrr.write(| w | w.br8().bit(!state).bs8().bit(state)),
-					9 => gpio.bsrr.write(| w | w.br9().bit(!state).bs9().bit(state)),
-					10 => gpio.bsrr.write(| w | w.br10().bit(!state).bs10().bit(state)),
-					11 => gpio.bsrr.write(| w | w.br11().bit(!state).bs11().bit(state)),
-					12 => gpio.bsrr.write(| w | w.br12().bit(!state).bs12().bit(state)),
-					13 => gpio.bsrr.write(| w | w.br13().bit(!state).bs13().bit(state)),
-					14 => gpio.bsrr.write(| w | w.br14().bit(!state).bs14().bit(state)),
-					15 => gpio.bsrr.write(| w | w.br15().bit(!state).bs15().bit(state)),
-					_ => ()
-				}
-			},
-			GpioPort::PortG => if let Some(ref mut gpio) = GPIOG_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => gpio.bsrr.write(| w | w.br0().bit(!state).bs0().bit(state)),
-					1 => gpio.bsrr.write(| w | w.br1().bit(!state).bs1().bit(state)),
-					2 => gpio.bsrr.write(| w | w.br2().bit(!state).bs2().bit(state)),
-					3 => gpio.bsrr.write(| w | w.br3().bit(!state).bs3().bit(state)),
-					4 => gpio.bsrr.write(| w | w.br4().bit(!state).bs4().bit(state)),
-					5 => gpio.bsrr.write(| w | w.br5().bit(!state).bs5().bit(state)),
-					6 => gpio.bsrr.write(| w | w.br6().bit(!state).bs6().bit(state)),
-					7 => gpio.bsrr.write(| w | w.br7().bit(!state).bs7().bit(state)),
-					8 => gpio.bsrr.write(| w | w.br8().bit(!state).bs8().bit(state)),
-					9 => gpio.bsrr.write(| w | w.br9().bit(!state).bs9().bit(state)),
-					10 => gpio.bsrr.write(| w | w.br10().bit(!state).bs10().bit(state)),
-					11 => gpio.bsrr.write(| w | w.br11().bit(!state).bs11().bit(state)),
-					12 => gpio.bsrr.write(| w | w.br12().bit(!state).bs12().bit(state)),
-					13 => gpio.bsrr.write(| w | w.br13().bit(!state).bs13().bit(state)),
-					14 => gpio.bsrr.write(| w | w.br14().bit(!state).bs14().bit(state)),
-					15 => gpio.bsrr.write(| w | w.br15().bit(!state).bs15().bit(state)),
-					_ => ()
-				}
-			},
-			GpioPort::PortH => if let Some(ref mut gpio) = GPIOH_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				match pin {
-					0 => gpio.bsrr.write(| w | w.br0().bit(!state).bs0().bit(state)),
-					1 => gpio.bsrr.write(| w | w.br1().bit(!state).bs1().bit(state)),
-					2 => gpio.bsrr.write(| w | w.br2().bit(!state).bs2().bit(state)),
-					3 => gpio.bsrr.write(| w | w.br3().bit(!state).bs3().bit(state)),
-					4 => gpio.bsrr.write(| w | w.br4().bit(!state).bs4().bit(state)),
-					5 => gpio.bsrr.write(| w | w.br5().bit(!state).bs5().bit(state)),
-					6 => gpio.bsrr.write(| w | w.br6().bit(!state).bs6().bit(state)),
-					7 => gpio.bsrr.write(| w | w.br7().bit(!state).bs7().bit(state)),
-					8 => gpio.bsrr.write(| w | w.br8().bit(!state).bs8().bit(state)),
-					9 => gpio.bsrr.write(| w | w.br9().bit(!state).bs9().bit(state)),
-					10 => gpio.bsrr.write(| w | w.br10().bit(!state).bs10().bit(state)),
-					11 => gpio.bsrr.write(| w | w.br11().bit(!state).bs11().bit(state)),
-					12 => gpio.bsrr.write(| w | w.br12().bit(!state).bs12().bit(state)),
-					13 => gpio.bsrr.write(| w | w.br13().bit(!state).bs13().bit(state)),
-					14 => gpio.bsrr.write(| w | w.br14().bit(!state).bs14().bit(state)),
-					15 => gpio.bsrr.write(| w | w.br15().bit(!state).bs15().bit(state)),
-					_ => ()
-				}
-			},
-		}
-	});
+		let mut buttons = BUTTONS.borrow(cs).borrow_mut();
+		let id = match buttons.iter().position(|b| b.is_none()) {
+			Some(id) => id,
+			None => return Err((pin, GpioError::ButtonTableFull))
+		};
+
+		let pressed = pin.get_state() == active;
+		buttons[id] = Some(Button {
+			pin,
+			active,
+			timings,
+			callback,
+			raw: pressed,
+			pressed,
+			changed_at: timer::get_tick(),
+			pressed_at: 0,
+			long_sent: pressed,
+			double_sent: false,
+			released_at: None,
+			event: None
+		});
+
+		Ok(id)
+	})
+}
+
+#[allow(dead_code)]
+pub fn remove_button(id: usize) -> Option<Pin<Input>> {
+	free(|cs| BUTTONS.borrow(cs).borrow_mut().get_mut(id)?.take().map(|b| b.pin))
+}
+
+// The latest event not yet taken
+#[allow(dead_code)]
+pub fn take_button_event(id: usize) -> Option<ButtonEvent> {
+	free(|cs| BUTTONS.borrow(cs).borrow_mut().get_mut(id)?.as_mut()?.event.take())
+}
+
+// For diagnostics, any pin can be read back regardless of who owns it
+#[allow(dead_code)]
+pub fn get_pin_config(port: GpioPort, pin: u8) -> Option<PinConfig> {
+	if pin >= PIN_COUNT {
+		return None;
+	}
+
+	Some(read_config(port, pin))
+}
+
+// Times the output path on a pin with DWT CYCCNT, the pin is left toggling.
+// Trace and the cycle counter are only switched on for the measurement,
+// whatever was already on (e.g. by a debugger) stays on.
+#[allow(dead_code)]
+pub fn benchmark(pin: &mut Pin<Output>) -> GpioBenchmark {
+	// Only DEMCR and the DWT control register are touched, nothing else
+	// holds on to DCB or DWT
+	let mut core_peripherals = unsafe { cortex_m::Peripherals::steal() };
+	let tracing = core_peripherals.DCB.demcr.read() & DEMCR_TRCENA != 0;
+	let counting = DWT::cycle_counter_enabled();
+	core_peripherals.DCB.enable_trace();
+	core_peripherals.DWT.enable_cycle_counter();
+
+	let overhead = measure(|| {});
+	let result = GpioBenchmark {
+		set: measure(|| pin.set_high()).saturating_sub(overhead),
+		toggle: measure(|| pin.toggle()).saturating_sub(overhead),
+		read: measure(|| { core::hint::black_box(pin.get_state()); }).saturating_sub(overhead),
+	};
+
+	if !counting {
+		core_peripherals.DWT.disable_cycle_counter();
+	}
+	if !tracing {
+		core_peripherals.DCB.disable_trace();
+	}
+	result
 }
 
 //==============================================================================
 // Private Functions
 //==============================================================================
-#[allow(dead_code)]
-fn set_output_type(port: GpioPort, pin: u8, out_type: OutputType) {
-	free(|cs| {
-		match port {
-			GpioPort::PortA => if let Some(ref mut gpio) = GPIOA_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let out_type = match out_type {
-					OutputType::PushPull => stm32f303::gpioa::otyper::OT0_A::PUSHPULL,
-					OutputType::OpenDrain => stm32f303::gpioa::otyper::OT0_A::OPENDRAIN,
-				};
-				match pin {
-					0 => gpio.otyper.modify(|_, w| w.ot0().variant(out_type)),
-					1 => gpio.otyper.modify(|_, w| w.ot1().variant(out_type)),
-					2 => gpio.otyper.modify(|_, w| w.ot2().variant(out_type)),
-					3 => gpio.otyper.modify(|_, w| w.ot3().variant(out_type)),
-					4 => gpio.otyper.modify(|_, w| w.ot4().variant(out_type)),
-					5 => gpio.otyper.modify(|_, w| w.ot5().variant(out_type)),
-					6 => gpio.otyper.modify(|_, w| w.ot6().variant(out_type)),
-					7 => gpio.otyper.modify(|_, w| w.ot7().variant(out_type)),
-					8 => gpio.otyper.modify(|_, w| w.ot8().variant(out_type)),
-					9 => gpio.otyper.modify(|_, w| w.ot9().variant(out_type)),
-					10 => gpio.otyper.modify(|_, w| w.ot10().variant(out_type)),
-					11 => gpio.otyper.modify(|_, w| w.ot11().variant(out_type)),
-					12 => gpio.otyper.modify(|_, w| w.ot12().variant(out_type)),
-					13 => gpio.otyper.modify(|_, w| w.ot13().variant(out_type)),
-					14 => gpio.otyper.modify(|_, w| w.ot14().variant(out_type)),
-					15 => gpio.otyper.modify(|_, w| w.ot15().variant(out_type)),
-					_ => ()
-				}
-			},
-			GpioPort::PortB => if let Some(ref mut gpio) = GPIOB_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let out_type = match out_type {
-					OutputType::PushPull => stm32f303::gpiob::otyper::OT0_A::PUSHPULL,
-					OutputType::OpenDrain => stm32f303::gpiob::otyper::OT0_A::OPENDRAIN,
-				};
-				match pin {
-					0 => gpio.otyper.modify(|_, w| w.ot0().variant(out_type)),
-					1 => gpio.otyper.modify(|_, w| w.ot1().variant(out_type)),
-					2 => gpio.otyper.modify(|_, w| w.ot2().variant(out_type)),
-					3 => gpio.otyper.modify(|_, w| w.ot3().variant(out_type)),
-					4 => gpio.otyper.modify(|_, w| w.ot4().variant(out_type)),
-					5 => gpio.otyper.modify(|_, w| w.ot5().variant(out_type)),
-					6 => gpio.otyper.modify(|_, w| w.ot6().variant(out_type)),
-					7 => gpio.otyper.modify(|_, w| w.ot7().variant(out_type)),
-					8 => gpio.otyper.modify(|_, w| w.ot8().variant(out_type)),
-					9 => gpio.otyper.modify(|_, w| w.ot9().variant(out_type)),
-					10 => gpio.otyper.modify(|_, w| w.ot10().variant(out_type)),
-					11 => gpio.otyper.modify(|_, w| w.ot11().variant(out_type)),
-					12 => gpio.otyper.modify(|_, w| w.ot12().variant(out_type)),
-					13 => gpio.otyper.modify(|_, w| w.ot13().variant(out_type)),
-					14 => gpio.otyper.modify(|_, w| w.ot14().variant(out_type)),
-					15 => gpio.otyper.modify(|_, w| w.ot15().variant(out_type)),
-					_ => ()
-				}
-			},
-			GpioPort::PortC => if let Some(ref mut gpio) = GPIOC_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let out_type = match out_type {
-					OutputType::PushPull => stm32f303::gpioc::otyper::OT0_A::PUSHPULL,
-					OutputType::OpenDrain => stm32f303::gpioc::otyper::OT0_A::OPENDRAIN,
-				};
-				match pin {
-					0 => gpio.otyper.modify(|_, w| w.ot0().variant(out_type)),
-					1 => gpio.otyper.modify(|_, w| w.ot1().variant(out_type)),
-					2 => gpio.otyper.modify(|_, w| w.ot2().variant(out_type)),
-					3 => gpio.otyper.modify(|_, w| w.ot3().variant(out_type)),
-					4 => gpio.otyper.modify(|_, w| w.ot4().variant(out_type)),
-					5 => gpio.otyper.modify(|_, w| w.ot5().variant(out_type)),
-					6 => gpio.otyper.modify(|_, w| w.ot6().variant(out_type)),
-					7 => gpio.otyper.modify(|_, w| w.ot7().variant(out_type)),
-					8 => gpio.otyper.modify(|_, w| w.ot8().variant(out_type)),
-					9 => gpio.otyper.modify(|_, w| w.ot9().variant(out_type)),
-					10 => gpio.otyper.modify(|_, w| w.ot10().variant(out_type)),
-					11 => gpio.otyper.modify(|_, w| w.ot11().variant(out_type)),
-					12 => gpio.otyper.modify(|_, w| w.ot12().variant(out_type)),
-					13 => gpio.otyper.modify(|_, w| w.ot13().variant(out_type)),
-					14 => gpio.otyper.modify(|_, w| w.ot14().variant(out_type)),
-					15 => gpio.otyper.modify(|_, w| w.ot15().variant(out_type)),
-					_ => ()
-				}
-			},
-			GpioPort::PortD => if let Some(ref mut gpio) = GPIOD_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let out_type = match out_type {
-					OutputType::PushPull => stm32f303::gpioc::otyper::OT0_A::PUSHPULL,
-					OutputType::OpenDrain => stm32f303::gpioc::otyper::OT0_A::OPENDRAIN,
-				};
-				match pin {
-					0 => gpio.otyper.modify(|_, w| w.ot0().variant(out_type)),
-					1 => gpio.otyper.modify(|_, w| w.ot1().variant(out_type)),
-					2 => gpio.otyper.modify(|_, w| w.ot2().variant(out_type)),
-					3 => gpio.otyper.modify(|_, w| w.ot3().variant(out_type)),
-					4 => gpio.otyper.modify(|_, w| w.ot4().variant(out_type)),
-					5 => gpio.otyper.modify(|_, w| w.ot5().variant(out_type)),
-					6 => gpio.otyper.modify(|_, w| w.ot6().variant(out_type)),
-					7 => gpio.otyper.modify(|_, w| w.ot7().variant(out_type)),
-					8 => gpio.otyper.modify(|_, w| w.ot8().variant(out_type)),
-					9 => gpio.otyper.modify(|_, w| w.ot9().variant(out_type)),
-					10 => gpio.otyper.modify(|_, w| w.ot10().variant(out_type)),
-					11 => gpio.otyper.modify(|_, w| w.ot11().variant(out_type)),
-					12 => gpio.otyper.modify(|_, w| w.ot12().variant(out_type)),
-					13 => gpio.otyper.modify(|_, w| w.ot13().variant(out_type)),
-					14 => gpio.otyper.modify(|_, w| w.ot14().variant(out_type)),
-					15 => gpio.otyper.modify(|_, w| w.ot15().variant(out_type)),
-					_ => ()
-				}
-			},
-			GpioPort::PortE => if let Some(ref mut gpio) = GPIOE_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let out_type = match out_type {
-					OutputType::PushPull => stm32f303::gpioc::otyper::OT0_A::PUSHPULL,
-					OutputType::OpenDrain => stm32f303::gpioc::otyper::OT0_A::OPENDRAIN,
-				};
-				match pin {
-					0 => gpio.otyper.modify(|_, w| w.ot0().variant(out_type)),
-					1 => gpio.otyper.modify(|_, w| w.ot1().variant(out_type)),
-					2 => gpio.otyper.modify(|_, w| w.ot2().variant(out_type)),
-					3 => gpio.otyper.modify(|_, w| w.ot3().variant(out_type)),
-					4 => gpio.otyper.modify(|_, w| w.ot4().variant(out_type)),
-					5 => gpio.otyper.modify(|_, w| w.ot5().variant(out_type)),
-					6 => gpio.otyper.modify(|_, w| w.ot6().variant(out_type)),
-					7 => gpio.otyper.modify(|_, w| w.ot7().variant(out_type)),
-					8 => gpio.otyper.modify(|_, w| w.ot8().variant(out_type)),
-					9 => gpio.otyper.modify(|_, w| w.ot9().variant(out_type)),
-					10 => gpio.otyper.modify(|_, w| w.ot10().variant(out_type)),
-					11 => gpio.otyper.modify(|_, w| w.ot11().variant(out_type)),
-					12 => gpio.otyper.modify(|_, w| w.ot12().variant(out_type)),
-					13 => gpio.otyper.modify(|_, w| w.ot13().variant(out_type)),
-					14 => gpio.otyper.modify(|_, w| w.ot14().variant(out_type)),
-					15 => gpio.otyper.modify(|_, w| w.ot15().variant(out_type)),
-					_ => ()
-				}
-			},
-			GpioPort::PortF => if let Some(ref mut gpio) = GPIOF_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let out_type = match out_type {
-					OutputType::PushPull => stm32f303::gpioc::otyper::OT0_A::PUSHPULL,
-					OutputType::OpenDrain => stm32f303::gpioc::otyper::OT0_A::OPENDRAIN,
-				};
-				match pin {
-					0 => gpio.otyper.modify(|_, w| w.ot0().variant(out_type)),
-					1 => gpio.otyper.modify(|_, w| w.ot1().variant(out_type)),
-					2 => gpio.otyper.modify(|_, w| w.ot2().variant(out_type)),
-					3 => gpio.otyper.modify(|_, w| w.ot3().variant(out_type)),
-					4 => gpio.otyper.modify(|_, w| w.ot4().variant(out_type)),
-					5 => gpio.otyper.modify(|_, w| w.ot5().variant(out_type)),
-					6 => gpio.otyper.modify(|_, w| w.ot6().variant(out_type)),
-					7 => gpio.otyper.modify(|_, w| w.ot7().variant(out_type)),
-					8 => gpio.otyper.modify(|_, w| w.ot8().variant(out_type)),
-					9 => gpio.otyper.modify(|_, w| w.ot9().variant(out_type)),
-					10 => gpio.otyper.modify(|_, w| w.ot10().variant(out_type)),
-					11 => gpio.otyper.modify(|_, w| w.ot11().variant(out_type)),
-					12 => gpio.otyper.modify(|_, w| w.ot12().variant(out_type)),
-					13 => gpio.otyper.modify(|_, w| w.ot13().variant(out_type)),
-					14 => gpio.otyper.modify(|_, w| w.ot14().variant(out_type)),
-					15 => gpio.otyper.modify(|_, w| w.ot15().variant(out_type)),
-					_ => ()
-				}
-			},
-			GpioPort::PortG => if let Some(ref mut gpio) = GPIOG_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let out_type = match out_type {
-					OutputType::PushPull => stm32f303::gpioc::otyper::OT0_A::PUSHPULL,
-					OutputType::OpenDrain => stm32f303::gpioc::otyper::OT0_A::OPENDRAIN,
-				};
-				match pin {
-					0 => gpio.otyper.modify(|_, w| w.ot0().variant(out_type)),
-					1 => gpio.otyper.modify(|_, w| w.ot1().variant(out_type)),
-					2 => gpio.otyper.modify(|_, w| w.ot2().variant(out_type)),
-					3 => gpio.otyper.modify(|_, w| w.ot3().variant(out_type)),
-					4 => gpio.otyper.modify(|_, w| w.ot4().variant(out_type)),
-					5 => gpio.otyper.modify(|_, w| w.ot5().variant(out_type)),
-					6 => gpio.otyper.modify(|_, w| w.ot6().variant(out_type)),
-					7 => gpio.otyper.modify(|_, w| w.ot7().variant(out_type)),
-					8 => gpio.otyper.modify(|_, w| w.ot8().variant(out_type)),
-					9 => gpio.otyper.modify(|_, w| w.ot9().variant(out_type)),
-					10 => gpio.otyper.modify(|_, w| w.ot10().variant(out_type)),
-					11 => gpio.otyper.modify(|_, w| w.ot11().variant(out_type)),
-					12 => gpio.otyper.modify(|_, w| w.ot12().variant(out_type)),
-					13 => gpio.otyper.modify(|_, w| w.ot13().variant(out_type)),
-					14 => gpio.otyper.modify(|_, w| w.ot14().variant(out_type)),
-					15 => gpio.otyper.modify(|_, w| w.ot15().variant(out_type)),
-					_ => ()
-				}
-			},
-			GpioPort::PortH => if let Some(ref mut gpio) = GPIOH_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let out_type = match out_type {
-					OutputType::PushPull => stm32f303::gpioc::otyper::OT0_A::PUSHPULL,
-					OutputType::OpenDrain => stm32f303::gpioc::otyper::OT0_A::OPENDRAIN,
-				};
-				match pin {
-					0 => gpio.otyper.modify(|_, w| w.ot0().variant(out_type)),
-					1 => gpio.otyper.modify(|_, w| w.ot1().variant(out_type)),
-					2 => gpio.otyper.modify(|_, w| w.ot2().variant(out_type)),
-					3 => gpio.otyper.modify(|_, w| w.ot3().variant(out_type)),
-					4 => gpio.otyper.modify(|_, w| w.ot4().variant(out_type)),
-					5 => gpio.otyper.modify(|_, w| w.ot5().variant(out_type)),
-					6 => gpio.otyper.modify(|_, w| w.ot6().variant(out_type)),
-					7 => gpio.otyper.modify(|_, w| w.ot7().variant(out_type)),
-					8 => gpio.otyper.modify(|_, w| w.ot8().variant(out_type)),
-					9 => gpio.otyper.modify(|_, w| w.ot9().variant(out_type)),
-					10 => gpio.otyper.modify(|_, w| w.ot10().variant(out_type)),
-					11 => gpio.otyper.modify(|_, w| w.ot11().variant(out_type)),
-					12 => gpio.otyper.modify(|_, w| w.ot12().variant(out_type)),
-					13 => gpio.otyper.modify(|_, w| w.ot13().variant(out_type)),
-					14 => gpio.otyper.modify(|_, w| w.ot14().variant(out_type)),
-					15 => gpio.otyper.modify(|_, w| w.ot15().variant(out_type)),
-					_ => ()
-				}
-			},
+// Every register is written inside one critical section, output level and
+// driver settings before MODER so the pin never glitches on the way
+fn apply_config(port: GpioPort, pin: u8, config: &PinConfig) {
+	let gpio = registers(port);
+	let mask = 1 << pin;
+	let af_mask = if pin < 8 { mask } else { mask >> 8 };
+
+	free(|_| unsafe {
+		set_pin_state(port, pin, config.state);
+		gpio.otyper.modify(|r, w| w.bits(set_field_mask(r.bits(), mask, 1, config.output_type as u32)));
+		gpio.ospeedr.modify(|r, w| w.bits(set_field_mask(r.bits(), mask, 2, config.speed as u32)));
+		gpio.pupdr.modify(|r, w| w.bits(set_field_mask(r.bits(), mask, 2, config.pull as u32)));
+		if pin < 8 {
+			gpio.afrl.modify(|r, w| w.bits(set_field_mask(r.bits(), af_mask, 4, config.alt_func as u32)));
 		}
+		else {
+			gpio.afrh.modify(|r, w| w.bits(set_field_mask(r.bits(), af_mask, 4, config.alt_func as u32)));
+		}
+		gpio.moder.modify(|r, w| w.bits(set_field_mask(r.bits(), mask, 2, config.mode as u32)));
 	});
 }
 
-#[allow(dead_code)]
-fn set_pin_mode(port: GpioPort, pin: u8, mode: GpioMode) {
-	free(|cs| {
-		match port {
-			GpioPort::PortA => if let Some(ref mut gpio) = GPIOA_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let mode = match mode {
-					GpioMode::Input => stm32f303::gpioa::moder::MODER0_A::INPUT,
-					GpioMode::Output => stm32f303::gpioa::moder::MODER0_A::OUTPUT,
-					GpioMode::AltFunc => stm32f303::gpioa::moder::MODER0_A::ALTERNATE,
-					GpioMode::Analog => stm32f303::gpioa::moder::MODER0_A::ANALOG
-				};
-				match pin {
-					0 => gpio.moder.modify(|_, w| w.moder0().variant(mode)),
-					1 => gpio.moder.modify(|_, w| w.moder1().variant(mode)),
-					2 => gpio.moder.modify(|_, w| w.moder2().variant(mode)),
-					3 => gpio.moder.modify(|_, w| w.moder3().variant(mode)),
-					4 => gpio.moder.modify(|_, w| w.moder4().variant(mode)),
-					5 => gpio.moder.modify(|_, w| w.moder5().variant(mode)),
-					6 => gpio.moder.modify(|_, w| w.moder6().variant(mode)),
-					7 => gpio.moder.modify(|_, w| w.moder7().variant(mode)),
-					8 => gpio.moder.modify(|_, w| w.moder8().variant(mode)),
-					9 => gpio.moder.modify(|_, w| w.moder9().variant(mode)),
-					10 => gpio.moder.modify(|_, w| w.moder10().variant(mode)),
-					11 => gpio.moder.modify(|_, w| w.moder11().variant(mode)),
-					12 => gpio.moder.modify(|_, w| w.moder12().variant(mode)),
-					13 => gpio.moder.modify(|_, w| w.moder13().variant(mode)),
-					14 => gpio.moder.modify(|_, w| w.moder14().variant(mode)),
-					15 => gpio.moder.modify(|_, w| w.moder15().variant(mode)),
-					_ => ()
-				}
-			},
-			GpioPort::PortB => if let Some(ref mut gpio) = GPIOB_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let mode = match mode {
-					GpioMode::Input => stm32f303::gpiob::moder::MODER0_A::INPUT,
-					GpioMode::Output => stm32f303::gpiob::moder::MODER0_A::OUTPUT,
-					GpioMode::AltFunc => stm32f303::gpiob::moder::MODER0_A::ALTERNATE,
-					GpioMode::Analog => stm32f303::gpiob::moder::MODER0_A::ANALOG
-				};
-				match pin {
-					0 => gpio.moder.modify(|_, w| w.moder0().variant(mode)),
-					1 => gpio.moder.modify(|_, w| w.moder1().variant(mode)),
-					2 => gpio.moder.modify(|_, w| w.moder2().variant(mode)),
-					3 => gpio.moder.modify(|_, w| w.moder3().variant(mode)),
-					4 => gpio.moder.modify(|_, w| w.moder4().variant(mode)),
-					5 => gpio.moder.modify(|_, w| w.moder5().variant(mode)),
-					6 => gpio.moder.modify(|_, w| w.moder6().variant(mode)),
-					7 => gpio.moder.modify(|_, w| w.moder7().variant(mode)),
-					8 => gpio.moder.modify(|_, w| w.moder8().variant(mode)),
-					9 => gpio.moder.modify(|_, w| w.moder9().variant(mode)),
-					10 => gpio.moder.modify(|_, w| w.moder10().variant(mode)),
-					11 => gpio.moder.modify(|_, w| w.moder11().variant(mode)),
-					12 => gpio.moder.modify(|_, w| w.moder12().variant(mode)),
-					13 => gpio.moder.modify(|_, w| w.moder13().variant(mode)),
-					14 => gpio.moder.modify(|_, w| w.moder14().variant(mode)),
-					15 => gpio.moder.modify(|_, w| w.moder15().variant(mode)),
-					_ => ()
-				}
-			},
-			GpioPort::PortC => if let Some(ref mut gpio) = GPIOC_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let mode = match mode {
-					GpioMode::Input => stm32f303::gpioc::moder::MODER0_A::INPUT,
-					GpioMode::Output => stm32f303::gpioc::moder::MODER0_A::OUTPUT,
-					GpioMode::AltFunc => stm32f303::gpioc::moder::MODER0_A::ALTERNATE,
-					GpioMode::Analog => stm32f303::gpioc::moder::MODER0_A::ANALOG
-				};
-				match pin {
-					0 => gpio.moder.modify(|_, w| w.moder0().variant(mode)),
-					1 => gpio.moder.modify(|_, w| w.moder1().variant(mode)),
-					2 => gpio.moder.modify(|_, w| w.moder2().variant(mode)),
-					3 => gpio.moder.modify(|_, w| w.moder3().variant(mode)),
-					4 => gpio.moder.modify(|_, w| w.moder4().variant(mode)),
-					5 => gpio.moder.modify(|_, w| w.moder5().variant(mode)),
-					6 => gpio.moder.modify(|_, w| w.moder6().variant(mode)),
-					7 => gpio.moder.modify(|_, w| w.moder7().variant(mode)),
-					8 => gpio.moder.modify(|_, w| w.moder8().variant(mode)),
-					9 => gpio.moder.modify(|_, w| w.moder9().variant(mode)),
-					10 => gpio.moder.modify(|_, w| w.moder10().variant(mode)),
-					11 => gpio.moder.modify(|_, w| w.moder11().variant(mode)),
-					12 => gpio.moder.modify(|_, w| w.moder12().variant(mode)),
-					13 => gpio.moder.modify(|_, w| w.moder13().variant(mode)),
-					14 => gpio.moder.modify(|_, w| w.moder14().variant(mode)),
-					15 => gpio.moder.modify(|_, w| w.moder15().variant(mode)),
-					_ => ()
-				}
-			},
-			GpioPort::PortD => if let Some(ref mut gpio) = GPIOD_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let mode = match mode {
-					GpioMode::Input => stm32f303::gpioc::moder::MODER0_A::INPUT,
-					GpioMode::Output => stm32f303::gpioc::moder::MODER0_A::OUTPUT,
-					GpioMode::AltFunc => stm32f303::gpioc::moder::MODER0_A::ALTERNATE,
-					GpioMode::Analog => stm32f303::gpioc::moder::MODER0_A::ANALOG
-				};
-				match pin {
-					0 => gpio.moder.modify(|_, w| w.moder0().variant(mode)),
-					1 => gpio.moder.modify(|_, w| w.moder1().variant(mode)),
-					2 => gpio.moder.modify(|_, w| w.moder2().variant(mode)),
-					3 => gpio.moder.modify(|_, w| w.moder3().variant(mode)),
-					4 => gpio.moder.modify(|_, w| w.moder4().variant(mode)),
-					5 => gpio.moder.modify(|_, w| w.moder5().variant(mode)),
-					6 => gpio.moder.modify(|_, w| w.moder6().variant(mode)),
-					7 => gpio.moder.modify(|_, w| w.moder7().variant(mode)),
-					8 => gpio.moder.modify(|_, w| w.moder8().variant(mode)),
-					9 => gpio.moder.modify(|_, w| w.moder9().variant(mode)),
-					10 => gpio.moder.modify(|_, w| w.moder10().variant(mode)),
-					11 => gpio.moder.modify(|_, w| w.moder11().variant(mode)),
-					12 => gpio.moder.modify(|_, w| w.moder12().variant(mode)),
-					13 => gpio.moder.modify(|_, w| w.moder13().variant(mode)),
-					14 => gpio.moder.modify(|_, w| w.moder14().variant(mode)),
-					15 => gpio.moder.modify(|_, w| w.moder15().variant(mode)),
-					_ => ()
-				}
-			},
-			GpioPort::PortE => if let Some(ref mut gpio) = GPIOE_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let mode = match mode {
-					GpioMode::Input => stm32f303::gpioc::moder::MODER0_A::INPUT,
-					GpioMode::Output => stm32f303::gpioc::moder::MODER0_A::OUTPUT,
-					GpioMode::AltFunc => stm32f303::gpioc::moder::MODER0_A::ALTERNATE,
-					GpioMode::Analog => stm32f303::gpioc::moder::MODER0_A::ANALOG
-				};
-				match pin {
-					0 => gpio.moder.modify(|_, w| w.moder0().variant(mode)),
-					1 => gpio.moder.modify(|_, w| w.moder1().variant(mode)),
-					2 => gpio.moder.modify(|_, w| w.moder2().variant(mode)),
-					3 => gpio.moder.modify(|_, w| w.moder3().variant(mode)),
-					4 => gpio.moder.modify(|_, w| w.moder4().variant(mode)),
-					5 => gpio.moder.modify(|_, w| w.moder5().variant(mode)),
-					6 => gpio.moder.modify(|_, w| w.moder6().variant(mode)),
-					7 => gpio.moder.modify(|_, w| w.moder7().variant(mode)),
-					8 => gpio.moder.modify(|_, w| w.moder8().variant(mode)),
-					9 => gpio.moder.modify(|_, w| w.moder9().variant(mode)),
-					10 => gpio.moder.modify(|_, w| w.moder10().variant(mode)),
-					11 => gpio.moder.modify(|_, w| w.moder11().variant(mode)),
-					12 => gpio.moder.modify(|_, w| w.moder12().variant(mode)),
-					13 => gpio.moder.modify(|_, w| w.moder13().variant(mode)),
-					14 => gpio.moder.modify(|_, w| w.moder14().variant(mode)),
-					15 => gpio.moder.modify(|_, w| w.moder15().variant(mode)),
-					_ => ()
-				}
-			},
-			GpioPort::PortF => if let Some(ref mut gpio) = GPIOF_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let mode = match mode {
-					GpioMode::Input => stm32f303::gpioc::moder::MODER0_A::INPUT,
-					GpioMode::Output => stm32f303::gpioc::moder::MODER0_A::OUTPUT,
-					GpioMode::AltFunc => stm32f303::gpioc::moder::MODER0_A::ALTERNATE,
-					GpioMode::Analog => stm32f303::gpioc::moder::MODER0_A::ANALOG
-				};
-				match pin {
-					0 => gpio.moder.modify(|_, w| w.moder0().variant(mode)),
-					1 => gpio.moder.modify(|_, w| w.moder1().variant(mode)),
-					2 => gpio.moder.modify(|_, w| w.moder2().variant(mode)),
-					3 => gpio.moder.modify(|_, w| w.moder3().variant(mode)),
-					4 => gpio.moder.modify(|_, w| w.moder4().variant(mode)),
-					5 => gpio.moder.modify(|_, w| w.moder5().variant(mode)),
-					6 => gpio.moder.modify(|_, w| w.moder6().variant(mode)),
-					7 => gpio.moder.modify(|_, w| w.moder7().variant(mode)),
-					8 => gpio.moder.modify(|_, w| w.moder8().variant(mode)),
-					9 => gpio.moder.modify(|_, w| w.moder9().variant(mode)),
-					10 => gpio.moder.modify(|_, w| w.moder10().variant(mode)),
-					11 => gpio.moder.modify(|_, w| w.moder11().variant(mode)),
-					12 => gpio.moder.modify(|_, w| w.moder12().variant(mode)),
-					13 => gpio.moder.modify(|_, w| w.moder13().variant(mode)),
-					14 => gpio.moder.modify(|_, w| w.moder14().variant(mode)),
-					15 => gpio.moder.modify(|_, w| w.moder15().variant(mode)),
-					_ => ()
-				}
-			},
-			GpioPort::PortG => if let Some(ref mut gpio) = GPIOG_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let mode = match mode {
-					GpioMode::Input => stm32f303::gpioc::moder::MODER0_A::INPUT,
-					GpioMode::Output => stm32f303::gpioc::moder::MODER0_A::OUTPUT,
-					GpioMode::AltFunc => stm32f303::gpioc::moder::MODER0_A::ALTERNATE,
-					GpioMode::Analog => stm32f303::gpioc::moder::MODER0_A::ANALOG
-				};
-				match pin {
-					0 => gpio.moder.modify(|_, w| w.moder0().variant(mode)),
-					1 => gpio.moder.modify(|_, w| w.moder1().variant(mode)),
-					2 => gpio.moder.modify(|_, w| w.moder2().variant(mode)),
-					3 => gpio.moder.modify(|_, w| w.moder3().variant(mode)),
-					4 => gpio.moder.modify(|_, w| w.moder4().variant(mode)),
-					5 => gpio.moder.modify(|_, w| w.moder5().variant(mode)),
-					6 => gpio.moder.modify(|_, w| w.moder6().variant(mode)),
-					7 => gpio.moder.modify(|_, w| w.moder7().variant(mode)),
-					8 => gpio.moder.modify(|_, w| w.moder8().variant(mode)),
-					9 => gpio.moder.modify(|_, w| w.moder9().variant(mode)),
-					10 => gpio.moder.modify(|_, w| w.moder10().variant(mode)),
-					11 => gpio.moder.modify(|_, w| w.moder11().variant(mode)),
-					12 => gpio.moder.modify(|_, w| w.moder12().variant(mode)),
-					13 => gpio.moder.modify(|_, w| w.moder13().variant(mode)),
-					14 => gpio.moder.modify(|_, w| w.moder14().variant(mode)),
-					15 => gpio.moder.modify(|_, w| w.moder15().variant(mode)),
-					_ => ()
-				}
-			},
-			GpioPort::PortH => if let Some(ref mut gpio) = GPIOH_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let mode = match mode {
-					GpioMode::Input => stm32f303::gpioc::moder::MODER0_A::INPUT,
-					GpioMode::Output => stm32f303::gpioc::moder::MODER0_A::OUTPUT,
-					GpioMode::AltFunc => stm32f303::gpioc::moder::MODER0_A::ALTERNATE,
-					GpioMode::Analog => stm32f303::gpioc::moder::MODER0_A::ANALOG
-				};
-				match pin {
-					0 => gpio.moder.modify(|_, w| w.moder0().variant(mode)),
-					1 => gpio.moder.modify(|_, w| w.moder1().variant(mode)),
-					2 => gpio.moder.modify(|_, w| w.moder2().variant(mode)),
-					3 => gpio.moder.modify(|_, w| w.moder3().variant(mode)),
-					4 => gpio.moder.modify(|_, w| w.moder4().variant(mode)),
-					5 => gpio.moder.modify(|_, w| w.moder5().variant(mode)),
-					6 => gpio.moder.modify(|_, w| w.moder6().variant(mode)),
-					7 => gpio.moder.modify(|_, w| w.moder7().variant(mode)),
-					8 => gpio.moder.modify(|_, w| w.moder8().variant(mode)),
-					9 => gpio.moder.modify(|_, w| w.moder9().variant(mode)),
-					10 => gpio.moder.modify(|_, w| w.moder10().variant(mode)),
-					11 => gpio.moder.modify(|_, w| w.moder11().variant(mode)),
-					12 => gpio.moder.modify(|_, w| w.moder12().variant(mode)),
-					13 => gpio.moder.modify(|_, w| w.moder13().variant(mode)),
-					14 => gpio.moder.modify(|_, w| w.moder14().variant(mode)),
-					15 => gpio.moder.modify(|_, w| w.moder15().variant(mode)),
-					_ => ()
-				}
-			},
+fn read_config(port: GpioPort, pin: u8) -> PinConfig {
+	let gpio = registers(port);
+	let field = |bits: u32, width: u8| (bits >> (pin * width)) & ((1 << width) - 1);
+	let afr = if pin < 8 { gpio.afrl.read().bits() } else { gpio.afrh.read().bits() };
+
+	PinConfig {
+		mode: match field(gpio.moder.read().bits(), 2) {
+			0 => GpioMode::Input,
+			1 => GpioMode::Output,
+			2 => GpioMode::AltFunc,
+			_ => GpioMode::Analog,
+		},
+		pull: match field(gpio.pupdr.read().bits(), 2) {
+			1 => PinPull::PullUp,
+			2 => PinPull::PullDown,
+			_ => PinPull::NoPull,
+		},
+		output_type: if field(gpio.otyper.read().bits(), 1) != 0 { OutputType::OpenDrain } else { OutputType::PushPull },
+		speed: match field(gpio.ospeedr.read().bits(), 2) {
+			1 => OutputSpeed::Medium,
+			3 => OutputSpeed::High,
+			_ => OutputSpeed::Low,
+		},
+		alt_func: ((afr >> ((pin % 8) * 4)) & 0xF) as u8,
+		signal: pinmux::get_owner(port, pin),
+		state: if field(gpio.odr.read().bits(), 1) != 0 { PinState::PinHigh } else { PinState::PinLow },
+	}
+}
+
+// A single BSRR store, no critical section needed
+#[inline]
+fn set_pin_state(port: GpioPort, pin: u8, state: PinState) {
+	if pin >= PIN_COUNT {
+		return;
+	}
+
+	let bits = match state {
+		PinState::PinHigh => 1 << pin,
+		PinState::PinLow => 1 << (pin + 16),
+	};
+	registers(port).bsrr.write(|w| unsafe { w.bits(bits) });
+}
+
+// ODR is only read, the change itself is a BSRR store, so a concurrent write
+// to another pin of the port cannot be lost
+#[inline]
+fn toggle_pin(port: GpioPort, pin: u8) {
+	if pin >= PIN_COUNT {
+		return;
+	}
+
+	let gpio = registers(port);
+	let mask = 1 << pin;
+	let bits = if gpio.odr.read().bits() & mask != 0 { mask << 16 } else { mask };
+	gpio.bsrr.write(|w| unsafe { w.bits(bits) });
+}
+
+#[inline(always)]
+fn registers(port: GpioPort) -> &'static RegisterBlock {
+	let ptr = match port {
+		GpioPort::PortA => stm32f303::GPIOA::ptr() as *const RegisterBlock,
+		GpioPort::PortB => stm32f303::GPIOB::ptr() as *const RegisterBlock,
+		GpioPort::PortC => stm32f303::GPIOC::ptr(),
+		GpioPort::PortD => stm32f303::GPIOD::ptr(),
+		GpioPort::PortE => stm32f303::GPIOE::ptr(),
+		GpioPort::PortF => stm32f303::GPIOF::ptr(),
+		GpioPort::PortG => stm32f303::GPIOG::ptr(),
+		GpioPort::PortH => stm32f303::GPIOH::ptr(),
+	};
+
+	// Same layout on every port and the handles are held by this module
+	unsafe { &*ptr }
+}
+
+// Replaces the `width` bit field of each pin set in `mask` with `value`
+fn set_field_mask(bits: u32, mask: u16, width: u32, value: u32) -> u32 {
+	let field = (1 << width) - 1;
+
+	(0..PIN_COUNT as u32)
+		.filter(|pin| mask & (1 << pin) != 0)
+		.fold(bits, |bits, pin| bits & !(field << (pin * width)) | (value & field) << (pin * width))
+}
+
+fn set_output_type(port: GpioPort, pin: u8, out_type: OutputType) {
+	if pin >= PIN_COUNT {
+		return;
+	}
+
+	let gpio = registers(port);
+	free(|_| gpio.otyper.modify(|r, w| unsafe { w.bits(set_field_mask(r.bits(), 1 << pin, 1, out_type as u32)) }));
+}
+
+fn set_exti_source(port: GpioPort, line: u8) {
+	let shift = (line % 4) * 4;
+	let code = port as u32;
+
+	free(|cs| if let Some(syscfg) = SYSCFG_HANDLE.borrow(cs).borrow().as_ref() {
+		match line / 4 {
+			0 => syscfg.exticr1.modify(|r, w| unsafe { w.bits(r.bits() & !(0xF << shift) | code << shift) }),
+			1 => syscfg.exticr2.modify(|r, w| unsafe { w.bits(r.bits() & !(0xF << shift) | code << shift) }),
+			2 => syscfg.exticr3.modify(|r, w| unsafe { w.bits(r.bits() & !(0xF << shift) | code << shift) }),
+			_ => syscfg.exticr4.modify(|r, w| unsafe { w.bits(r.bits() & !(0xF << shift) | code << shift) }),
 		}
 	});
 }
 
-#[allow(dead_code)]
-fn set_pin_pull(port: GpioPort, pin: u8, pull: PinPull) {
-	free(|cs| {
-		match port {
-			GpioPort::PortA => if let Some(ref mut gpio) = GPIOA_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let pull = match pull {
-					PinPull::NoPull => stm32f303::gpioa::pupdr::PUPDR0_A::FLOATING,
-					PinPull::PullUp => stm32f303::gpioa::pupdr::PUPDR0_A::PULLUP,
-					PinPull::PullDown => stm32f303::gpioa::pupdr::PUPDR0_A::PULLDOWN,
-				};
-				match pin {
-					0 => gpio.pupdr.modify(|_, w| w.pupdr0().variant(pull)),
-					1 => gpio.pupdr.modify(|_, w| w.pupdr1().variant(pull)),
-					2 => gpio.pupdr.modify(|_, w| w.pupdr2().variant(pull)),
-					3 => gpio.pupdr.modify(|_, w| w.pupdr3().variant(pull)),
-					4 => gpio.pupdr.modify(|_, w| w.pupdr4().variant(pull)),
-					5 => gpio.pupdr.modify(|_, w| w.pupdr5().variant(pull)),
-					6 => gpio.pupdr.modify(|_, w| w.pupdr6().variant(pull)),
-					7 => gpio.pupdr.modify(|_, w| w.pupdr7().variant(pull)),
-					8 => gpio.pupdr.modify(|_, w| w.pupdr8().variant(pull)),
-					9 => gpio.pupdr.modify(|_, w| w.pupdr9().variant(pull)),
-					10 => gpio.pupdr.modify(|_, w| w.pupdr10().variant(pull)),
-					11 => gpio.pupdr.modify(|_, w| w.pupdr11().variant(pull)),
-					12 => gpio.pupdr.modify(|_, w| w.pupdr12().variant(pull)),
-					13 => gpio.pupdr.modify(|_, w| w.pupdr13().variant(pull)),
-					14 => gpio.pupdr.modify(|_, w| w.pupdr14().variant(pull)),
-					15 => gpio.pupdr.modify(|_, w| w.pupdr15().variant(pull)),
-					_ => ()
-				}
-			},
-			GpioPort::PortB => if let Some(ref mut gpio) = GPIOB_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let pull = match pull {
-					PinPull::NoPull => stm32f303::gpiob::pupdr::PUPDR0_A::FLOATING,
-					PinPull::PullUp => stm32f303::gpiob::pupdr::PUPDR0_A::PULLUP,
-					PinPull::PullDown => stm32f303::gpiob::pupdr::PUPDR0_A::PULLDOWN,
-				};
-				match pin {
-					0 => gpio.pupdr.modify(|_, w| w.pupdr0().variant(pull)),
-					1 => gpio.pupdr.modify(|_, w| w.pupdr1().variant(pull)),
-					2 => gpio.pupdr.modify(|_, w| w.pupdr2().variant(pull)),
-					3 => gpio.pupdr.modify(|_, w| w.pupdr3().variant(pull)),
-					4 => gpio.pupdr.modify(|_, w| w.pupdr4().variant(pull)),
-					5 => gpio.pupdr.modify(|_, w| w.pupdr5().variant(pull)),
-					6 => gpio.pupdr.modify(|_, w| w.pupdr6().variant(pull)),
-					7 => gpio.pupdr.modify(|_, w| w.pupdr7().variant(pull)),
-					8 => gpio.pupdr.modify(|_, w| w.pupdr8().variant(pull)),
-					9 => gpio.pupdr.modify(|_, w| w.pupdr9().variant(pull)),
-					10 => gpio.pupdr.modify(|_, w| w.pupdr10().variant(pull)),
-					11 => gpio.pupdr.modify(|_, w| w.pupdr11().variant(pull)),
-					12 => gpio.pupdr.modify(|_, w| w.pupdr12().variant(pull)),
-					13 => gpio.pupdr.modify(|_, w| w.pupdr13().variant(pull)),
-					14 => gpio.pupdr.modify(|_, w| w.pupdr14().variant(pull)),
-					15 => gpio.pupdr.modify(|_, w| w.pupdr15().variant(pull)),
-					_ => ()
-				}
-			},
-			GpioPort::PortC => if let Some(ref mut gpio) = GPIOC_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let pull = match pull {
-					PinPull::NoPull => stm32f303::gpioc::pupdr::PUPDR0_A::FLOATING,
-					PinPull::PullUp => stm32f303::gpioc::pupdr::PUPDR0_A::PULLUP,
-					PinPull::PullDown => stm32f303::gpioc::pupdr::PUPDR0_A::PULLDOWN,
-				};
-				match pin {
-					0 => gpio.pupdr.modify(|_, w| w.pupdr0().variant(pull)),
-					1 => gpio.pupdr.modify(|_, w| w.pupdr1().variant(pull)),
-					2 => gpio.pupdr.modify(|_, w| w.pupdr2().variant(pull)),
-					3 => gpio.pupdr.modify(|_, w| w.pupdr3().variant(pull)),
-					4 => gpio.pupdr.modify(|_, w| w.pupdr4().variant(pull)),
-					5 => gpio.pupdr.modify(|_, w| w.pupdr5().variant(pull)),
-					6 => gpio.pupdr.modify(|_, w| w.pupdr6().variant(pull)),
-					7 => gpio.pupdr.modify(|_, w| w.pupdr7().variant(pull)),
-					8 => gpio.pupdr.modify(|_, w| w.pupdr8().variant(pull)),
-					9 => gpio.pupdr.modify(|_, w| w.pupdr9().variant(pull)),
-					10 => gpio.pupdr.modify(|_, w| w.pupdr10().variant(pull)),
-					11 => gpio.pupdr.modify(|_, w| w.pupdr11().variant(pull)),
-					12 => gpio.pupdr.modify(|_, w| w.pupdr12().variant(pull)),
-					13 => gpio.pupdr.modify(|_, w| w.pupdr13().variant(pull)),
-					14 => gpio.pupdr.modify(|_, w| w.pupdr14().variant(pull)),
-					15 => gpio.pupdr.modify(|_, w| w.pupdr15().variant(pull)),
-					_ => ()
-				}
-			},
-			GpioPort::PortD => if let Some(ref mut gpio) = GPIOD_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let pull = match pull {
-					PinPull::NoPull => stm32f303::gpioc::pupdr::PUPDR0_A::FLOATING,
-					PinPull::PullUp => stm32f303::gpioc::pupdr::PUPDR0_A::PULLUP,
-					PinPull::PullDown => stm32f303::gpioc::pupdr::PUPDR0_A::PULLDOWN,
-				};
-				match pin {
-					0 => gpio.pupdr.modify(|_, w| w.pupdr0().variant(pull)),
-					1 => gpio.pupdr.modify(|_, w| w.pupdr1().variant(pull)),
-					2 => gpio.pupdr.modify(|_, w| w.pupdr2().variant(pull)),
-					3 => gpio.pupdr.modify(|_, w| w.pupdr3().variant(pull)),
-					4 => gpio.pupdr.modify(|_, w| w.pupdr4().variant(pull)),
-					5 => gpio.pupdr.modify(|_, w| w.pupdr5().variant(pull)),
-					6 => gpio.pupdr.modify(|_, w| w.pupdr6().variant(pull)),
-					7 => gpio.pupdr.modify(|_, w| w.pupdr7().variant(pull)),
-					8 => gpio.pupdr.modify(|_, w| w.pupdr8().variant(pull)),
-					9 => gpio.pupdr.modify(|_, w| w.pupdr9().variant(pull)),
-					10 => gpio.pupdr.modify(|_, w| w.pupdr10().variant(pull)),
-					11 => gpio.pupdr.modify(|_, w| w.pupdr11().variant(pull)),
-					12 => gpio.pupdr.modify(|_, w| w.pupdr12().variant(pull)),
-					13 => gpio.pupdr.modify(|_, w| w.pupdr13().variant(pull)),
-					14 => gpio.pupdr.modify(|_, w| w.pupdr14().variant(pull)),
-					15 => gpio.pupdr.modify(|_, w| w.pupdr15().variant(pull)),
-					_ => ()
-				}
-			},
-			GpioPort::PortE => if let Some(ref mut gpio) = GPIOE_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let pull = match pull {
-					PinPull::NoPull => stm32f303::gpioc::pupdr::PUPDR0_A::FLOATING,
-					PinPull::PullUp => stm32f303::gpioc::pupdr::PUPDR0_A::PULLUP,
-					PinPull::PullDown => stm32f303::gpioc::pupdr::PUPDR0_A::PULLDOWN,
-				};
-				match pin {
-					0 => gpio.pupdr.modify(|_, w| w.pupdr0().variant(pull)),
-					1 => gpio.pupdr.modify(|_, w| w.pupdr1().variant(pull)),
-					2 => gpio.pupdr.modify(|_, w| w.pupdr2().variant(pull)),
-					3 => gpio.pupdr.modify(|_, w| w.pupdr3().variant(pull)),
-					4 => gpio.pupdr.modify(|_, w| w.pupdr4().variant(pull)),
-					5 => gpio.pupdr.modify(|_, w| w.pupdr5().variant(pull)),
-					6 => gpio.pupdr.modify(|_, w| w.pupdr6().variant(pull)),
-					7 => gpio.pupdr.modify(|_, w| w.pupdr7().variant(pull)),
-					8 => gpio.pupdr.modify(|_, w| w.pupdr8().variant(pull)),
-					9 => gpio.pupdr.modify(|_, w| w.pupdr9().variant(pull)),
-					10 => gpio.pupdr.modify(|_, w| w.pupdr10().variant(pull)),
-					11 => gpio.pupdr.modify(|_, w| w.pupdr11().variant(pull)),
-					12 => gpio.pupdr.modify(|_, w| w.pupdr12().variant(pull)),
-					13 => gpio.pupdr.modify(|_, w| w.pupdr13().variant(pull)),
-					14 => gpio.pupdr.modify(|_, w| w.pupdr14().variant(pull)),
-					15 => gpio.pupdr.modify(|_, w| w.pupdr15().variant(pull)),
-					_ => ()
-				}
-			},
-			GpioPort::PortF => if let Some(ref mut gpio) = GPIOF_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let pull = match pull {
-					PinPull::NoPull => stm32f303::gpioc::pupdr::PUPDR0_A::FLOATING,
-					PinPull::PullUp => stm32f303::gpioc::pupdr::PUPDR0_A::PULLUP,
-					PinPull::PullDown => stm32f303::gpioc::pupdr::PUPDR0_A::PULLDOWN,
-				};
-				match pin {
-					0 => gpio.pupdr.modify(|_, w| w.pupdr0().variant(pull)),
-					1 => gpio.pupdr.modify(|_, w| w.pupdr1().variant(pull)),
-					2 => gpio.pupdr.modify(|_, w| w.pupdr2().variant(pull)),
-					3 => gpio.pupdr.modify(|_, w| w.pupdr3().variant(pull)),
-					4 => gpio.pupdr.modify(|_, w| w.pupdr4().variant(pull)),
-					5 => gpio.pupdr.modify(|_, w| w.pupdr5().variant(pull)),
-					6 => gpio.pupdr.modify(|_, w| w.pupdr6().variant(pull)),
-					7 => gpio.pupdr.modify(|_, w| w.pupdr7().variant(pull)),
-					8 => gpio.pupdr.modify(|_, w| w.pupdr8().variant(pull)),
-					9 => gpio.pupdr.modify(|_, w| w.pupdr9().variant(pull)),
-					10 => gpio.pupdr.modify(|_, w| w.pupdr10().variant(pull)),
-					11 => gpio.pupdr.modify(|_, w| w.pupdr11().variant(pull)),
-					12 => gpio.pupdr.modify(|_, w| w.pupdr12().variant(pull)),
-					13 => gpio.pupdr.modify(|_, w| w.pupdr13().variant(pull)),
-					14 => gpio.pupdr.modify(|_, w| w.pupdr14().variant(pull)),
-					15 => gpio.pupdr.modify(|_, w| w.pupdr15().variant(pull)),
-					_ => ()
-				}
-			},
-			GpioPort::PortG => if let Some(ref mut gpio) = GPIOG_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let pull = match pull {
-					PinPull::NoPull => stm32f303::gpioc::pupdr::PUPDR0_A::FLOATING,
-					PinPull::PullUp => stm32f303::gpioc::pupdr::PUPDR0_A::PULLUP,
-					PinPull::PullDown => stm32f303::gpioc::pupdr::PUPDR0_A::PULLDOWN,
-				};
-				match pin {
-					0 => gpio.pupdr.modify(|_, w| w.pupdr0().variant(pull)),
-					1 => gpio.pupdr.modify(|_, w| w.pupdr1().variant(pull)),
-					2 => gpio.pupdr.modify(|_, w| w.pupdr2().variant(pull)),
-					3 => gpio.pupdr.modify(|_, w| w.pupdr3().variant(pull)),
-					4 => gpio.pupdr.modify(|_, w| w.pupdr4().variant(pull)),
-					5 => gpio.pupdr.modify(|_, w| w.pupdr5().variant(pull)),
-					6 => gpio.pupdr.modify(|_, w| w.pupdr6().variant(pull)),
-					7 => gpio.pupdr.modify(|_, w| w.pupdr7().variant(pull)),
-					8 => gpio.pupdr.modify(|_, w| w.pupdr8().variant(pull)),
-					9 => gpio.pupdr.modify(|_, w| w.pupdr9().variant(pull)),
-					10 => gpio.pupdr.modify(|_, w| w.pupdr10().variant(pull)),
-					11 => gpio.pupdr.modify(|_, w| w.pupdr11().variant(pull)),
-					12 => gpio.pupdr.modify(|_, w| w.pupdr12().variant(pull)),
-					13 => gpio.pupdr.modify(|_, w| w.pupdr13().variant(pull)),
-					14 => gpio.pupdr.modify(|_, w| w.pupdr14().variant(pull)),
-					15 => gpio.pupdr.modify(|_, w| w.pupdr15().variant(pull)),
-					_ => ()
-				}
-			},
-			GpioPort::PortH => if let Some(ref mut gpio) = GPIOH_HANDLE.borrow(cs).borrow_mut().deref_mut() {
-				let pull = match pull {
-					PinPull::NoPull => stm32f303::gpioc::pupdr::PUPDR0_A::FLOATING,
-					PinPull::PullUp => stm32f303::gpioc::pupdr::PUPDR0_A::PULLUP,
-					PinPull::PullDown => stm32f303::gpioc::pupdr::PUPDR0_A::PULLDOWN,
-				};
-				match pin {
-					0 => gpio.pupdr.modify(|_, w| w.pupdr0().variant(pull)),
-					1 => gpio.pupdr.modify(|_, w| w.pupdr1().variant(pull)),
-					2 => gpio.pupdr.modify(|_, w| w.pupdr2().variant(pull)),
-					3 => gpio.pupdr.modify(|_, w| w.pupdr3().variant(pull)),
-					4 => gpio.pupdr.modify(|_, w| w.pupdr4().variant(pull)),
-					5 => gpio.pupdr.modify(|_, w| w.pupdr5().variant(pull)),
-					6 => gpio.pupdr.modify(|_, w| w.pupdr6().variant(pull)),
-					7 => gpio.pupdr.modify(|_, w| w.pupdr7().variant(pull)),
-					8 => gpio.pupdr.modify(|_, w| w.pupdr8().variant(pull)),
-					9 => gpio.pupdr.modify(|_, w| w.pupdr9().variant(pull)),
-					10 => gpio.pupdr.modify(|_, w| w.pupdr10().variant(pull)),
-					11 => gpio.pupdr.modify(|_, w| w.pupdr11().variant(pull)),
-					12 => gpio.pupdr.modify(|_, w| w.pupdr12().variant(pull)),
-					13 => gpio.pupdr.modify(|_, w| w.pupdr13().variant(pull)),
-					14 => gpio.pupdr.modify(|_, w| w.pupdr14().variant(pull)),
-					15 => gpio.pupdr.modify(|_, w| w.pupdr15().variant(pull)),
-					_ => ()
-				}
-			},
+// Frees the line if this port holds it, nothing otherwise
+fn release_exti(port: GpioPort, line: u8) {
+	let owned = free(|cs| {
+		let mut owners = EXTI_OWNERS.borrow(cs).get();
+		if owners[line as usize] != Some(port) {
+			return false;
 		}
+		owners[line as usize] = None;
+		EXTI_OWNERS.borrow(cs).set(owners);
+
+		let mut callbacks = EXTI_CALLBACKS.borrow(cs).get();
+		callbacks[line as usize] = None;
+		EXTI_CALLBACKS.borrow(cs).set(callbacks);
+		true
 	});
+
+	if owned {
+		exti::set_interrupt_enable(line, false);
+		exti::set_event_enable(line, false);
+		exti::clear_pending(line);
+		EXTI_FLAGS.fetch_and(!(1 << line), Ordering::Relaxed);
+	}
+}
+
+// The vector shared by a line, the NVIC side stays unmasked once used
+fn exti_interrupt(line: u8) -> stm32f303::Interrupt {
+	match line {
+		0 => stm32f303::Interrupt::EXTI0,
+		1 => stm32f303::Interrupt::EXTI1,
+		2 => stm32f303::Interrupt::EXTI2_TSC,
+		3 => stm32f303::Interrupt::EXTI3,
+		4 => stm32f303::Interrupt::EXTI4,
+		5..=9 => stm32f303::Interrupt::EXTI9_5,
+		_ => stm32f303::Interrupt::EXTI15_10,
+	}
+}
+
+fn handle_exti(mask: u32) {
+	let pending = exti::take_pending(mask);
+	if pending == 0 {
+		return;
+	}
+
+	EXTI_FLAGS.fetch_or(pending, Ordering::Relaxed);
+
+	let callbacks = free(|cs| EXTI_CALLBACKS.borrow(cs).get());
+	for line in 0..PIN_COUNT {
+		if pending & (1 << line) != 0 {
+			if let Some(callback) = callbacks[line as usize] {
+				callback(line);
+			}
+		}
+	}
+}
+
+// Advances one button, pushing any events through `emit`
+fn update_button(button: &mut Button, now: u32, mut emit: impl FnMut(ButtonEvent)) {
+	let raw = button.pin.get_state() == button.active;
+	if raw != button.raw {
+		button.raw = raw;
+		button.changed_at = now;
+	}
+
+	if raw != button.pressed && now.wrapping_sub(button.changed_at) >= button.timings.debounce {
+		button.pressed = raw;
+
+		if raw {
+			button.pressed_at = now;
+			button.long_sent = false;
+			emit(ButtonEvent::Press);
+
+			button.double_sent = matches!(button.released_at,
+				Some(released) if now.wrapping_sub(released) <= button.timings.double_click);
+			if button.double_sent {
+				button.released_at = None;
+				emit(ButtonEvent::DoubleClick);
+			}
+		}
+		else {
+			emit(ButtonEvent::Release);
+
+			// Only a short, single press can start a double click
+			button.released_at = if button.long_sent || button.double_sent { None } else { Some(now) };
+		}
+	}
+
+	if button.pressed && !button.long_sent && now.wrapping_sub(button.pressed_at) >= button.timings.long_press {
+		button.long_sent = true;
+		emit(ButtonEvent::LongPress);
+	}
+}
+
+fn measure<F: FnMut()>(mut operation: F) -> u32 {
+	let start = DWT::cycle_count();
+	// black_box keeps the empty overhead loop from being optimised out
+	for i in 0..BENCHMARK_ITERATIONS {
+		core::hint::black_box(i);
+		operation();
+	}
+	DWT::cycle_count().wrapping_sub(start) / BENCHMARK_ITERATIONS
+}
+
+//==============================================================================
+// Interrupt Handlers
+//==============================================================================
+#[interrupt]
+fn EXTI0() {
+	handle_exti(1 << 0);
+}
+
+#[interrupt]
+fn EXTI1() {
+	handle_exti(1 << 1);
+}
+
+#[interrupt]
+fn EXTI2_TSC() {
+	handle_exti(1 << 2);
+}
+
+#[interrupt]
+fn EXTI3() {
+	handle_exti(1 << 3);
+}
+
+#[interrupt]
+fn EXTI4() {
+	handle_exti(1 << 4);
+}
+
+#[interrupt]
+fn EXTI9_5() {
+	handle_exti(0x0000_03E0);
+}
+
+#[interrupt]
+fn EXTI15_10() {
+	handle_exti(0x0000_FC00);
 }
 
 //==============================================================================
 // Task Handler
 //==============================================================================
 pub fn task_handler() {
+	let now = timer::get_tick();
+	let mut events: [Option<(ButtonCallback, usize, ButtonEvent)>; MAX_BUTTON_EVENTS] = [None; MAX_BUTTON_EVENTS];
+	let mut count = 0;
+
+	free(|cs| {
+		for (id, button) in BUTTONS.borrow(cs).borrow_mut().iter_mut().enumerate() {
+			if let Some(button) = button {
+				let callback = button.callback;
+				let mut event = None;
 
-}
\ No newline at end of file
+				update_button(button, now, |e| {
+					event = Some(e);
+					if let Some(callback) = callback {
+						if count < MAX_BUTTON_EVENTS {
+							events[count] = Some((callback, id, e));
+							count += 1;
+						}
+					}
+				});
+
+				if event.is_some() {
+					button.event = event;
+				}
+			}
+		}
+	});
+
+	// Callbacks run outside the critical section, free to use the gpio API
+	for (callback, id, event) in events.iter().flatten() {
+		callback(*id, *event);
+	}
+}
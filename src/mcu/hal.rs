@@ -0,0 +1,378 @@
+//==============================================================================
+// Notes
+//==============================================================================
+// mcu/hal.rs
+
+/*
+ * embedded-hal trait implementations over the mcu modules, built with the
+ * embedded-hal feature. Everything here is blocking or polled, the traits
+ * sit directly on the module functions:
+ *
+ * - digital: gpio::Pin<Output> and gpio::Pin<Input>
+ * - serial: uart::Uart, embedded-hal-nb for non-blocking and embedded-io
+ *   for blocking
+ * - spi: spi::Spi as the bus, spi::SpiDevice as a device with its own chip
+ *   select and settings, holding the bus lock for each transaction
+ * - i2c: i2c::I2c
+ * - delay: Delay, on the TIM6 delays in timer
+ * - adc: embedded-hal 0.2 OneShot, 1.0 has no ADC traits
+ */
+
+//==============================================================================
+// Crates and Mods
+//==============================================================================
+use core::convert::Infallible;
+use embedded_hal::delay;
+use embedded_hal::digital;
+use embedded_hal::i2c as hal_i2c;
+use embedded_hal::spi as hal_spi;
+use embedded_hal_02::adc as hal_adc;
+use embedded_hal_nb::serial;
+
+use crate::mcu::adc;
+use crate::mcu::gpio;
+use crate::mcu::i2c;
+use crate::mcu::spi;
+use crate::mcu::timer;
+use crate::mcu::uart;
+
+//==============================================================================
+// Enums, Structs, and Types
+//==============================================================================
+// Busy-wait delays on TIM6
+#[allow(dead_code)]
+pub struct Delay;
+
+// ADC1-4, the const is the ADC number
+#[allow(dead_code)]
+pub struct AdcUnit<const N: u8>;
+
+// Regular input channel CH of ADC N
+#[allow(dead_code)]
+pub struct AdcChannel<const N: u8, const CH: u8>;
+
+#[allow(dead_code)]
+pub type Adc1 = AdcUnit<1>;
+#[allow(dead_code)]
+pub type Adc2 = AdcUnit<2>;
+#[allow(dead_code)]
+pub type Adc3 = AdcUnit<3>;
+#[allow(dead_code)]
+pub type Adc4 = AdcUnit<4>;
+
+//==============================================================================
+// GPIO
+//==============================================================================
+impl digital::ErrorType for gpio::Pin<gpio::Output> {
+	type Error = Infallible;
+}
+
+impl digital::ErrorType for gpio::Pin<gpio::Input> {
+	type Error = Infallible;
+}
+
+impl digital::OutputPin for gpio::Pin<gpio::Output> {
+	fn set_low(&mut self) -> Result<(), Self::Error> {
+		gpio::Pin::set_low(self);
+		Ok(())
+	}
+
+	fn set_high(&mut self) -> Result<(), Self::Error> {
+		gpio::Pin::set_high(self);
+		Ok(())
+	}
+}
+
+impl digital::StatefulOutputPin for gpio::Pin<gpio::Output> {
+	fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+		Ok(self.get_output_state() == gpio::PinState::PinHigh)
+	}
+
+	fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+		Ok(self.get_output_state() == gpio::PinState::PinLow)
+	}
+
+	fn toggle(&mut self) -> Result<(), Self::Error> {
+		gpio::Pin::toggle(self);
+		Ok(())
+	}
+}
+
+impl digital::InputPin for gpio::Pin<gpio::Input> {
+	fn is_high(&mut self) -> Result<bool, Self::Error> {
+		Ok(self.get_state() == gpio::PinState::PinHigh)
+	}
+
+	fn is_low(&mut self) -> Result<bool, Self::Error> {
+		Ok(self.get_state() == gpio::PinState::PinLow)
+	}
+}
+
+// The pad level, which can differ from the driven one on an open-drain line
+impl digital::InputPin for gpio::Pin<gpio::Output> {
+	fn is_high(&mut self) -> Result<bool, Self::Error> {
+		Ok(self.get_state() == gpio::PinState::PinHigh)
+	}
+
+	fn is_low(&mut self) -> Result<bool, Self::Error> {
+		Ok(self.get_state() == gpio::PinState::PinLow)
+	}
+}
+
+//==============================================================================
+// UART
+//==============================================================================
+impl serial::Error for uart::UartError {
+	fn kind(&self) -> serial::ErrorKind {
+		match self {
+			uart::UartError::Overrun => serial::ErrorKind::Overrun,
+			uart::UartError::Framing => serial::ErrorKind::FrameFormat,
+			uart::UartError::Parity => serial::ErrorKind::Parity,
+			uart::UartError::Noise => serial::ErrorKind::Noise,
+			_ => serial::ErrorKind::Other,
+		}
+	}
+}
+
+impl serial::ErrorType for uart::Uart {
+	type Error = uart::UartError;
+}
+
+impl serial::Read<u8> for uart::Uart {
+	fn read(&mut self) -> nb::Result<u8, Self::Error> {
+		match self.read_byte() {
+			Ok(Some(byte)) => Ok(byte),
+			Ok(None) => Err(nb::Error::WouldBlock),
+			Err(error) => Err(nb::Error::Other(error)),
+		}
+	}
+}
+
+impl serial::Write<u8> for uart::Uart {
+	fn write(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+		if self.write_byte(word) { Ok(()) } else { Err(nb::Error::WouldBlock) }
+	}
+
+	fn flush(&mut self) -> nb::Result<(), Self::Error> {
+		if self.is_tx_complete() { Ok(()) } else { Err(nb::Error::WouldBlock) }
+	}
+}
+
+impl embedded_io::Error for uart::UartError {
+	fn kind(&self) -> embedded_io::ErrorKind {
+		match self {
+			uart::UartError::Overrun => embedded_io::ErrorKind::OutOfMemory,
+			uart::UartError::Framing | uart::UartError::Parity | uart::UartError::Noise => embedded_io::ErrorKind::InvalidData,
+			uart::UartError::NotStarted => embedded_io::ErrorKind::NotConnected,
+			_ => embedded_io::ErrorKind::Other,
+		}
+	}
+}
+
+impl embedded_io::ErrorType for uart::Uart {
+	type Error = uart::UartError;
+}
+
+// Blocks for the first byte, then takes whatever else has already arrived.
+// A receive error after some bytes ends the read short, and is left for the
+// next read.
+impl embedded_io::Read for uart::Uart {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+		if buf.is_empty() {
+			return Ok(0);
+		}
+
+		let mut count = 0;
+		while count < buf.len() {
+			if count > 0 && self.has_rx_error() {
+				break;
+			}
+
+			match self.read_byte()? {
+				Some(byte) => {
+					buf[count] = byte;
+					count += 1;
+				},
+				None if count > 0 => break,
+				None => {},
+			}
+		}
+
+		Ok(count)
+	}
+}
+
+// Blocks until the whole buffer is in the transmit register
+impl embedded_io::Write for uart::Uart {
+	fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+		for byte in buf {
+			while !self.write_byte(*byte) {}
+		}
+
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> Result<(), Self::Error> {
+		while !self.is_tx_complete() {}
+		Ok(())
+	}
+}
+
+//==============================================================================
+// SPI
+//==============================================================================
+impl hal_spi::Error for spi::SpiError {
+	fn kind(&self) -> hal_spi::ErrorKind {
+		match self {
+			spi::SpiError::ModeFault => hal_spi::ErrorKind::ModeFault,
+			spi::SpiError::Overrun => hal_spi::ErrorKind::Overrun,
+			_ => hal_spi::ErrorKind::Other,
+		}
+	}
+}
+
+impl hal_spi::ErrorType for spi::Spi {
+	type Error = spi::SpiError;
+}
+
+// u8 words for frames up to 8 bits, u16 above
+impl<W: spi::SpiWord + 'static> hal_spi::SpiBus<W> for spi::Spi {
+	fn read(&mut self, words: &mut [W]) -> Result<(), Self::Error> {
+		spi::read(*self, words)
+	}
+
+	fn write(&mut self, words: &[W]) -> Result<(), Self::Error> {
+		spi::write(*self, words)
+	}
+
+	// Runs for the longer of the two, padding the write and dropping the
+	// surplus reads
+	fn transfer(&mut self, read: &mut [W], write: &[W]) -> Result<(), Self::Error> {
+		for i in 0..read.len().max(write.len()) {
+			let word = spi::transfer_word(*self, write.get(i).copied().unwrap_or(W::FILL))?;
+			if let Some(slot) = read.get_mut(i) {
+				*slot = word;
+			}
+		}
+		Ok(())
+	}
+
+	fn transfer_in_place(&mut self, words: &mut [W]) -> Result<(), Self::Error> {
+		spi::transfer(*self, words)
+	}
+
+	fn flush(&mut self) -> Result<(), Self::Error> {
+		spi::flush(*self)
+	}
+}
+
+impl hal_spi::ErrorType for spi::SpiDevice {
+	type Error = spi::SpiError;
+}
+
+impl<W: spi::SpiWord + 'static> hal_spi::SpiDevice<W> for spi::SpiDevice {
+	fn transaction(&mut self, operations: &mut [hal_spi::Operation<'_, W>]) -> Result<(), Self::Error> {
+		spi::SpiDevice::transaction(self, |bus| operations.iter_mut().try_for_each(|operation| match operation {
+			hal_spi::Operation::Read(words) => bus.read(words),
+			hal_spi::Operation::Write(words) => bus.write(words),
+			hal_spi::Operation::Transfer(read, write) => {
+				for i in 0..read.len().max(write.len()) {
+					let word = bus.transfer_word(write.get(i).copied().unwrap_or(W::FILL))?;
+					if let Some(slot) = read.get_mut(i) {
+						*slot = word;
+					}
+				}
+				Ok(())
+			},
+			hal_spi::Operation::TransferInPlace(words) => bus.transfer(words),
+			hal_spi::Operation::DelayNs(ns) => bus.delay_ns(*ns),
+		}))
+	}
+}
+
+//==============================================================================
+// I2C
+//==============================================================================
+impl hal_i2c::Error for i2c::I2cError {
+	fn kind(&self) -> hal_i2c::ErrorKind {
+		match self {
+			i2c::I2cError::Nack => hal_i2c::ErrorKind::NoAcknowledge(hal_i2c::NoAcknowledgeSource::Unknown),
+			i2c::I2cError::ArbitrationLost => hal_i2c::ErrorKind::ArbitrationLoss,
+			i2c::I2cError::Bus => hal_i2c::ErrorKind::Bus,
+			i2c::I2cError::Timeout => hal_i2c::ErrorKind::Other,
+		}
+	}
+}
+
+impl hal_i2c::ErrorType for i2c::I2c {
+	type Error = i2c::I2cError;
+}
+
+// Neighbouring operations of the same kind run as one segment, a change of
+// direction gets a repeated START and the last one ends with STOP
+impl hal_i2c::I2c for i2c::I2c {
+	fn transaction(&mut self, address: u8, operations: &mut [hal_i2c::Operation<'_>]) -> Result<(), Self::Error> {
+		let is_read = |operation: &hal_i2c::Operation<'_>| matches!(operation, hal_i2c::Operation::Read(_));
+		let count = operations.len();
+		let mut previous: Option<bool> = None;
+
+		for i in 0..count {
+			let read = is_read(&operations[i]);
+			let start = previous != Some(read);
+			let end = match operations.get(i + 1) {
+				None => i2c::I2cEnd::Stop,
+				Some(next) if is_read(next) == read => i2c::I2cEnd::Continue,
+				Some(_) => i2c::I2cEnd::Restart,
+			};
+
+			match &mut operations[i] {
+				hal_i2c::Operation::Read(buffer) => i2c::transfer_read(*self, address, buffer, start, end)?,
+				hal_i2c::Operation::Write(bytes) => i2c::transfer_write(*self, address, bytes, start, end)?,
+			}
+			previous = Some(read);
+		}
+
+		Ok(())
+	}
+}
+
+//==============================================================================
+// Delay
+//==============================================================================
+impl delay::DelayNs for Delay {
+	fn delay_ns(&mut self, ns: u32) {
+		timer::delay_ns(ns);
+	}
+
+	fn delay_us(&mut self, us: u32) {
+		timer::delay_us(us);
+	}
+
+	fn delay_ms(&mut self, ms: u32) {
+		timer::delay_ms(ms);
+	}
+}
+
+//==============================================================================
+// ADC
+//==============================================================================
+impl<const N: u8, const CH: u8> hal_adc::Channel<AdcUnit<N>> for AdcChannel<N, CH> {
+	type ID = u8;
+
+	fn channel() -> u8 { CH }
+}
+
+impl<const N: u8, const CH: u8> hal_adc::OneShot<AdcUnit<N>, u16, AdcChannel<N, CH>> for AdcUnit<N> {
+	type Error = adc::AdcError;
+
+	fn read(&mut self, _channel: &mut AdcChannel<N, CH>) -> nb::Result<u16, Self::Error> {
+		let unit = match N {
+			1 => adc::Adc::Adc1,
+			2 => adc::Adc::Adc2,
+			3 => adc::Adc::Adc3,
+			_ => adc::Adc::Adc4,
+		};
+
+		adc::read_channel(unit, CH).map_err(nb::Error::Other)
+	}
+}
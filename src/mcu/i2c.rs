@@ -3,17 +3,95 @@
 //==============================================================================
 // mcu/i2c.rs
 
+/*
+ * Polled master, 7 bit addresses. Each transfer_*() call is one segment of a
+ * transaction: it can open with a START (or repeated START) and end by
+ * carrying on in the same direction, leaving the bus for a repeated START,
+ * or with a STOP. Segments over 255 bytes are split with RELOAD.
+ */
+
 //==============================================================================
 // Crates and Mods
 //==============================================================================
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 // use core::ops::DerefMut;
 use cortex_m::interrupt::{free, Mutex};
 use stm32f3::stm32f303;
+use stm32f3::stm32f303::i2c1::RegisterBlock;
+
+use crate::mcu::clocks;
+use crate::mcu::gpio;
+use crate::mcu::pinmux::{self, PinmuxError, Signal};
 
 //==============================================================================
 // Enums, Structs, and Types
 //==============================================================================
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum I2c {
+	I2c1,
+	I2c2
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum I2cSpeed {
+	Standard,
+	Fast
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum I2cError {
+	Nack,
+	ArbitrationLost,
+	Bus,
+	Timeout
+}
+
+// How a segment leaves the bus. Continue means the next segment is in the
+// same direction and follows without a START.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum I2cEnd {
+	Continue,
+	Restart,
+	Stop
+}
+
+// Both lines should also be set open-drain by their owner
+#[allow(dead_code)]
+pub struct I2cPinSet {
+	pub scl: gpio::Pin<gpio::AltFunc<4>>,
+	pub sda: gpio::Pin<gpio::AltFunc<4>>
+}
+
+// I2C3 is not bonded out on the F303VC
+#[allow(dead_code)]
+pub enum I2cPins {
+	I2c1(I2cPinSet),
+	I2c2(I2cPinSet)
+}
+
+impl I2cPins {
+	fn i2c(&self) -> I2c {
+		match self {
+			I2cPins::I2c1(_) => I2c::I2c1,
+			I2cPins::I2c2(_) => I2c::I2c2,
+		}
+	}
+
+	// Each pin has to have been put in its AF as the matching signal
+	fn check(&self) -> Result<(), PinmuxError> {
+		let (set, scl, sda) = match self {
+			I2cPins::I2c1(set) => (set, Signal::I2c1Scl, Signal::I2c1Sda),
+			I2cPins::I2c2(set) => (set, Signal::I2c2Scl, Signal::I2c2Sda),
+		};
+
+		pinmux::check(set.scl.port(), set.scl.pin(), scl)?;
+		pinmux::check(set.sda.port(), set.sda.pin(), sda)
+	}
+}
 
 
 //==============================================================================
@@ -26,6 +104,15 @@ static I2C2_HANDLE: Mutex<RefCell<Option<stm32f303::I2C2>>> =
 static I2C3_HANDLE: Mutex<RefCell<Option<stm32f303::I2C3>>> = 
 	Mutex::new(RefCell::new(None));
 
+static I2C_PINS: Mutex<RefCell<[Option<I2cPins>; 2]>> =
+	Mutex::new(RefCell::new([None, None]));
+
+// Whether enable() holds the I2C's clock
+static ENABLED: Mutex<Cell<[bool; 2]>> = Mutex::new(Cell::new([false; 2]));
+
+const MAX_NBYTES: usize = 255;
+const I2C_TIMEOUT: u32 = 100_000;
+
 //==============================================================================
 // Public Functions
 //==============================================================================
@@ -39,9 +126,228 @@ pub fn init(
 	free(|cs| I2C3_HANDLE.borrow(cs).replace(Some(i2c3)));
 }
 
+// Hands the pins to their I2C, returning any it held before. Pins that do
+// not carry the I2C's signals are handed back with the conflict.
+#[allow(dead_code)]
+pub fn set_pins(pins: I2cPins) -> Result<Option<I2cPins>, (I2cPins, PinmuxError)> {
+	if let Err(error) = pins.check() {
+		return Err((pins, error));
+	}
+
+	let i2c = pins.i2c();
+	Ok(free(|cs| I2C_PINS.borrow(cs).borrow_mut()[i2c as usize].replace(pins)))
+}
+
+#[allow(dead_code)]
+pub fn release_pins(i2c: I2c) -> Option<I2cPins> {
+	free(|cs| I2C_PINS.borrow(cs).borrow_mut()[i2c as usize].take())
+}
+
+// TIMINGR is worked out from the I2C kernel clock, to the RM0316 bus timings.
+// Enabling an enabled bus only changes its speed.
+#[allow(dead_code)]
+pub fn enable(i2c: I2c, speed: I2cSpeed) {
+	if !set_enabled(i2c, true) {
+		set_clock(i2c, true);
+	}
+
+	let clocks = clocks::get_clocks();
+	let kernel_clock = match i2c {
+		I2c::I2c1 => clocks.i2c1clk(),
+		I2c::I2c2 => clocks.i2c2clk(),
+	};
+
+	// (prescaled clock, SCL low, SCL high, data setup, data hold) in ns
+	let (tick_freq, low, high, setup, hold) = match speed {
+		I2cSpeed::Standard => (4_000_000, 5_000, 4_000, 1_250, 500),
+		I2cSpeed::Fast => (8_000_000, 1_250, 500, 500, 125),
+	};
+	let presc = kernel_clock.div_ceil(tick_freq).clamp(1, 16);
+	let tick = (presc as u64 * 1_000_000_000 / kernel_clock.max(1) as u64).max(1) as u32;
+	let ticks = |ns: u32, max: u32| (ns / tick).clamp(1, max + 1);
+
+	with_i2c(i2c, |regs| {
+		regs.cr1.modify(|_, w| w.pe().clear_bit());
+		regs.timingr.write(|w| w
+			.presc().bits((presc - 1) as u8)
+			.scll().bits((ticks(low, 255) - 1) as u8)
+			.sclh().bits((ticks(high, 255) - 1) as u8)
+			.scldel().bits((ticks(setup, 15) - 1) as u8)
+			.sdadel().bits(ticks(hold, 15).min(15) as u8)
+		);
+		regs.cr1.modify(|_, w| w.pe().set_bit());
+	});
+}
+
+#[allow(dead_code)]
+pub fn disable(i2c: I2c) {
+	if set_enabled(i2c, false) {
+		with_i2c(i2c, |regs| regs.cr1.modify(|_, w| w.pe().clear_bit()));
+		set_clock(i2c, false);
+	}
+}
+
+#[allow(dead_code)]
+pub fn write(i2c: I2c, address: u8, bytes: &[u8]) -> Result<(), I2cError> {
+	transfer_write(i2c, address, bytes, true, I2cEnd::Stop)
+}
+
+#[allow(dead_code)]
+pub fn read(i2c: I2c, address: u8, buffer: &mut [u8]) -> Result<(), I2cError> {
+	transfer_read(i2c, address, buffer, true, I2cEnd::Stop)
+}
+
+// Write then read with a repeated START in between, e.g. a register read
+#[allow(dead_code)]
+pub fn write_read(i2c: I2c, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), I2cError> {
+	transfer_write(i2c, address, bytes, true, I2cEnd::Restart)?;
+	transfer_read(i2c, address, buffer, true, I2cEnd::Stop)
+}
+
+#[allow(dead_code)]
+pub fn transfer_write(i2c: I2c, address: u8, bytes: &[u8], start: bool, end: I2cEnd) -> Result<(), I2cError> {
+	let mut chunks = bytes.chunks(MAX_NBYTES).peekable();
+	let mut first = true;
+
+	loop {
+		let chunk = chunks.next().unwrap_or(&[]);
+		let reload = chunks.peek().is_some() || end == I2cEnd::Continue;
+		begin(i2c, address, false, chunk.len(), start && first, reload);
+		first = false;
+
+		for byte in chunk {
+			wait(i2c, |regs| regs.isr.read().txis().bit_is_set())?;
+			with_i2c(i2c, |regs| regs.txdr.write(|w| w.txdata().bits(*byte)));
+		}
+
+		if chunks.peek().is_none() {
+			return finish(i2c, end);
+		}
+		wait(i2c, |regs| regs.isr.read().tcr().bit_is_set())?;
+	}
+}
+
+#[allow(dead_code)]
+pub fn transfer_read(i2c: I2c, address: u8, buffer: &mut [u8], start: bool, end: I2cEnd) -> Result<(), I2cError> {
+	let len = buffer.len();
+	let mut chunks = buffer.chunks_mut(MAX_NBYTES).peekable();
+	let mut first = true;
+
+	if len == 0 {
+		begin(i2c, address, true, 0, start, end == I2cEnd::Continue);
+		return finish(i2c, end);
+	}
+
+	while let Some(chunk) = chunks.next() {
+		let reload = chunks.peek().is_some() || end == I2cEnd::Continue;
+		begin(i2c, address, true, chunk.len(), start && first, reload);
+		first = false;
+
+		for byte in chunk.iter_mut() {
+			wait(i2c, |regs| regs.isr.read().rxne().bit_is_set())?;
+			*byte = with_i2c(i2c, |regs| regs.rxdr.read().rxdata().bits()).unwrap_or(0);
+		}
+
+		if chunks.peek().is_some() {
+			wait(i2c, |regs| regs.isr.read().tcr().bit_is_set())?;
+		}
+	}
+
+	finish(i2c, end)
+}
+
 //==============================================================================
 // Private Functions
 //==============================================================================
+// Returns whether the I2C was enabled before
+fn set_enabled(i2c: I2c, enabled: bool) -> bool {
+	free(|cs| {
+		let mut all = ENABLED.borrow(cs).get();
+		let previous = all[i2c as usize];
+		all[i2c as usize] = enabled;
+		ENABLED.borrow(cs).set(all);
+		previous
+	})
+}
+
+fn set_clock(i2c: I2c, enable: bool) {
+	match i2c {
+		I2c::I2c1 => clocks::set_apb1_peripheral_clock_enable(clocks::Apb1Peripherals::I2C1, enable),
+		I2c::I2c2 => clocks::set_apb1_peripheral_clock_enable(clocks::Apb1Peripherals::I2C2, enable),
+	}
+}
+
+// A START writes the whole of CR2, a continuation only reloads NBYTES after TCR
+fn begin(i2c: I2c, address: u8, read: bool, len: usize, start: bool, reload: bool) {
+	with_i2c(i2c, |regs| if start {
+		regs.cr2.write(|w| w
+			.sadd().bits((address as u16) << 1)
+			.rd_wrn().bit(read)
+			.nbytes().bits(len as u8)
+			.reload().bit(reload)
+			.autoend().clear_bit()
+			.start().set_bit()
+		);
+	}
+	else {
+		regs.cr2.modify(|_, w| w.nbytes().bits(len as u8).reload().bit(reload));
+	});
+}
+
+fn finish(i2c: I2c, end: I2cEnd) -> Result<(), I2cError> {
+	match end {
+		I2cEnd::Continue => wait(i2c, |regs| regs.isr.read().tcr().bit_is_set()),
+		I2cEnd::Restart => wait(i2c, |regs| regs.isr.read().tc().bit_is_set()),
+		I2cEnd::Stop => {
+			wait(i2c, |regs| regs.isr.read().tc().bit_is_set())?;
+			with_i2c(i2c, |regs| regs.cr2.modify(|_, w| w.stop().set_bit()));
+			wait(i2c, |regs| regs.isr.read().stopf().bit_is_set())?;
+			with_i2c(i2c, |regs| regs.icr.write(|w| w.stopcf().set_bit()));
+			Ok(())
+		},
+	}
+}
+
+// Also reports, and clears, a NACK or bus error seen while waiting. After a
+// NACK the peripheral sends the STOP itself.
+fn wait(i2c: I2c, done: impl Fn(&RegisterBlock) -> bool) -> Result<(), I2cError> {
+	for _ in 0..I2C_TIMEOUT {
+		let status = with_i2c(i2c, |regs| {
+			let isr = regs.isr.read();
+			if isr.nackf().bit_is_set() {
+				if isr.stopf().bit_is_clear() {
+					return Ok(false);
+				}
+				regs.icr.write(|w| w.nackcf().set_bit().stopcf().set_bit());
+				// Flush a byte left in TXDR
+				regs.isr.write(|w| w.txe().set_bit());
+				return Err(I2cError::Nack);
+			}
+			if isr.arlo().bit_is_set() {
+				regs.icr.write(|w| w.arlocf().set_bit());
+				return Err(I2cError::ArbitrationLost);
+			}
+			if isr.berr().bit_is_set() {
+				regs.icr.write(|w| w.berrcf().set_bit());
+				return Err(I2cError::Bus);
+			}
+			Ok(done(regs))
+		}).ok_or(I2cError::Timeout)?;
+
+		if status? {
+			return Ok(());
+		}
+	}
+
+	Err(I2cError::Timeout)
+}
+
+fn with_i2c<R>(i2c: I2c, f: impl FnOnce(&RegisterBlock) -> R) -> Option<R> {
+	free(|cs| match i2c {
+		I2c::I2c1 => I2C1_HANDLE.borrow(cs).borrow().as_ref().map(|regs| f(regs)),
+		I2c::I2c2 => I2C2_HANDLE.borrow(cs).borrow().as_ref().map(|regs| f(regs)),
+	})
+}
 
 
 //==============================================================================
@@ -10,9 +10,15 @@ use stm32f3::stm32f303;
 
 pub mod adc;
 pub mod clocks;
+pub mod dma;
+pub mod exti;
 pub mod flash;
 pub mod gpio;
+#[cfg(feature = "embedded-hal")]
+pub mod hal;
 pub mod i2c;
+pub mod pinmux;
+pub mod power;
 pub mod rtc;
 pub mod spi;
 pub mod timer;
@@ -32,23 +38,44 @@ pub mod wdt;
 //==============================================================================
 // Public Functions
 //==============================================================================
-pub fn init() {
+// Returns the GPIO pins for the drivers to claim
+pub fn init() -> gpio::Pins {
 	let peripherals = stm32f303::Peripherals::take().unwrap();
+	let core_peripherals = cortex_m::Peripherals::take().unwrap();
 
+	// Flash first: the clock setup has to adjust its wait states
+	flash::init(
+		peripherals.FLASH
+	);
 	clocks::init(
 		peripherals.RCC
 	);
+	exti::init(
+		peripherals.EXTI
+	);
+	rtc::init(
+		peripherals.RTC
+	);
+	// Power unlocks the backup domain, only then can the LSE and RTC clock start
+	power::init(
+		peripherals.PWR,
+		core_peripherals.SCB
+	);
+	clocks::init_low_speed_clock();
 	
 	adc::init(
 		peripherals.ADC1,
 		peripherals.ADC2,
 		peripherals.ADC3,
-		peripherals.ADC4
+		peripherals.ADC4,
+		peripherals.ADC1_2,
+		peripherals.ADC3_4
 	);
-	flash::init(
-		peripherals.FLASH
+	dma::init(
+		peripherals.DMA1,
+		peripherals.DMA2
 	);
-	gpio::init(
+	let pins = gpio::init(
 		peripherals.GPIOA,
 		peripherals.GPIOB,
 		peripherals.GPIOC,
@@ -56,16 +83,14 @@ pub fn init() {
 		peripherals.GPIOE,
 		peripherals.GPIOF,
 		peripherals.GPIOG,
-		peripherals.GPIOH
+		peripherals.GPIOH,
+		peripherals.SYSCFG
 	);
 	i2c::init(
 		peripherals.I2C1,
 		peripherals.I2C2,
 		peripherals.I2C3
 	);
-	rtc::init(
-		peripherals.RTC
-	);
 	spi::init(
 		peripherals.SPI1,
 		peripherals.SPI2,
@@ -82,7 +107,8 @@ pub fn init() {
 		peripherals.TIM4,
 		peripherals.TIM15,
 		peripherals.TIM16,
-		peripherals.TIM17
+		peripherals.TIM17,
+		core_peripherals.SYST
 	);
 	uart::init(
 		peripherals.USART1,
@@ -92,6 +118,8 @@ pub fn init() {
 	wdt::init(
 		peripherals.IWDG
 	);
+
+	pins
 }
 
 //==============================================================================
@@ -105,9 +133,12 @@ pub fn init() {
 pub fn task_handler() {
 	adc::task_handler();
 	clocks::task_handler();
+	dma::task_handler();
+	exti::task_handler();
 	flash::task_handler();
 	gpio::task_handler();
 	i2c::task_handler();
+	power::task_handler();
 	rtc::task_handler();
 	spi::task_handler();
 	timer::task_handler();
@@ -0,0 +1,328 @@
+//==============================================================================
+// Notes
+//==============================================================================
+// mcu/pinmux.rs
+
+/*
+ * Alternate function table for the STM32F303VC (LQFP100), from the datasheet
+ * alternate function mapping, limited to the signals this firmware drives.
+ * SPI4 is not bonded out on the VC, its signals have no entries.
+ *
+ * gpio claims a pin here whenever it goes into an alternate function, so an
+ * AF number that does not carry the signal on that pin, or a signal routed to
+ * two pins at once, is refused with both parties named in the error. Drivers
+ * check() the pins they are handed against the signals they expect.
+ */
+
+//==============================================================================
+// Crates and Mods
+//==============================================================================
+use core::cell::Cell;
+use cortex_m::interrupt::{free, Mutex};
+
+use crate::mcu::gpio::GpioPort;
+
+//==============================================================================
+// Enums, Structs, and Types
+//==============================================================================
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Signal {
+	// System
+	Mco,
+	Swdio,
+	Swclk,
+	Jtdi,
+	Jtdo,
+	Njtrst,
+
+	// USART
+	Usart1Tx,
+	Usart1Rx,
+	Usart1Cts,
+	Usart1Rts,
+	Usart2Tx,
+	Usart2Rx,
+	Usart2Cts,
+	Usart2Rts,
+	Usart3Tx,
+	Usart3Rx,
+	Usart3Cts,
+	Usart3Rts,
+
+	// SPI
+	Spi1Nss,
+	Spi1Sck,
+	Spi1Miso,
+	Spi1Mosi,
+	Spi2Nss,
+	Spi2Sck,
+	Spi2Miso,
+	Spi2Mosi,
+	Spi3Nss,
+	Spi3Sck,
+	Spi3Miso,
+	Spi3Mosi,
+	Spi4Sck,
+	Spi4Miso,
+	Spi4Mosi,
+
+	// I2C
+	I2c1Scl,
+	I2c1Sda,
+	I2c2Scl,
+	I2c2Sda
+}
+
+// Pins are (port, pin) pairs
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PinmuxError {
+	InvalidAltFunc { port: GpioPort, pin: u8, af: u8, signal: Signal },
+	PinClaimed { port: GpioPort, pin: u8, owner: Signal, claimant: Signal },
+	SignalClaimed { signal: Signal, owner: (GpioPort, u8), claimant: (GpioPort, u8) },
+	NotClaimed { port: GpioPort, pin: u8, signal: Signal }
+}
+
+#[derive(Clone, Copy)]
+struct AltFuncEntry {
+	port: GpioPort,
+	pin: u8,
+	af: u8,
+	signal: Signal
+}
+
+//==============================================================================
+// Variables
+//==============================================================================
+const fn entry(port: GpioPort, pin: u8, af: u8, signal: Signal) -> AltFuncEntry {
+	AltFuncEntry { port, pin, af, signal }
+}
+
+static ALT_FUNC_TABLE: [AltFuncEntry; 73] = [
+	// System
+	entry(GpioPort::PortA, 8, 0, Signal::Mco),
+	entry(GpioPort::PortA, 13, 0, Signal::Swdio),
+	entry(GpioPort::PortA, 14, 0, Signal::Swclk),
+	entry(GpioPort::PortA, 15, 0, Signal::Jtdi),
+	entry(GpioPort::PortB, 3, 0, Signal::Jtdo),
+	entry(GpioPort::PortB, 4, 0, Signal::Njtrst),
+
+	// USART1, AF7
+	entry(GpioPort::PortA, 9, 7, Signal::Usart1Tx),
+	entry(GpioPort::PortA, 10, 7, Signal::Usart1Rx),
+	entry(GpioPort::PortA, 11, 7, Signal::Usart1Cts),
+	entry(GpioPort::PortA, 12, 7, Signal::Usart1Rts),
+	entry(GpioPort::PortB, 6, 7, Signal::Usart1Tx),
+	entry(GpioPort::PortB, 7, 7, Signal::Usart1Rx),
+	entry(GpioPort::PortC, 4, 7, Signal::Usart1Tx),
+	entry(GpioPort::PortC, 5, 7, Signal::Usart1Rx),
+	entry(GpioPort::PortE, 0, 7, Signal::Usart1Tx),
+	entry(GpioPort::PortE, 1, 7, Signal::Usart1Rx),
+
+	// USART2, AF7
+	entry(GpioPort::PortA, 0, 7, Signal::Usart2Cts),
+	entry(GpioPort::PortA, 1, 7, Signal::Usart2Rts),
+	entry(GpioPort::PortA, 2, 7, Signal::Usart2Tx),
+	entry(GpioPort::PortA, 3, 7, Signal::Usart2Rx),
+	entry(GpioPort::PortA, 14, 7, Signal::Usart2Tx),
+	entry(GpioPort::PortA, 15, 7, Signal::Usart2Rx),
+	entry(GpioPort::PortB, 3, 7, Signal::Usart2Tx),
+	entry(GpioPort::PortB, 4, 7, Signal::Usart2Rx),
+	entry(GpioPort::PortD, 3, 7, Signal::Usart2Cts),
+	entry(GpioPort::PortD, 4, 7, Signal::Usart2Rts),
+	entry(GpioPort::PortD, 5, 7, Signal::Usart2Tx),
+	entry(GpioPort::PortD, 6, 7, Signal::Usart2Rx),
+
+	// USART3, AF7
+	entry(GpioPort::PortB, 10, 7, Signal::Usart3Tx),
+	entry(GpioPort::PortB, 11, 7, Signal::Usart3Rx),
+	entry(GpioPort::PortB, 13, 7, Signal::Usart3Cts),
+	entry(GpioPort::PortB, 14, 7, Signal::Usart3Rts),
+	entry(GpioPort::PortC, 10, 7, Signal::Usart3Tx),
+	entry(GpioPort::PortC, 11, 7, Signal::Usart3Rx),
+	entry(GpioPort::PortD, 8, 7, Signal::Usart3Tx),
+	entry(GpioPort::PortD, 9, 7, Signal::Usart3Rx),
+	entry(GpioPort::PortD, 11, 7, Signal::Usart3Cts),
+	entry(GpioPort::PortD, 12, 7, Signal::Usart3Rts),
+	entry(GpioPort::PortA, 13, 7, Signal::Usart3Cts),
+
+	// SPI1, AF5
+	entry(GpioPort::PortA, 4, 5, Signal::Spi1Nss),
+	entry(GpioPort::PortA, 5, 5, Signal::Spi1Sck),
+	entry(GpioPort::PortA, 6, 5, Signal::Spi1Miso),
+	entry(GpioPort::PortA, 7, 5, Signal::Spi1Mosi),
+	entry(GpioPort::PortA, 15, 5, Signal::Spi1Nss),
+	entry(GpioPort::PortB, 3, 5, Signal::Spi1Sck),
+	entry(GpioPort::PortB, 4, 5, Signal::Spi1Miso),
+	entry(GpioPort::PortB, 5, 5, Signal::Spi1Mosi),
+
+	// SPI2, AF5
+	entry(GpioPort::PortB, 12, 5, Signal::Spi2Nss),
+	entry(GpioPort::PortB, 13, 5, Signal::Spi2Sck),
+	entry(GpioPort::PortB, 14, 5, Signal::Spi2Miso),
+	entry(GpioPort::PortB, 15, 5, Signal::Spi2Mosi),
+	entry(GpioPort::PortF, 1, 5, Signal::Spi2Sck),
+	entry(GpioPort::PortF, 9, 5, Signal::Spi2Sck),
+	entry(GpioPort::PortF, 10, 5, Signal::Spi2Sck),
+
+	// SPI3, AF6
+	entry(GpioPort::PortA, 4, 6, Signal::Spi3Nss),
+	entry(GpioPort::PortA, 15, 6, Signal::Spi3Nss),
+	entry(GpioPort::PortB, 3, 6, Signal::Spi3Sck),
+	entry(GpioPort::PortB, 4, 6, Signal::Spi3Miso),
+	entry(GpioPort::PortB, 5, 6, Signal::Spi3Mosi),
+	entry(GpioPort::PortC, 10, 6, Signal::Spi3Sck),
+	entry(GpioPort::PortC, 11, 6, Signal::Spi3Miso),
+	entry(GpioPort::PortC, 12, 6, Signal::Spi3Mosi),
+
+	// I2C1, AF4
+	entry(GpioPort::PortA, 14, 4, Signal::I2c1Sda),
+	entry(GpioPort::PortA, 15, 4, Signal::I2c1Scl),
+	entry(GpioPort::PortB, 6, 4, Signal::I2c1Scl),
+	entry(GpioPort::PortB, 7, 4, Signal::I2c1Sda),
+	entry(GpioPort::PortB, 8, 4, Signal::I2c1Scl),
+	entry(GpioPort::PortB, 9, 4, Signal::I2c1Sda),
+
+	// I2C2, AF4
+	entry(GpioPort::PortA, 9, 4, Signal::I2c2Scl),
+	entry(GpioPort::PortA, 10, 4, Signal::I2c2Sda),
+	entry(GpioPort::PortF, 0, 4, Signal::I2c2Sda),
+	entry(GpioPort::PortF, 1, 4, Signal::I2c2Scl),
+	entry(GpioPort::PortF, 6, 4, Signal::I2c2Scl)
+];
+
+// Ports A-F, the only ones bonded out on the VC package
+const PORT_COUNT: usize = 6;
+const PIN_COUNT: usize = 16;
+
+type OwnerTable = [[Option<Signal>; PIN_COUNT]; PORT_COUNT];
+
+// The debug pins come out of reset in AF0
+static PIN_OWNERS: Mutex<Cell<OwnerTable>> = Mutex::new(Cell::new(initial_owners()));
+
+//==============================================================================
+// Public Functions
+//==============================================================================
+// True if `af` on the pin carries `signal`
+pub fn is_valid(port: GpioPort, pin: u8, af: u8, signal: Signal) -> bool {
+	ALT_FUNC_TABLE.iter().any(|e| e.port == port && e.pin == pin && e.af == af && e.signal == signal)
+}
+
+// Reverse lookup, an AF number carries at most one listed signal per pin
+#[allow(dead_code)]
+pub fn get_signal(port: GpioPort, pin: u8, af: u8) -> Option<Signal> {
+	ALT_FUNC_TABLE.iter()
+		.find(|e| e.port == port && e.pin == pin && e.af == af)
+		.map(|e| e.signal)
+}
+
+pub fn get_owner(port: GpioPort, pin: u8) -> Option<Signal> {
+	let (port, pin) = index(port, pin)?;
+	free(|cs| PIN_OWNERS.borrow(cs).get()[port][pin])
+}
+
+// Where a signal is currently routed
+#[allow(dead_code)]
+pub fn find_signal(signal: Signal) -> Option<(GpioPort, u8)> {
+	let owners = free(|cs| PIN_OWNERS.borrow(cs).get());
+
+	owners.iter().enumerate().find_map(|(port, pins)| {
+		pins.iter()
+			.position(|owner| *owner == Some(signal))
+			.map(|pin| (PORTS[port], pin as u8))
+	})
+}
+
+// Records `signal` on the pin. Fails if the AF does not carry the signal
+// there, if another signal holds the pin, or if the signal is already
+// routed to a different pin.
+#[allow(dead_code)]
+pub fn claim(port: GpioPort, pin: u8, af: u8, signal: Signal) -> Result<(), PinmuxError> {
+	record(port, pin, af, signal, false)
+}
+
+// As claim(), but whatever held the pin is dropped. For the holder of the
+// pin moving it from one function to another.
+pub fn reassign(port: GpioPort, pin: u8, af: u8, signal: Signal) -> Result<(), PinmuxError> {
+	record(port, pin, af, signal, true)
+}
+
+// Confirms the pin carries `signal`, used by drivers taking pins
+pub fn check(port: GpioPort, pin: u8, signal: Signal) -> Result<(), PinmuxError> {
+	match get_owner(port, pin) {
+		Some(owner) if owner == signal => Ok(()),
+		Some(owner) => Err(PinmuxError::PinClaimed { port, pin, owner, claimant: signal }),
+		None => Err(PinmuxError::NotClaimed { port, pin, signal }),
+	}
+}
+
+pub fn release(port: GpioPort, pin: u8) {
+	if let Some((port, pin)) = index(port, pin) {
+		free(|cs| {
+			let mut owners = PIN_OWNERS.borrow(cs).get();
+			owners[port][pin] = None;
+			PIN_OWNERS.borrow(cs).set(owners);
+		});
+	}
+}
+
+//==============================================================================
+// Private Functions
+//==============================================================================
+const PORTS: [GpioPort; PORT_COUNT] = [
+	GpioPort::PortA,
+	GpioPort::PortB,
+	GpioPort::PortC,
+	GpioPort::PortD,
+	GpioPort::PortE,
+	GpioPort::PortF
+];
+
+fn record(port: GpioPort, pin: u8, af: u8, signal: Signal, replace: bool) -> Result<(), PinmuxError> {
+	let (port_index, pin_index) = match index(port, pin) {
+		Some(index) if is_valid(port, pin, af, signal) => index,
+		_ => return Err(PinmuxError::InvalidAltFunc { port, pin, af, signal })
+	};
+
+	free(|cs| {
+		let mut owners = PIN_OWNERS.borrow(cs).get();
+
+		if let Some(owner) = owners[port_index][pin_index] {
+			if owner != signal && !replace {
+				return Err(PinmuxError::PinClaimed { port, pin, owner, claimant: signal });
+			}
+		}
+
+		for (other_port, pins) in owners.iter().enumerate() {
+			for (other_pin, owner) in pins.iter().enumerate() {
+				if *owner == Some(signal) && (other_port, other_pin) != (port_index, pin_index) {
+					return Err(PinmuxError::SignalClaimed {
+						signal,
+						owner: (PORTS[other_port], other_pin as u8),
+						claimant: (port, pin)
+					});
+				}
+			}
+		}
+
+		owners[port_index][pin_index] = Some(signal);
+		PIN_OWNERS.borrow(cs).set(owners);
+		Ok(())
+	})
+}
+
+fn index(port: GpioPort, pin: u8) -> Option<(usize, usize)> {
+	let port = port as usize;
+	if port < PORT_COUNT && (pin as usize) < PIN_COUNT { Some((port, pin as usize)) } else { None }
+}
+
+const fn initial_owners() -> OwnerTable {
+	let mut owners = [[None; PIN_COUNT]; PORT_COUNT];
+	owners[0][13] = Some(Signal::Swdio);
+	owners[0][14] = Some(Signal::Swclk);
+	owners[0][15] = Some(Signal::Jtdi);
+	owners[1][3] = Some(Signal::Jtdo);
+	owners[1][4] = Some(Signal::Njtrst);
+	owners
+}
@@ -0,0 +1,468 @@
+//==============================================================================
+// Notes
+//==============================================================================
+// mcu/power.rs
+
+/*
+ * The main loop calls idle() once per pass. Each module votes for the deepest
+ * mode it can tolerate and the shallowest vote wins, capped by
+ * config::LOW_POWER_MODE:
+ *   Sleep   - core clock stopped, peripherals running, any interrupt wakes
+ *   Stop    - all clocks stopped, SYSCLK comes back on the HSI, only EXTI
+ *             lines wake (pins, RTC wakeup, USART wake-from-stop)
+ *   Standby - 1.8V domain off, wakes through a reset from WKUP pins or the RTC
+ * The modules that run work in the background (UART, SPI, DMA, log) vote
+ * Sleep when they queue it and give their vote back from their task handler
+ * once idle. They start at Sleep until that first pass, every other client
+ * starts at Standby and only holds the core up if it votes.
+ *
+ * The PVD compares VDD against the selected level and reports dips on EXTI
+ * line 16. The F303 has no separate brown-out flag, a BOR sets PORRSTF like
+ * a cold start. The PVD handler leaves a marker in a backup register so the
+ * next boot can tell the two apart, provided VBAT kept the backup domain up.
+ */
+
+//==============================================================================
+// Crates and Mods
+//==============================================================================
+use core::cell::{Cell, RefCell};
+use core::ops::DerefMut;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::interrupt::{free, Mutex};
+use cortex_m::peripheral::{NVIC, SCB};
+use stm32f3::stm32f303;
+use stm32f3::stm32f303::interrupt;
+
+use crate::config;
+use crate::mcu::clocks;
+use crate::mcu::exti;
+use crate::mcu::rtc;
+use crate::mcu::uart;
+
+//==============================================================================
+// Enums, Structs, and Types
+//==============================================================================
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum PowerMode {
+	Run,
+	Sleep,
+	Stop,
+	Standby
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum PowerClient {
+	Adc = 0,
+	Clocks,
+	Gpio,
+	I2c,
+	Rtc,
+	Spi,
+	Timer,
+	Uart,
+	Dma,
+	Log,
+	Drivers
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum WakeupPin {
+	Wkup1,	// PA0
+	Wkup2,	// PC13
+	Wkup3	// PE6
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum WakeupSource {
+	ExtiLine(u8),
+	RtcWakeup,
+	Usart1,
+	Usart2,
+	Usart3
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StandbyWakeup {
+	WakeupPin,
+	RtcWakeup,
+	Reset
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PowerError {
+	CallbackTableFull,
+	Uart(uart::UartError)
+}
+
+// VDD thresholds, the rising threshold is ~100mV above
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum PvdLevel {
+	Pvd2V18 = 0,
+	Pvd2V28 = 1,
+	Pvd2V38 = 2,
+	Pvd2V48 = 3,
+	Pvd2V58 = 4,
+	Pvd2V68 = 5,
+	Pvd2V78 = 6,
+	Pvd2V88 = 7
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PvdEvent {
+	SupplyLow,
+	SupplyRestored
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResetCause {
+	PowerOn,
+	Brownout,
+	Pin,
+	Software,
+	IndependentWatchdog,
+	WindowWatchdog,
+	LowPower,
+	OptionByteLoad,
+	Unknown
+}
+
+// Runs in the PVD interrupt: keep it short, the supply may be going away
+pub type PvdCallback = fn(PvdEvent);
+
+//==============================================================================
+// Variables
+//==============================================================================
+const CLIENT_COUNT: usize = 11;
+const MAX_PVD_CALLBACKS: usize = 4;
+
+// Backup register holding the brown-out marker
+const BROWNOUT_REGISTER: usize = 0;
+const BROWNOUT_MARKER: u32 = 0xB0B0_0001;
+
+// RCC_CSR reset flags
+const LPWRRSTF: u32 = 1 << 31;
+const WWDGRSTF: u32 = 1 << 30;
+const IWDGRSTF: u32 = 1 << 29;
+const SFTRSTF: u32 = 1 << 28;
+const PORRSTF: u32 = 1 << 27;
+const PINRSTF: u32 = 1 << 26;
+const OBLRSTF: u32 = 1 << 25;
+
+static PWR_HANDLE: Mutex<RefCell<Option<stm32f303::PWR>>> =
+	Mutex::new(RefCell::new(None));
+
+static SCB_HANDLE: Mutex<RefCell<Option<SCB>>> =
+	Mutex::new(RefCell::new(None));
+
+static MODE_LIMITS: Mutex<Cell<[PowerMode; CLIENT_COUNT]>> =
+	Mutex::new(Cell::new(INITIAL_LIMITS));
+const INITIAL_LIMITS: [PowerMode; CLIENT_COUNT] = initial_limits();
+
+static STANDBY_WAKEUP: Mutex<Cell<Option<StandbyWakeup>>> =
+	Mutex::new(Cell::new(None));
+
+// WFE instead of WFI, so EXTI lines in event mode also wake the core
+static WAIT_FOR_EVENT: AtomicBool = AtomicBool::new(false);
+
+static RESET_CAUSE: Mutex<Cell<ResetCause>> =
+	Mutex::new(Cell::new(ResetCause::Unknown));
+
+static PVD_CALLBACKS: Mutex<RefCell<[Option<PvdCallback>; MAX_PVD_CALLBACKS]>> =
+	Mutex::new(RefCell::new([None; MAX_PVD_CALLBACKS]));
+
+//==============================================================================
+// Public Functions
+//==============================================================================
+pub fn init(
+	pwr: stm32f303::PWR,
+	scb: SCB) {
+
+	clocks::set_apb1_peripheral_clock_enable(clocks::Apb1Peripherals::PWR, true);
+
+	// The RTC and LSE live in the backup domain, keep it writable
+	pwr.cr.modify(|_, w| w.dbp().set_bit());
+
+	// Work out why we are running before the flags are cleared
+	let wakeup = if pwr.csr.read().sbf().bit_is_set() {
+		if rtc::get_wakeup_flag() {
+			Some(StandbyWakeup::RtcWakeup)
+		}
+		else if pwr.csr.read().wuf().bit_is_set() {
+			Some(StandbyWakeup::WakeupPin)
+		}
+		else {
+			Some(StandbyWakeup::Reset)
+		}
+	}
+	else { None };
+
+	pwr.cr.modify(|_, w| w.csbf().set_bit().cwuf().set_bit());
+	rtc::clear_wakeup_flag();
+
+	let brownout = rtc::get_backup_register(BROWNOUT_REGISTER) == Ok(BROWNOUT_MARKER);
+	let _ = rtc::set_backup_register(BROWNOUT_REGISTER, 0);
+	let reset_cause = decode_reset_cause(clocks::take_reset_flags(), brownout);
+
+	free(|cs| {
+		STANDBY_WAKEUP.borrow(cs).set(wakeup);
+		RESET_CAUSE.borrow(cs).set(reset_cause);
+		PWR_HANDLE.borrow(cs).replace(Some(pwr));
+		SCB_HANDLE.borrow(cs).replace(Some(scb));
+	});
+}
+
+// None after a normal power-up or reset, otherwise what ended the last Standby
+#[allow(dead_code)]
+pub fn get_standby_wakeup() -> Option<StandbyWakeup> {
+	free(|cs| STANDBY_WAKEUP.borrow(cs).get())
+}
+
+#[allow(dead_code)]
+pub fn get_reset_cause() -> ResetCause {
+	free(|cs| RESET_CAUSE.borrow(cs).get())
+}
+
+#[allow(dead_code)]
+pub fn set_pvd(level: PvdLevel, enable: bool) {
+	free(|cs| if let Some(pwr) = PWR_HANDLE.borrow(cs).borrow().as_ref() {
+		pwr.cr.modify(|_, w| unsafe { w.pls().bits(level as u8) }.pvde().bit(enable));
+	});
+
+	// Both edges: falling VDD raises PVDO, recovery clears it
+	exti::set_trigger(exti::LINE_PVD, exti::Trigger::Both);
+	exti::clear_pending(exti::LINE_PVD);
+	exti::set_interrupt_enable(exti::LINE_PVD, enable);
+
+	if enable {
+		unsafe { NVIC::unmask(stm32f303::Interrupt::PVD) };
+	}
+	else {
+		NVIC::mask(stm32f303::Interrupt::PVD);
+	}
+}
+
+// True while VDD is below the PVD level
+#[allow(dead_code)]
+pub fn get_supply_low() -> bool {
+	free(|cs| if let Some(pwr) = PWR_HANDLE.borrow(cs).borrow().as_ref() {
+		pwr.csr.read().pvdo().bit_is_set()
+	} else { false })
+}
+
+#[allow(dead_code)]
+pub fn register_pvd_callback(callback: PvdCallback) -> Result<(), PowerError> {
+	free(|cs| {
+		let mut callbacks = PVD_CALLBACKS.borrow(cs).borrow_mut();
+		match callbacks.iter_mut().find(|c| c.is_none()) {
+			Some(slot) => {
+				*slot = Some(callback);
+				Ok(())
+			},
+			None => Err(PowerError::CallbackTableFull)
+		}
+	})
+}
+
+// Deepest mode the client can currently tolerate, Run keeps the core awake
+#[allow(dead_code)]
+pub fn set_mode_limit(client: PowerClient, mode: PowerMode) {
+	free(|cs| {
+		let mut limits = MODE_LIMITS.borrow(cs).get();
+		limits[client as usize] = mode;
+		MODE_LIMITS.borrow(cs).set(limits);
+	});
+}
+
+#[allow(dead_code)]
+pub fn get_mode() -> PowerMode {
+	deepest(&free(|cs| MODE_LIMITS.borrow(cs).get()), config::LOW_POWER_MODE)
+}
+
+#[allow(dead_code)]
+pub fn set_wakeup_pin(pin: WakeupPin, enable: bool) {
+	free(|cs| if let Some(pwr) = PWR_HANDLE.borrow(cs).borrow().as_ref() {
+		pwr.csr.modify(|_, w| match pin {
+			WakeupPin::Wkup1 => w.ewup1().bit(enable),
+			WakeupPin::Wkup2 => w.ewup2().bit(enable),
+			WakeupPin::Wkup3 => w.ewup3().bit(enable),
+		});
+	});
+}
+
+// Only unmasks the wakeup path, the source itself (RTC period, GPIO line
+// trigger) still has to be configured by its owner
+#[allow(dead_code)]
+pub fn set_wakeup_source(source: WakeupSource, enable: bool) -> Result<(), PowerError> {
+	match source {
+		WakeupSource::ExtiLine(line) => exti::set_interrupt_enable(line, enable),
+		WakeupSource::RtcWakeup => {
+			if !enable {
+				rtc::disable_wakeup_timer();
+			}
+			exti::set_interrupt_enable(exti::LINE_RTC_WAKEUP, enable);
+		},
+		WakeupSource::Usart1 => uart::set_wakeup_from_stop(uart::Usart::Usart1, enable).map_err(PowerError::Uart)?,
+		WakeupSource::Usart2 => uart::set_wakeup_from_stop(uart::Usart::Usart2, enable).map_err(PowerError::Uart)?,
+		WakeupSource::Usart3 => uart::set_wakeup_from_stop(uart::Usart::Usart3, enable).map_err(PowerError::Uart)?,
+	}
+	Ok(())
+}
+
+#[allow(dead_code)]
+pub fn set_backup_domain_access(enable: bool) {
+	free(|cs| if let Some(pwr) = PWR_HANDLE.borrow(cs).borrow().as_ref() {
+		pwr.cr.modify(|_, w| w.dbp().bit(enable));
+	});
+}
+
+// Sleep and Stop wait with WFE, woken by EXTI events as well as interrupts.
+// SEVONPEND makes a pending interrupt an event even with PRIMASK set.
+#[allow(dead_code)]
+pub fn set_wait_for_event(enable: bool) {
+	free(|cs| if let Some(ref mut scb) = SCB_HANDLE.borrow(cs).borrow_mut().deref_mut() {
+		if enable { scb.set_sevonpend() } else { scb.clear_sevonpend() }
+	});
+	WAIT_FOR_EVENT.store(enable, Ordering::Relaxed);
+}
+
+// Called from the main loop once every task handler has run
+pub fn idle() {
+	// WFI still wakes on a pending interrupt with PRIMASK set, the handler
+	// runs once the critical section ends, so nothing slips in between the
+	// vote and going to sleep
+	let mode = free(|cs| {
+		let mode = get_mode();
+		if mode == PowerMode::Run {
+			return mode;
+		}
+
+		if let (Some(pwr), Some(ref mut scb)) = (
+			PWR_HANDLE.borrow(cs).borrow().as_ref(),
+			SCB_HANDLE.borrow(cs).borrow_mut().deref_mut()) {
+
+			match mode {
+				PowerMode::Run => {},
+				PowerMode::Sleep => {
+					scb.clear_sleepdeep();
+					wait();
+				},
+				PowerMode::Stop => {
+					pwr.cr.modify(|_, w| w.pdds().stop_mode().lpds().set_bit());
+					scb.set_sleepdeep();
+					wait();
+					scb.clear_sleepdeep();
+				},
+				PowerMode::Standby => {
+					pwr.cr.modify(|_, w| w.pdds().standby_mode().cwuf().set_bit());
+					scb.set_sleepdeep();
+					cortex_m::asm::wfi();
+				},
+			}
+		}
+		mode
+	});
+
+	if mode == PowerMode::Stop {
+		// Best effort, a failed HSE restart is already handled as a clock fault
+		let _ = clocks::restore_clock_config();
+	}
+}
+
+//==============================================================================
+// Private Functions
+//==============================================================================
+const fn initial_limits() -> [PowerMode; CLIENT_COUNT] {
+	let mut limits = [PowerMode::Standby; CLIENT_COUNT];
+	limits[PowerClient::Uart as usize] = PowerMode::Sleep;
+	limits[PowerClient::Spi as usize] = PowerMode::Sleep;
+	limits[PowerClient::Dma as usize] = PowerMode::Sleep;
+	limits[PowerClient::Log as usize] = PowerMode::Sleep;
+	limits
+}
+
+// The shallowest vote, no deeper than `cap`
+fn deepest(limits: &[PowerMode], cap: PowerMode) -> PowerMode {
+	limits.iter().fold(cap, |mode, &limit| if limit < mode { limit } else { mode })
+}
+
+fn wait() {
+	if WAIT_FOR_EVENT.load(Ordering::Relaxed) {
+		cortex_m::asm::wfe();
+	}
+	else {
+		cortex_m::asm::wfi();
+	}
+}
+
+// A pin reset accompanies every other cause, so it is checked last
+fn decode_reset_cause(flags: u32, brownout: bool) -> ResetCause {
+	if flags & LPWRRSTF != 0 { ResetCause::LowPower }
+	else if flags & WWDGRSTF != 0 { ResetCause::WindowWatchdog }
+	else if flags & IWDGRSTF != 0 { ResetCause::IndependentWatchdog }
+	else if flags & SFTRSTF != 0 { ResetCause::Software }
+	else if flags & PORRSTF != 0 {
+		if brownout { ResetCause::Brownout } else { ResetCause::PowerOn }
+	}
+	else if flags & OBLRSTF != 0 { ResetCause::OptionByteLoad }
+	else if flags & PINRSTF != 0 { ResetCause::Pin }
+	else { ResetCause::Unknown }
+}
+
+//==============================================================================
+// Interrupt Handler
+//==============================================================================
+#[interrupt]
+fn PVD() {
+	exti::clear_pending(exti::LINE_PVD);
+
+	let event = if get_supply_low() { PvdEvent::SupplyLow } else { PvdEvent::SupplyRestored };
+
+	// Marker first, in case the rail collapses during the callbacks
+	let marker = if event == PvdEvent::SupplyLow { BROWNOUT_MARKER } else { 0 };
+	let _ = rtc::set_backup_register(BROWNOUT_REGISTER, marker);
+
+	let callbacks = free(|cs| *PVD_CALLBACKS.borrow(cs).borrow());
+	for callback in callbacks.iter().flatten() {
+		callback(event);
+	}
+}
+
+
+//==============================================================================
+// Task Handler
+//==============================================================================
+pub fn task_handler() {
+
+}
+
+//==============================================================================
+// Tests
+//==============================================================================
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn stop_is_reached_once_the_busy_modules_are_idle() {
+		let mut limits = INITIAL_LIMITS;
+		assert_eq!(deepest(&limits, PowerMode::Stop), PowerMode::Sleep);
+
+		for client in [PowerClient::Uart, PowerClient::Spi, PowerClient::Dma, PowerClient::Log] {
+			limits[client as usize] = PowerMode::Standby;
+		}
+		assert_eq!(deepest(&limits, PowerMode::Stop), PowerMode::Stop);
+		assert_eq!(deepest(&limits, PowerMode::Standby), PowerMode::Standby);
+
+		limits[PowerClient::Spi as usize] = PowerMode::Sleep;
+		assert_eq!(deepest(&limits, PowerMode::Stop), PowerMode::Sleep);
+	}
+}
@@ -9,12 +9,24 @@
 use core::cell::RefCell;
 // use core::ops::DerefMut;
 use cortex_m::interrupt::{free, Mutex};
+use cortex_m::peripheral::NVIC;
 use stm32f3::stm32f303;
+use stm32f3::stm32f303::interrupt;
+
+use crate::mcu::clocks;
+use crate::mcu::exti;
 
 //==============================================================================
 // Enums, Structs, and Types
 //==============================================================================
-
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RtcError {
+	WakeupPeriodTooShort,
+	WakeupPeriodTooLong,
+	InvalidBackupRegister,
+	Timeout
+}
 
 //==============================================================================
 // Variables
@@ -22,6 +34,11 @@ use stm32f3::stm32f303;
 static RTC_HANDLE: Mutex<RefCell<Option<stm32f303::RTC>>> = 
 	Mutex::new(RefCell::new(None));
 
+// The wakeup timer runs from RTCCLK/16
+const WAKEUP_CLOCK_DIV: u32 = 16;
+const WRITE_TIMEOUT: u32 = 10_000;
+const BACKUP_REGISTERS: usize = 16;
+
 //==============================================================================
 // Public Functions
 //==============================================================================
@@ -31,9 +48,117 @@ pub fn init(
 	free(|cs| RTC_HANDLE.borrow(cs).replace(Some(rtc)));
 }
 
+// The backup registers keep their value through resets and Standby for as
+// long as VBAT holds up. Writes need the backup domain unlocked.
+#[allow(dead_code)]
+pub fn get_backup_register(index: usize) -> Result<u32, RtcError> {
+	if index >= BACKUP_REGISTERS {
+		return Err(RtcError::InvalidBackupRegister);
+	}
+
+	Ok(free(|cs| if let Some(rtc) = RTC_HANDLE.borrow(cs).borrow().as_ref() {
+		rtc.bkpr[index].read().bits()
+	} else { 0 }))
+}
+
+#[allow(dead_code)]
+pub fn set_backup_register(index: usize, value: u32) -> Result<(), RtcError> {
+	if index >= BACKUP_REGISTERS {
+		return Err(RtcError::InvalidBackupRegister);
+	}
+
+	free(|cs| if let Some(rtc) = RTC_HANDLE.borrow(cs).borrow().as_ref() {
+		rtc.bkpr[index].write(|w| w.bkp().bits(value));
+	});
+
+	Ok(())
+}
+
+// Periodic wakeup on EXTI line 20, also able to bring the core out of Stop
+// and Standby. The longest period is ~32s with the LSE.
+#[allow(dead_code)]
+pub fn set_wakeup_timer(period_ms: u32) -> Result<(), RtcError> {
+	let ticks = clocks::get_clocks().lclk() as u64 / WAKEUP_CLOCK_DIV as u64 * period_ms as u64 / 1000;
+	if ticks == 0 {
+		return Err(RtcError::WakeupPeriodTooShort);
+	}
+	if ticks > 0x1_0000 {
+		return Err(RtcError::WakeupPeriodTooLong);
+	}
+
+	free(|cs| if let Some(rtc) = RTC_HANDLE.borrow(cs).borrow().as_ref() {
+		unlock(rtc);
+
+		rtc.cr.modify(|_, w| w.wute().clear_bit().wutie().clear_bit());
+		if !(0..WRITE_TIMEOUT).any(|_| rtc.isr.read().wutwf().bit_is_set()) {
+			lock(rtc);
+			return Err(RtcError::Timeout);
+		}
+
+		rtc.wutr.write(|w| w.wut().bits((ticks - 1) as u16));
+		rtc.cr.modify(|_, w| unsafe { w.wucksel().bits(0b000) });
+		rtc.isr.modify(|_, w| w.wutf().clear_bit());
+		rtc.cr.modify(|_, w| w.wute().set_bit().wutie().set_bit());
+
+		lock(rtc);
+		Ok(())
+	} else { Ok(()) })?;
+
+	exti::set_trigger(exti::LINE_RTC_WAKEUP, exti::Trigger::Rising);
+	exti::set_interrupt_enable(exti::LINE_RTC_WAKEUP, true);
+	unsafe { NVIC::unmask(stm32f303::Interrupt::RTC_WKUP) };
+
+	Ok(())
+}
+
+#[allow(dead_code)]
+pub fn disable_wakeup_timer() {
+	NVIC::mask(stm32f303::Interrupt::RTC_WKUP);
+	exti::set_interrupt_enable(exti::LINE_RTC_WAKEUP, false);
+
+	free(|cs| if let Some(rtc) = RTC_HANDLE.borrow(cs).borrow().as_ref() {
+		unlock(rtc);
+		rtc.cr.modify(|_, w| w.wute().clear_bit().wutie().clear_bit());
+		lock(rtc);
+	});
+}
+
+// WUTF lives in the backup domain, so after a Standby wakeup it still tells
+// the RTC apart from the wakeup pins
+#[allow(dead_code)]
+pub fn get_wakeup_flag() -> bool {
+	free(|cs| if let Some(rtc) = RTC_HANDLE.borrow(cs).borrow().as_ref() {
+		rtc.isr.read().wutf().bit_is_set()
+	} else { false })
+}
+
+#[allow(dead_code)]
+pub fn clear_wakeup_flag() {
+	free(|cs| if let Some(rtc) = RTC_HANDLE.borrow(cs).borrow().as_ref() {
+		rtc.isr.modify(|_, w| w.wutf().clear_bit());
+	});
+	exti::clear_pending(exti::LINE_RTC_WAKEUP);
+}
+
 //==============================================================================
 // Private Functions
 //==============================================================================
+fn unlock(rtc: &stm32f303::RTC) {
+	rtc.wpr.write(|w| w.key().bits(0xCA));
+	rtc.wpr.write(|w| w.key().bits(0x53));
+}
+
+fn lock(rtc: &stm32f303::RTC) {
+	rtc.wpr.write(|w| w.key().bits(0xFF));
+}
+
+//==============================================================================
+// Interrupt Handler
+//==============================================================================
+#[interrupt]
+fn RTC_WKUP() {
+	clear_wakeup_flag();
+}
 
 
 //==============================================================================
@@ -3,17 +3,401 @@
 //==============================================================================
 // mcu/spi.rs
 
+/*
+ * Master only with software NSS, chip selects are plain GPIO outputs owned by
+ * whoever talks to the device. configure() sets the mode, bit order and a
+ * frame size of 4 to 16 bits, with SCK as fast as the SPI's PCLK allows
+ * without going over the requested frequency.
+ *
+ * Frames of up to 8 bits are u8 words, wider ones u16. DR is accessed at the
+ * word's width, so the FIFO does not pack two frames per access, and the RX
+ * FIFO threshold follows: RXNE at a byte for u8 words, a halfword for u16.
+ *
+ * The blocking calls poll the flags a frame at a time. The _irq calls run
+ * from the SPI interrupt, one frame in flight, and hand the buffer back
+ * through the callback. The blocking calls are refused while one runs.
+ *
+ * Devices sharing a bus each get an SpiDevice, which owns the chip select and
+ * holds the device's SpiConfig. A transaction locks the bus, puts the
+ * device's settings back if the bus was last set up differently, on another
+ * PCLK, or lost its setup to a mode fault, and drives CS low around the
+ * transfers with the device's setup and hold delays.
+ * While the bus is locked every other call on it gets Busy, so a transfer
+ * started from an interrupt can't cut into the transaction. E.g. reading the
+ * L3GD20's WHO_AM_I, in a function returning an Option:
+ *
+ *     let cs = pins.pe3.into_output(gpio::PinState::PinHigh).ok()?;
+ *     let mut gyro = SpiDevice::new(Spi::Spi1, cs, SpiConfig::new(SpiMode::Mode3, 10_000_000)).ok()?;
+ *     let mut frame = [0x8Fu8, 0x00];
+ *     gyro.transaction(|bus| bus.transfer(&mut frame)).ok()?;
+ *     let who_am_i = frame[1];
+ */
+
 //==============================================================================
 // Crates and Mods
 //==============================================================================
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 // use core::ops::DerefMut;
-use cortex_m::interrupt::{free, Mutex};
+use cortex_m::interrupt::{free, CriticalSection, Mutex};
+use cortex_m::peripheral::NVIC;
 use stm32f3::stm32f303;
+use stm32f3::stm32f303::interrupt;
+use stm32f3::stm32f303::spi1::RegisterBlock;
+
+use crate::mcu::clocks;
+use crate::mcu::gpio;
+use crate::mcu::pinmux::{self, PinmuxError, Signal};
+use crate::mcu::power::{self, PowerClient, PowerMode};
+use crate::mcu::timer;
 
 //==============================================================================
 // Enums, Structs, and Types
 //==============================================================================
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum Spi {
+	Spi1,
+	Spi2,
+	Spi3,
+	Spi4
+}
+
+// CPOL is the high bit, CPHA the low one
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpiMode {
+	Mode0 = 0,
+	Mode1 = 1,
+	Mode2 = 2,
+	Mode3 = 3
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpiBitOrder {
+	MsbFirst,
+	LsbFirst
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpiError {
+	ModeFault,
+	Overrun,
+	Timeout,
+	InvalidDataSize,
+	WordSize,
+	Busy,
+	InvalidFrequency,
+	NotConfigured
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpiConfig {
+	pub mode: SpiMode,
+	pub bit_order: SpiBitOrder,
+	pub data_size: u8,
+	pub max_freq: u32
+}
+
+// 8 bit frames, MSB first
+#[allow(dead_code)]
+impl SpiConfig {
+	pub fn new(mode: SpiMode, max_freq: u32) -> Self {
+		SpiConfig { mode, bit_order: SpiBitOrder::MsbFirst, data_size: 8, max_freq }
+	}
+
+	pub fn bit_order(mut self, bit_order: SpiBitOrder) -> Self {
+		self.bit_order = bit_order;
+		self
+	}
+
+	pub fn data_size(mut self, data_size: u8) -> Self {
+		self.data_size = data_size;
+		self
+	}
+}
+
+// A frame as it goes through DR, u8 up to 8 bit frames and u16 above
+pub trait SpiWord: Copy {
+	const WIDE: bool;
+	// Clocked out by read()
+	const FILL: Self;
+
+	fn write_dr(regs: &RegisterBlock, word: Self);
+	fn read_dr(regs: &RegisterBlock) -> Self;
+}
+
+impl SpiWord for u8 {
+	const WIDE: bool = false;
+	const FILL: Self = 0xFF;
+
+	fn write_dr(regs: &RegisterBlock, word: Self) {
+		unsafe { core::ptr::write_volatile(regs.dr.as_ptr() as *mut u8, word) };
+	}
+
+	fn read_dr(regs: &RegisterBlock) -> Self {
+		unsafe { core::ptr::read_volatile(regs.dr.as_ptr() as *const u8) }
+	}
+}
+
+impl SpiWord for u16 {
+	const WIDE: bool = true;
+	const FILL: Self = 0xFFFF;
+
+	fn write_dr(regs: &RegisterBlock, word: Self) {
+		unsafe { core::ptr::write_volatile(regs.dr.as_ptr() as *mut u16, word) };
+	}
+
+	fn read_dr(regs: &RegisterBlock) -> Self {
+		unsafe { core::ptr::read_volatile(regs.dr.as_ptr() as *const u16) }
+	}
+}
+
+// One device on a shared bus, with its chip select and settings. CS delays
+// are in ns, setup from CS low to the first SCK edge, hold from the end of the
+// last frame to CS high.
+#[allow(dead_code)]
+pub struct SpiDevice {
+	spi: Spi,
+	cs: gpio::Pin<gpio::Output>,
+	config: SpiConfig,
+	cs_setup: u32,
+	cs_hold: u32
+}
+
+#[allow(dead_code)]
+impl SpiDevice {
+	// CS is driven high, the bus is only touched by transactions
+	pub fn new(spi: Spi, mut cs: gpio::Pin<gpio::Output>, config: SpiConfig) -> Result<Self, (gpio::Pin<gpio::Output>, SpiError)> {
+		if !(4..=16).contains(&config.data_size) {
+			return Err((cs, SpiError::InvalidDataSize));
+		}
+
+		cs.set_high();
+		Ok(SpiDevice { spi, cs, config, cs_setup: 0, cs_hold: 0 })
+	}
+
+	pub fn cs_delays(mut self, setup_ns: u32, hold_ns: u32) -> Self {
+		self.cs_setup = setup_ns;
+		self.cs_hold = hold_ns;
+		self
+	}
+
+	// Used from the next transaction
+	pub fn set_config(&mut self, config: SpiConfig) -> Result<(), SpiError> {
+		if !(4..=16).contains(&config.data_size) {
+			return Err(SpiError::InvalidDataSize);
+		}
+
+		self.config = config;
+		Ok(())
+	}
+
+	pub fn get_config(&self) -> SpiConfig {
+		self.config
+	}
+
+	pub fn spi(&self) -> Spi {
+		self.spi
+	}
+
+	// Runs `f` with the bus locked and CS low. CS goes high and the lock is
+	// dropped whatever `f` returns. Busy if the bus is locked or an _irq call
+	// has it.
+	pub fn transaction<R>(&mut self, f: impl FnOnce(&mut SpiTransaction) -> Result<R, SpiError>) -> Result<R, SpiError> {
+		lock(self.spi)?;
+
+		// A disabled bus is started here, the clock is held until disable()
+		let pclk = pclk(self.spi);
+		if free(|cs| CONFIGS.borrow(cs).get()[self.spi as usize]) != Some(Applied { config: self.config, pclk }) {
+			let applied = prescaler(pclk, self.config.max_freq).and_then(|_| {
+				if set_enabled(self.spi, true) {
+					let _ = flush(self.spi);
+				} else {
+					set_clock(self.spi, true);
+				}
+				apply(self.spi, &self.config)
+			});
+			if let Err(error) = applied {
+				unlock(self.spi);
+				return Err(error);
+			}
+		}
+
+		self.cs.set_low();
+		timer::delay_ns(self.cs_setup);
+
+		let mut bus = SpiTransaction { spi: self.spi };
+		let result = f(&mut bus);
+		let flushed = flush(self.spi);
+
+		timer::delay_ns(self.cs_hold);
+		self.cs.set_high();
+		unlock(self.spi);
+
+		let value = result?;
+		flushed.map(|_| value)
+	}
+
+	pub fn release(self) -> gpio::Pin<gpio::Output> {
+		self.cs
+	}
+}
+
+// The bus inside SpiDevice::transaction(), same calls as the free functions
+#[allow(dead_code)]
+pub struct SpiTransaction {
+	spi: Spi
+}
+
+#[allow(dead_code)]
+impl SpiTransaction {
+	pub fn transfer_word<W: SpiWord>(&mut self, word: W) -> Result<W, SpiError> {
+		check_width::<W>(self.spi)?;
+		exchange(self.spi, word)
+	}
+
+	pub fn transfer<W: SpiWord>(&mut self, words: &mut [W]) -> Result<(), SpiError> {
+		check_width::<W>(self.spi)?;
+		for word in words.iter_mut() {
+			*word = exchange(self.spi, *word)?;
+		}
+		Ok(())
+	}
+
+	pub fn write<W: SpiWord>(&mut self, words: &[W]) -> Result<(), SpiError> {
+		check_width::<W>(self.spi)?;
+		for word in words {
+			exchange(self.spi, *word)?;
+		}
+		Ok(())
+	}
+
+	pub fn read<W: SpiWord>(&mut self, words: &mut [W]) -> Result<(), SpiError> {
+		check_width::<W>(self.spi)?;
+		for word in words.iter_mut() {
+			*word = exchange(self.spi, W::FILL)?;
+		}
+		Ok(())
+	}
+
+	// Waits for the bus to go idle first, CS stays low
+	pub fn delay_ns(&mut self, ns: u32) -> Result<(), SpiError> {
+		flush(self.spi)?;
+		timer::delay_ns(ns);
+		Ok(())
+	}
+}
+
+// Lent to the _irq calls, the width has to match the frame size
+#[allow(dead_code)]
+pub enum SpiBuffer {
+	Bits8(&'static mut [u8]),
+	Bits16(&'static mut [u16])
+}
+
+// Called from the SPI interrupt with the buffer once the transfer is over
+pub type SpiCallback = fn(Spi, SpiBuffer, Result<(), SpiError>);
+
+// A config as applied, with the PCLK its BR was worked out from
+#[derive(Clone, Copy, PartialEq)]
+struct Applied {
+	config: SpiConfig,
+	pclk: u32
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum IrqKind {
+	Transfer,
+	Write,
+	Read
+}
+
+#[derive(Clone, Copy)]
+struct IrqTransfer {
+	kind: IrqKind,
+	buffer: usize,
+	len: usize,
+	wide: bool,
+	index: usize,
+	callback: SpiCallback
+}
+
+impl IrqTransfer {
+	// The buffer is only lent out again once the transfer is over
+	fn buffer(&self) -> SpiBuffer {
+		unsafe {
+			if self.wide { SpiBuffer::Bits16(core::slice::from_raw_parts_mut(self.buffer as *mut u16, self.len)) }
+			else { SpiBuffer::Bits8(core::slice::from_raw_parts_mut(self.buffer as *mut u8, self.len)) }
+		}
+	}
+
+	fn word(&self, index: usize) -> u16 {
+		if self.kind == IrqKind::Read {
+			return 0xFFFF;
+		}
+		unsafe {
+			if self.wide { *(self.buffer as *const u16).add(index) } else { *(self.buffer as *const u8).add(index) as u16 }
+		}
+	}
+
+	fn store(&self, index: usize, word: u16) {
+		if self.kind == IrqKind::Write {
+			return;
+		}
+		unsafe {
+			if self.wide { *(self.buffer as *mut u16).add(index) = word } else { *(self.buffer as *mut u8).add(index) = word as u8 }
+		}
+	}
+}
+
+#[allow(dead_code)]
+pub struct SpiPinSet<const AF: u8> {
+	pub sck: gpio::Pin<gpio::AltFunc<AF>>,
+	pub miso: gpio::Pin<gpio::AltFunc<AF>>,
+	pub mosi: gpio::Pin<gpio::AltFunc<AF>>
+}
+
+// The pins each SPI needs, SPI3 is the odd one out on AF6
+#[allow(dead_code)]
+pub enum SpiPins {
+	Spi1(SpiPinSet<5>),
+	Spi2(SpiPinSet<5>),
+	Spi3(SpiPinSet<6>),
+	Spi4(SpiPinSet<5>)
+}
+
+impl SpiPins {
+	fn spi(&self) -> Spi {
+		match self {
+			SpiPins::Spi1(_) => Spi::Spi1,
+			SpiPins::Spi2(_) => Spi::Spi2,
+			SpiPins::Spi3(_) => Spi::Spi3,
+			SpiPins::Spi4(_) => Spi::Spi4,
+		}
+	}
+
+	// Each pin has to have been put in its AF as the matching signal
+	fn check(&self) -> Result<(), PinmuxError> {
+		match self {
+			SpiPins::Spi1(set) => set.check([Signal::Spi1Sck, Signal::Spi1Miso, Signal::Spi1Mosi]),
+			SpiPins::Spi2(set) => set.check([Signal::Spi2Sck, Signal::Spi2Miso, Signal::Spi2Mosi]),
+			SpiPins::Spi3(set) => set.check([Signal::Spi3Sck, Signal::Spi3Miso, Signal::Spi3Mosi]),
+			SpiPins::Spi4(set) => set.check([Signal::Spi4Sck, Signal::Spi4Miso, Signal::Spi4Mosi]),
+		}
+	}
+}
+
+impl<const AF: u8> SpiPinSet<AF> {
+	fn check(&self, signals: [Signal; 3]) -> Result<(), PinmuxError> {
+		for (pin, signal) in [&self.sck, &self.miso, &self.mosi].iter().zip(signals.iter()) {
+			pinmux::check(pin.port(), pin.pin(), *signal)?;
+		}
+		Ok(())
+	}
+}
 
 
 //==============================================================================
@@ -28,6 +412,20 @@ static SPI3_HANDLE: Mutex<RefCell<Option<stm32f303::SPI3>>> =
 static SPI4_HANDLE: Mutex<RefCell<Option<stm32f303::SPI4>>> = 
 	Mutex::new(RefCell::new(None));
 
+static SPI_PINS: Mutex<RefCell<[Option<SpiPins>; 4]>> =
+	Mutex::new(RefCell::new([None, None, None, None]));
+
+const SPI_COUNT: usize = 4;
+const SPI_TIMEOUT: u32 = 100_000;
+
+// What each SPI was last set up with, dropped by disable() and by a mode
+// fault, which turns SPE off
+static CONFIGS: Mutex<Cell<[Option<Applied>; SPI_COUNT]>> = Mutex::new(Cell::new([None; SPI_COUNT]));
+// Whether the SPI holds its clock, from the first configure() to disable()
+static ENABLED: Mutex<Cell<[bool; SPI_COUNT]>> = Mutex::new(Cell::new([false; SPI_COUNT]));
+static LOCKS: Mutex<Cell<[bool; SPI_COUNT]>> = Mutex::new(Cell::new([false; SPI_COUNT]));
+static TRANSFERS: Mutex<Cell<[Option<IrqTransfer>; SPI_COUNT]>> = Mutex::new(Cell::new([None; SPI_COUNT]));
+
 //==============================================================================
 // Public Functions
 //==============================================================================
@@ -43,14 +441,448 @@ pub fn init(
 	free(|cs| SPI4_HANDLE.borrow(cs).replace(Some(spi4)));
 }
 
+// Hands the pins to their SPI, returning any it held before. Pins that do
+// not carry the SPI's signals are handed back with the conflict.
+#[allow(dead_code)]
+pub fn set_pins(pins: SpiPins) -> Result<Option<SpiPins>, (SpiPins, PinmuxError)> {
+	if let Err(error) = pins.check() {
+		return Err((pins, error));
+	}
+
+	let spi = pins.spi();
+	Ok(free(|cs| SPI_PINS.borrow(cs).borrow_mut()[spi as usize].replace(pins)))
+}
+
+#[allow(dead_code)]
+pub fn release_pins(spi: Spi) -> Option<SpiPins> {
+	free(|cs| SPI_PINS.borrow(cs).borrow_mut()[spi as usize].take())
+}
+
+// Master at the fastest SCK not above `max_freq`, the prescaler runs from
+// /2 to /256 of the SPI's PCLK, InvalidFrequency if even /256 is too fast.
+// Returns the SCK frequency set. The clock is taken the first time an SPI is
+// configured and held until disable().
+#[allow(dead_code)]
+pub fn configure(spi: Spi, config: &SpiConfig) -> Result<u32, SpiError> {
+	if !(4..=16).contains(&config.data_size) {
+		return Err(SpiError::InvalidDataSize);
+	}
+	prescaler(pclk(spi), config.max_freq)?;
+	if is_busy(spi) || is_locked(spi) {
+		return Err(SpiError::Busy);
+	}
+
+	if !set_enabled(spi, true) {
+		set_clock(spi, true);
+	}
+	apply(spi, config)
+}
+
+// 8 bit frames, MSB first
+#[allow(dead_code)]
+pub fn enable(spi: Spi, mode: SpiMode, max_freq: u32) -> Result<u32, SpiError> {
+	configure(spi, &SpiConfig::new(mode, max_freq))
+}
+
+#[allow(dead_code)]
+pub fn disable(spi: Spi) {
+	if !set_enabled(spi, false) {
+		return;
+	}
+
+	// Let the last frame finish, RM0316 30.5.9
+	let _ = flush(spi);
+	with_spi(spi, |regs| regs.cr1.modify(|_, w| w.spe().clear_bit()));
+	free(|cs| set_config(cs, spi, None));
+	set_clock(spi, false);
+}
+
+// Clocks out one byte and returns the one clocked in with it
+#[allow(dead_code)]
+pub fn transfer_byte(spi: Spi, byte: u8) -> Result<u8, SpiError> {
+	transfer_word(spi, byte)
+}
+
+#[allow(dead_code)]
+pub fn transfer_word<W: SpiWord>(spi: Spi, word: W) -> Result<W, SpiError> {
+	check_word::<W>(spi)?;
+	exchange(spi, word)
+}
+
+// Full duplex, each word is replaced by the one clocked in with it
+#[allow(dead_code)]
+pub fn transfer<W: SpiWord>(spi: Spi, words: &mut [W]) -> Result<(), SpiError> {
+	check_word::<W>(spi)?;
+	for word in words.iter_mut() {
+		*word = exchange(spi, *word)?;
+	}
+	Ok(())
+}
+
+// What comes back is dropped
+#[allow(dead_code)]
+pub fn write<W: SpiWord>(spi: Spi, words: &[W]) -> Result<(), SpiError> {
+	check_word::<W>(spi)?;
+	for word in words {
+		exchange(spi, *word)?;
+	}
+	Ok(())
+}
+
+// Clocks out all ones
+#[allow(dead_code)]
+pub fn read<W: SpiWord>(spi: Spi, words: &mut [W]) -> Result<(), SpiError> {
+	check_word::<W>(spi)?;
+	for word in words.iter_mut() {
+		*word = exchange(spi, W::FILL)?;
+	}
+	Ok(())
+}
+
+#[allow(dead_code)]
+pub fn transfer_irq(spi: Spi, buffer: SpiBuffer, callback: SpiCallback) -> Result<(), (SpiBuffer, SpiError)> {
+	start_irq(spi, IrqKind::Transfer, buffer, callback)
+}
+
+// The buffer is only read
+#[allow(dead_code)]
+pub fn write_irq(spi: Spi, buffer: SpiBuffer, callback: SpiCallback) -> Result<(), (SpiBuffer, SpiError)> {
+	start_irq(spi, IrqKind::Write, buffer, callback)
+}
+
+#[allow(dead_code)]
+pub fn read_irq(spi: Spi, buffer: SpiBuffer, callback: SpiCallback) -> Result<(), (SpiBuffer, SpiError)> {
+	start_irq(spi, IrqKind::Read, buffer, callback)
+}
+
+// True while an SpiDevice transaction has the SPI
+#[allow(dead_code)]
+pub fn is_locked(spi: Spi) -> bool {
+	free(|cs| LOCKS.borrow(cs).get()[spi as usize])
+}
+
+// True while an _irq call has the SPI
+#[allow(dead_code)]
+pub fn is_busy(spi: Spi) -> bool {
+	free(|cs| TRANSFERS.borrow(cs).get()[spi as usize].is_some())
+}
+
+// Stops an _irq call without its callback, a frame may still be on the wire
+#[allow(dead_code)]
+pub fn abort(spi: Spi) -> Option<SpiBuffer> {
+	let transfer = free(|cs| take_transfer(cs, spi))?;
+	with_spi(spi, |regs| regs.cr2.modify(|_, w| w.rxneie().clear_bit().errie().clear_bit()));
+	Some(transfer.buffer())
+}
+
+// Waits for the bus to go idle
+#[allow(dead_code)]
+pub fn flush(spi: Spi) -> Result<(), SpiError> {
+	wait(spi, |regs| regs.sr.read().bsy().bit_is_clear())
+}
+
 //==============================================================================
 // Private Functions
 //==============================================================================
+fn pclk(spi: Spi) -> u32 {
+	let clocks = clocks::get_clocks();
+	match spi {
+		Spi::Spi1 | Spi::Spi4 => clocks.pclk2(),
+		Spi::Spi2 | Spi::Spi3 => clocks.pclk1(),
+	}
+}
+
+// BR for the fastest SCK not above `max_freq`
+fn prescaler(pclk: u32, max_freq: u32) -> Result<u8, SpiError> {
+	(0..8u8).find(|br| pclk >> (br + 1) <= max_freq).ok_or(SpiError::InvalidFrequency)
+}
+
+// Registers only, the clock has to be running already. Nothing is touched if
+// the frequency can't be met.
+fn apply(spi: Spi, config: &SpiConfig) -> Result<u32, SpiError> {
+	let pclk = pclk(spi);
+	let br = prescaler(pclk, config.max_freq)?;
+
+	with_spi(spi, |regs| {
+		regs.cr1.write(|w| w.spe().clear_bit());
+		regs.cr2.write(|w| unsafe { w.ds().bits(config.data_size - 1).frxth().bit(config.data_size <= 8) });
+		regs.cr1.write(|w| w
+			.cpha().bit(config.mode as u8 & 1 != 0)
+			.cpol().bit(config.mode as u8 & 2 != 0)
+			.lsbfirst().bit(config.bit_order == SpiBitOrder::LsbFirst)
+			.mstr().set_bit()
+			.br().bits(br)
+			.ssm().set_bit()
+			.ssi().set_bit()
+			.spe().set_bit()
+		);
+	});
+
+	free(|cs| set_config(cs, spi, Some(Applied { config: *config, pclk })));
+	Ok(pclk >> (br + 1))
+}
 
+fn set_config(cs: &CriticalSection, spi: Spi, config: Option<Applied>) {
+	let mut configs = CONFIGS.borrow(cs).get();
+	configs[spi as usize] = config;
+	CONFIGS.borrow(cs).set(configs);
+}
+
+// Returns whether the SPI was enabled before
+fn set_enabled(spi: Spi, enabled: bool) -> bool {
+	free(|cs| {
+		let mut all = ENABLED.borrow(cs).get();
+		let previous = all[spi as usize];
+		all[spi as usize] = enabled;
+		ENABLED.borrow(cs).set(all);
+		previous
+	})
+}
+
+fn set_clock(spi: Spi, enable: bool) {
+	match spi {
+		Spi::Spi1 => clocks::set_apb2_peripheral_clock_enable(clocks::Apb2Peripherals::SPI1, enable),
+		Spi::Spi2 => clocks::set_apb1_peripheral_clock_enable(clocks::Apb1Peripherals::SPI2, enable),
+		Spi::Spi3 => clocks::set_apb1_peripheral_clock_enable(clocks::Apb1Peripherals::SPI3, enable),
+		Spi::Spi4 => clocks::set_apb2_peripheral_clock_enable(clocks::Apb2Peripherals::SPI4, enable),
+	}
+}
+
+fn check_word<W: SpiWord>(spi: Spi) -> Result<(), SpiError> {
+	if is_busy(spi) || is_locked(spi) {
+		return Err(SpiError::Busy);
+	}
+	check_width::<W>(spi)
+}
+
+fn check_width<W: SpiWord>(spi: Spi) -> Result<(), SpiError> {
+	if W::WIDE != is_wide(spi)? {
+		return Err(SpiError::WordSize);
+	}
+	Ok(())
+}
+
+// NotConfigured until configure(), the SPI isn't clocked before that
+fn is_wide(spi: Spi) -> Result<bool, SpiError> {
+	free(|cs| CONFIGS.borrow(cs).get()[spi as usize])
+		.map(|applied| applied.config.data_size > 8)
+		.ok_or(SpiError::NotConfigured)
+}
+
+// Fails if the bus is already locked or an _irq call has it
+fn lock(spi: Spi) -> Result<(), SpiError> {
+	free(|cs| {
+		let mut locks = LOCKS.borrow(cs).get();
+		if locks[spi as usize] || TRANSFERS.borrow(cs).get()[spi as usize].is_some() {
+			return Err(SpiError::Busy);
+		}
+
+		locks[spi as usize] = true;
+		LOCKS.borrow(cs).set(locks);
+		Ok(())
+	})
+}
+
+fn unlock(spi: Spi) {
+	free(|cs| {
+		let mut locks = LOCKS.borrow(cs).get();
+		locks[spi as usize] = false;
+		LOCKS.borrow(cs).set(locks);
+	});
+}
+
+fn exchange<W: SpiWord>(spi: Spi, word: W) -> Result<W, SpiError> {
+	wait(spi, |regs| regs.sr.read().txe().bit_is_set())?;
+	with_spi(spi, |regs| W::write_dr(regs, word));
+	wait(spi, |regs| regs.sr.read().rxne().bit_is_set())?;
+	with_spi(spi, |regs| W::read_dr(regs)).ok_or(SpiError::Timeout)
+}
+
+fn start_irq(spi: Spi, kind: IrqKind, buffer: SpiBuffer, callback: SpiCallback) -> Result<(), (SpiBuffer, SpiError)> {
+	let (address, len, wide) = match &buffer {
+		SpiBuffer::Bits8(words) => (words.as_ptr() as usize, words.len(), false),
+		SpiBuffer::Bits16(words) => (words.as_ptr() as usize, words.len(), true),
+	};
+	match is_wide(spi) {
+		Ok(configured) if configured == wide => (),
+		Ok(_) => return Err((buffer, SpiError::WordSize)),
+		Err(error) => return Err((buffer, error)),
+	}
+
+	let transfer = IrqTransfer { kind, buffer: address, len, wide, index: 0, callback };
+	let claimed = free(|cs| {
+		let mut all = TRANSFERS.borrow(cs).get();
+		if all[spi as usize].is_some() || LOCKS.borrow(cs).get()[spi as usize] {
+			return false;
+		}
+		all[spi as usize] = Some(transfer);
+		TRANSFERS.borrow(cs).set(all);
+		true
+	});
+	if !claimed {
+		return Err((buffer, SpiError::Busy));
+	}
+	power::set_mode_limit(PowerClient::Spi, PowerMode::Sleep);
+
+	// An empty buffer is done straight away
+	if len == 0 {
+		free(|cs| take_transfer(cs, spi));
+		callback(spi, buffer, Ok(()));
+		return Ok(());
+	}
+
+	with_spi(spi, |regs| {
+		write_frame(regs, wide, transfer.word(0));
+		regs.cr2.modify(|_, w| w.rxneie().set_bit().errie().set_bit());
+	});
+	unsafe { NVIC::unmask(interrupt(spi)) };
+	Ok(())
+}
+
+fn take_transfer(cs: &CriticalSection, spi: Spi) -> Option<IrqTransfer> {
+	let mut all = TRANSFERS.borrow(cs).get();
+	let transfer = all[spi as usize].take();
+	TRANSFERS.borrow(cs).set(all);
+	transfer
+}
+
+fn write_frame(regs: &RegisterBlock, wide: bool, word: u16) {
+	if wide { u16::write_dr(regs, word) } else { u8::write_dr(regs, word as u8) }
+}
+
+fn interrupt(spi: Spi) -> stm32f303::Interrupt {
+	match spi {
+		Spi::Spi1 => stm32f303::Interrupt::SPI1,
+		Spi::Spi2 => stm32f303::Interrupt::SPI2,
+		Spi::Spi3 => stm32f303::Interrupt::SPI3,
+		Spi::Spi4 => stm32f303::Interrupt::SPI4,
+	}
+}
+
+// One frame in flight: each received frame is stored and the next one sent.
+// Returns the finished transfer and its result for the callback.
+fn service(cs: &CriticalSection, spi: Spi, regs: &RegisterBlock) -> Option<(IrqTransfer, Result<(), SpiError>)> {
+	let mut transfer = TRANSFERS.borrow(cs).get()[spi as usize]?;
+	let sr = regs.sr.read();
+
+	let result = if sr.modf().bit_is_set() {
+		regs.cr1.modify(|_, w| w.mstr().set_bit());
+		set_config(cs, spi, None);
+		Some(Err(SpiError::ModeFault))
+	}
+	else if sr.ovr().bit_is_set() {
+		let _ = regs.dr.read();
+		let _ = regs.sr.read();
+		Some(Err(SpiError::Overrun))
+	}
+	else if sr.rxne().bit_is_set() {
+		let word = if transfer.wide { u16::read_dr(regs) } else { u8::read_dr(regs) as u16 };
+		transfer.store(transfer.index, word);
+		transfer.index += 1;
+
+		if transfer.index == transfer.len { Some(Ok(())) } else {
+			write_frame(regs, transfer.wide, transfer.word(transfer.index));
+			None
+		}
+	}
+	else { None };
+
+	match result {
+		Some(result) => {
+			regs.cr2.modify(|_, w| w.rxneie().clear_bit().errie().clear_bit());
+			take_transfer(cs, spi);
+			Some((transfer, result))
+		},
+		None => {
+			let mut all = TRANSFERS.borrow(cs).get();
+			all[spi as usize] = Some(transfer);
+			TRANSFERS.borrow(cs).set(all);
+			None
+		},
+	}
+}
+
+fn handle_interrupt(spi: Spi) {
+	let finished = free(|cs| match spi {
+		Spi::Spi1 => SPI1_HANDLE.borrow(cs).borrow().as_ref().and_then(|regs| service(cs, spi, regs)),
+		Spi::Spi2 => SPI2_HANDLE.borrow(cs).borrow().as_ref().and_then(|regs| service(cs, spi, regs)),
+		Spi::Spi3 => SPI3_HANDLE.borrow(cs).borrow().as_ref().and_then(|regs| service(cs, spi, regs)),
+		Spi::Spi4 => SPI4_HANDLE.borrow(cs).borrow().as_ref().and_then(|regs| service(cs, spi, regs)),
+	});
+
+	if let Some((transfer, result)) = finished {
+		(transfer.callback)(spi, transfer.buffer(), result);
+	}
+}
+
+// Also reports, and clears, a mode fault or overrun seen while waiting
+fn wait(spi: Spi, done: impl Fn(&RegisterBlock) -> bool) -> Result<(), SpiError> {
+	for _ in 0..SPI_TIMEOUT {
+		let status = with_spi(spi, |regs| {
+			let sr = regs.sr.read();
+			if sr.modf().bit_is_set() {
+				// The fault dropped MSTR and SPE, the CR1 write after the SR read
+				// clears it. SPE stays off until the SPI is configured again.
+				regs.cr1.modify(|_, w| w.mstr().set_bit());
+				free(|cs| set_config(cs, spi, None));
+				return Err(SpiError::ModeFault);
+			}
+			if sr.ovr().bit_is_set() {
+				// Cleared by reading DR then SR
+				let _ = regs.dr.read();
+				let _ = regs.sr.read();
+				return Err(SpiError::Overrun);
+			}
+			Ok(done(regs))
+		}).ok_or(SpiError::Timeout)?;
+
+		if status? {
+			return Ok(());
+		}
+	}
+
+	Err(SpiError::Timeout)
+}
+
+// All four share the SPI1 register layout
+fn with_spi<R>(spi: Spi, f: impl FnOnce(&RegisterBlock) -> R) -> Option<R> {
+	free(|cs| match spi {
+		Spi::Spi1 => SPI1_HANDLE.borrow(cs).borrow().as_ref().map(|regs| f(regs)),
+		Spi::Spi2 => SPI2_HANDLE.borrow(cs).borrow().as_ref().map(|regs| f(regs)),
+		Spi::Spi3 => SPI3_HANDLE.borrow(cs).borrow().as_ref().map(|regs| f(regs)),
+		Spi::Spi4 => SPI4_HANDLE.borrow(cs).borrow().as_ref().map(|regs| f(regs)),
+	})
+}
+
+//==============================================================================
+// Interrupt Handlers
+//==============================================================================
+#[interrupt]
+fn SPI1() {
+	handle_interrupt(Spi::Spi1);
+}
+
+#[interrupt]
+fn SPI2() {
+	handle_interrupt(Spi::Spi2);
+}
+
+#[interrupt]
+fn SPI3() {
+	handle_interrupt(Spi::Spi3);
+}
+
+#[interrupt]
+fn SPI4() {
+	handle_interrupt(Spi::Spi4);
+}
 
 //==============================================================================
 // Task Handler
 //==============================================================================
+// Gives the power vote back once no interrupt transfer is in flight
 pub fn task_handler() {
-
+	free(|cs| if TRANSFERS.borrow(cs).get().iter().all(|transfer| transfer.is_none()) {
+		power::set_mode_limit(PowerClient::Spi, PowerMode::Standby);
+	});
 }
\ No newline at end of file
@@ -3,18 +3,40 @@
 //==============================================================================
 // mcu/timer.rs
 
+/*
+ * SysTick runs off HCLK as the 1ms system tick behind get_tick(). It is
+ * reloaded whenever the clock tree changes and stops in Stop mode.
+ *
+ * TIM6 is kept for the busy-wait delays, one-pulse at the full timer clock.
+ * Delays are not reentrant, they are not meant to be called from interrupts.
+ */
+
 //==============================================================================
 // Crates and Mods
 //==============================================================================
 use core::cell::RefCell;
-// use core::ops::DerefMut;
+use core::ops::DerefMut;
+use core::sync::atomic::{AtomicU32, Ordering};
 use cortex_m::interrupt::{free, Mutex};
+use cortex_m::peripheral::SYST;
+use cortex_m::peripheral::syst::SystClkSource;
+use cortex_m_rt::exception;
 use stm32f3::stm32f303;
 
+use crate::mcu::clocks;
+
 //==============================================================================
 // Enums, Structs, and Types
 //==============================================================================
-
+// TIM16 channel 1 input remap (TIM16_OR TI1_RMP)
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum Tim16Input {
+	Gpio = 0,
+	RtcClock = 1,
+	HseDiv32 = 2,
+	Mco = 3
+}
 
 //==============================================================================
 // Variables
@@ -47,10 +69,26 @@ static TIM16_HANDLE: Mutex<RefCell<Option<stm32f303::TIM16>>> =
 static TIM17_HANDLE: Mutex<RefCell<Option<stm32f303::TIM17>>> = 
 	Mutex::new(RefCell::new(None));
 
+static SYST_HANDLE: Mutex<RefCell<Option<SYST>>> =
+	Mutex::new(RefCell::new(None));
+
+// Milliseconds since boot, wraps after ~49 days
+static TICK: AtomicU32 = AtomicU32::new(0);
+
+const TICK_FREQ: u32 = 1_000;
+
+// ARR = 0 holds the counter, so a one-pulse run is at least 2 ticks
+const DELAY_MIN_TICKS: u64 = 2;
+const DELAY_MAX_TICKS: u64 = 0x1_0000;
+
+// Every capture is taken with the /8 input prescaler
+const CAPTURE_PRESCALER: u32 = 8;
+const CAPTURE_TIMEOUT: u32 = 1_000_000;
 
 //==============================================================================
 // Public Functions
 //==============================================================================
+#[allow(clippy::too_many_arguments)]
 pub fn init(
 	tim1: stm32f303::TIM1,
 	tim8: stm32f303::TIM8,
@@ -61,7 +99,8 @@ pub fn init(
 	tim4: stm32f303::TIM4,
 	tim15: stm32f303::TIM15,
 	tim16: stm32f303::TIM16,
-	tim17: stm32f303::TIM17) {
+	tim17: stm32f303::TIM17,
+	syst: SYST) {
 	
 	free(|cs| TIM1_HANDLE.borrow(cs).replace(Some(tim1)));
 	free(|cs| TIM8_HANDLE.borrow(cs).replace(Some(tim8)));
@@ -73,12 +112,152 @@ pub fn init(
 	free(|cs| TIM15_HANDLE.borrow(cs).replace(Some(tim15)));
 	free(|cs| TIM16_HANDLE.borrow(cs).replace(Some(tim16)));
 	free(|cs| TIM17_HANDLE.borrow(cs).replace(Some(tim17)));
+	free(|cs| SYST_HANDLE.borrow(cs).replace(Some(syst)));
+
+	clocks::set_apb1_peripheral_clock_enable(clocks::Apb1Peripherals::TIM6, true);
+
+	start_systick(&clocks::get_clocks());
+	// Only fails once the table is full, which init is too early for
+	let _ = clocks::register_clock_change_callback(start_systick);
+}
+
+#[allow(dead_code)]
+pub fn get_tick() -> u32 {
+	TICK.load(Ordering::Relaxed)
+}
+
+#[allow(dead_code)]
+pub fn delay_ns(ns: u32) {
+	delay_ticks(ns as u64 * clocks::get_clocks().timclk1() as u64 / 1_000_000_000);
+}
+
+#[allow(dead_code)]
+pub fn delay_us(us: u32) {
+	delay_ticks(us as u64 * clocks::get_clocks().timclk1() as u64 / 1_000_000);
+}
+
+#[allow(dead_code)]
+pub fn delay_ms(ms: u32) {
+	delay_ticks(ms as u64 * clocks::get_clocks().timclk1() as u64 / 1_000);
+}
+
+// Measures `periods` cycles of the selected TIM16 input and returns the number
+// of timer clock ticks they took, or None if the input is not toggling or an
+// interrupt ran long enough to lose a capture. The count is rounded down to
+// whole captures of 8 periods each.
+#[allow(dead_code)]
+pub fn capture_tim16_period(input: Tim16Input, periods: u32) -> Option<u32> {
+	let captures = periods / CAPTURE_PRESCALER;
+	if captures == 0 {
+		return None;
+	}
+
+	clocks::set_apb2_peripheral_clock_enable(clocks::Apb2Peripherals::TIM16, true);
+
+	let started = free(|cs| if let Some(tim) = TIM16_HANDLE.borrow(cs).borrow().as_ref() {
+		tim.cr1.write(|w| w.cen().clear_bit());
+		tim.psc.write(|w| w.psc().bits(0));
+		tim.arr.write(|w| unsafe { w.arr().bits(0xFFFF) });
+		tim.or.write(|w| unsafe { w.bits(input as u32) });
+		tim.ccmr1_input().write(|w| unsafe { w
+			.cc1s().bits(0b01)
+			.ic1psc().bits(0b11)
+		});
+		tim.ccer.write(|w| w.cc1e().set_bit());
+		tim.egr.write(|w| w.ug().set_bit());
+		tim.sr.write(|w| unsafe { w.bits(0) });
+		tim.cr1.write(|w| w.cen().set_bit());
+		true
+	} else { false });
+
+	// The first capture only marks the starting edge
+	let ticks = if started { wait_tim16_capture() } else { None }.and_then(|mut last| {
+		let mut ticks: u32 = 0;
+		for _ in 0..captures {
+			let capture = wait_tim16_capture()?;
+			ticks += capture.wrapping_sub(last) as u32;
+			last = capture;
+		}
+
+		Some(ticks)
+	});
+
+	free(|cs| if let Some(tim) = TIM16_HANDLE.borrow(cs).borrow().as_ref() {
+		tim.cr1.write(|w| w.cen().clear_bit());
+		tim.ccer.write(|w| w.cc1e().clear_bit());
+		tim.or.write(|w| unsafe { w.bits(0) });
+	});
+
+	clocks::set_apb2_peripheral_clock_enable(clocks::Apb2Peripherals::TIM16, false);
+
+	ticks
 }
 
 //==============================================================================
 // Private Functions
 //==============================================================================
+fn start_systick(clocks: &clocks::Clocks) {
+	free(|cs| if let Some(ref mut syst) = SYST_HANDLE.borrow(cs).borrow_mut().deref_mut() {
+		syst.disable_counter();
+		syst.set_clock_source(SystClkSource::Core);
+		syst.set_reload(clocks.hclk() / TICK_FREQ - 1);
+		syst.clear_current();
+		syst.enable_interrupt();
+		syst.enable_counter();
+	});
+}
+
+// Only the setup is in a critical section, interrupts still run during the
+// wait and lengthen it
+fn delay_ticks(mut ticks: u64) {
+	while ticks >= DELAY_MIN_TICKS {
+		let chunk = ticks.min(DELAY_MAX_TICKS);
+
+		free(|cs| if let Some(tim) = TIM6_HANDLE.borrow(cs).borrow().as_ref() {
+			tim.cr1.write(|w| w.opm().set_bit().urs().set_bit());
+			tim.psc.write(|w| w.psc().bits(0));
+			tim.arr.write(|w| w.arr().bits((chunk - 1) as u16));
+			tim.egr.write(|w| w.ug().set_bit());
+			tim.sr.write(|w| w.uif().clear_bit());
+			tim.cr1.modify(|_, w| w.cen().set_bit());
+		});
 
+		while !free(|cs| TIM6_HANDLE.borrow(cs).borrow().as_ref()
+			.is_none_or(|tim| tim.sr.read().uif().bit_is_set())) {}
+
+		ticks -= chunk;
+	}
+}
+
+// Polled outside a critical section, so interrupts can run between captures.
+// None on a timeout, or if an interrupt held things up long enough for a
+// capture to be overwritten.
+fn wait_tim16_capture() -> Option<u16> {
+	for _ in 0..CAPTURE_TIMEOUT {
+		let capture = free(|cs| match TIM16_HANDLE.borrow(cs).borrow().as_ref() {
+			Some(tim) if tim.sr.read().cc1of().bit_is_clear() => Some(
+				if tim.sr.read().cc1if().bit_is_set() { Some(tim.ccr1.read().ccr().bits()) } else { None }
+			),
+			_ => None,
+		});
+
+		match capture {
+			Some(Some(capture)) => return Some(capture),
+			Some(None) => {},
+			None => return None,
+		}
+	}
+
+	None
+}
+
+//==============================================================================
+// Interrupt Handler
+//==============================================================================
+#[exception]
+fn SysTick() {
+	TICK.fetch_add(1, Ordering::Relaxed);
+}
 
 //==============================================================================
 // Task Handler
@@ -3,35 +3,443 @@
 //==============================================================================
 // mcu/uart.rs
 
+/*
+ * A Uart holds the pins and settings for one USART. Used directly it is a
+ * polled port: enable() then read_byte()/write_byte(). Handed to start() it
+ * becomes interrupt driven, with TX and RX ring buffers behind the
+ * non-blocking write() and read(). Receive errors and RX buffer overflows are
+ * collected for take_errors().
+ *
+ * A started USART can also move data with DMA1: start_dma_rx() receives
+ * into a circular buffer and hands over each frame when the line goes idle,
+ * write_dma() sends a whole buffer and calls back when DMA is done with it.
+ * write_dma() waits for the TX ring buffer to drain, and bytes written while
+ * the DMA runs stay queued until it is done. The buffer given to
+ * start_dma_rx() must hold the longest expected frame, a frame that laps the
+ * buffer is lost.
+ *
+ * Hardware flow control follows the pins: RTSE with an RTS pin, CTSE with a
+ * CTS pin. While the RX ring buffer is full the interrupt leaves RDR unread so
+ * RTS drops, read() or discard_rx() picks reception up again. DMA RX always
+ * empties RDR, there RTS only covers the DMA's own latency.
+ *
+ * with_rs485() turns the RTS pin into the RS-485 driver enable (DE) instead.
+ * The USART raises DE ahead of the start bit and holds it after the last stop
+ * bit for the configured times, in sample times: 1/16 of a bit, or 1/8 with
+ * 8x oversampling.
+ *
+ * Several logical users can share one started USART. Each attach() adds an
+ * instance with its own baud rate, RS-485 setting and receive callback, and
+ * select() puts one instance's settings on the wire. The selected instance's
+ * callback gets the received bytes from task_handler(), which then owns the
+ * RX buffer. Handles are checked against a generation count, so a detached
+ * instance's handle is refused rather than reaching its reused slot. To swap
+ * pins, stop() the USART and start() a Uart with the new pins. The selected
+ * instance stays selected and its settings carry over.
+ *
+ * start_auto_baud() has the USART measure the next character and report the
+ * rate, which the port then keeps. The 0x7F and 0x55 sync characters are
+ * dropped, the character measured in start bit or falling edge mode is data.
+ * The result is picked up on the RX interrupt, so auto-baud and DMA RX
+ * exclude each other. set_rx_timeout() closes a frame after a run of silent
+ * bit times, e.g. the 3.5 character gap between Modbus RTU frames. DMA RX
+ * then cuts frames on the timeout instead of on IDLE. Both last until stop().
+ *
+ * LIN master and slave nodes run on a started USART through uart::lin.
+ *
+ * BRR follows the kernel clock, it is recomputed on every clock tree change
+ * for the started USARTs. Below 16 kernel clocks per bit 8x oversampling is
+ * used instead. A rate over kernel clock / 8, or one that needs a divider
+ * past 0xFFFF, is rejected with BaudOutOfRange.
+ */
+
 //==============================================================================
 // Crates and Mods
 //==============================================================================
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 // use core::ops::DerefMut;
-use cortex_m::interrupt::{free, Mutex};
+use cortex_m::interrupt::{free, CriticalSection, Mutex};
+use cortex_m::peripheral::NVIC;
 use stm32f3::stm32f303;
+use stm32f3::stm32f303::interrupt;
 
+use crate::mcu::clocks;
+use crate::mcu::dma;
+use crate::mcu::exti;
 use crate::mcu::gpio;
+use crate::mcu::pinmux::{self, PinmuxError, Signal};
+use crate::mcu::power::{self, PowerClient, PowerMode};
+
+pub mod lin;
 
 //==============================================================================
 // Enums, Structs, and Types
 //==============================================================================
+// Every USART signal is on AF7 on the F303
+pub type UartPin = gpio::Pin<gpio::AltFunc<7>>;
+
 #[allow(dead_code)]
 pub struct Uart {
-	rx_port: gpio::GpioPort,
-	rx_pin: u8,
-	tx_port: gpio::GpioPort,
-	tx_pin: u8,
-	rts_port: gpio::GpioPort,
-	rts_pin: u8,
-	cts_port: gpio::GpioPort,
-	cts_pin: u8,
+	rx: UartPin,
+	tx: UartPin,
+	rts: Option<UartPin>,
+	cts: Option<UartPin>,
 	usart: Usart,
 	baud: u32,
-	next: Option<*mut Uart>
+	rs485: Option<Rs485Config>,
+	clocked: bool
+}
+
+#[allow(dead_code)]
+impl Uart {
+	// Each pin has to have been put in AF7 as the matching signal of `usart`.
+	// On failure the Uart comes back with the error so release() can return
+	// the pins.
+	pub fn new(usart: Usart, baud: u32, rx: UartPin, tx: UartPin, rts: Option<UartPin>, cts: Option<UartPin>) -> Result<Self, (Self, PinmuxError)> {
+		let uart = Uart { rx, tx, rts, cts, usart, baud, rs485: None, clocked: false };
+
+		match uart.check_pins() {
+			Ok(()) => Ok(uart),
+			Err(error) => Err((uart, error)),
+		}
+	}
+
+	// Drives DE on the RTS pin, which is then no longer used for flow control.
+	// Takes effect on the next enable() or start().
+	pub fn with_rs485(mut self, config: Rs485Config) -> Result<Self, (Self, UartError)> {
+		if self.rts.is_none() {
+			return Err((self, UartError::NoDePin));
+		}
+		if config.assertion > DE_TIME_MAX || config.deassertion > DE_TIME_MAX {
+			return Err((self, UartError::InvalidDeTime));
+		}
+
+		self.rs485 = Some(config);
+		Ok(self)
+	}
+
+	// Back to RTS flow control, if there is an RTS pin
+	pub fn without_rs485(mut self) -> Self {
+		self.rs485 = None;
+		self
+	}
+
+	// 8N1, polled, BRR from the USART's current kernel clock
+	pub fn enable(&mut self) -> Result<(), UartError> {
+		self.configure()?;
+		with_usart(self.usart, |uart| uart.cr1.modify(|_, w| w.te().set_bit().re().set_bit().ue().set_bit()));
+		Ok(())
+	}
+
+	// Also gives back the clock enable taken by enable() or start()
+	pub fn disable(&mut self) {
+		with_usart(self.usart, |uart| uart.cr1.modify(|_, w| w.ue().clear_bit()));
+		if self.clocked {
+			set_clock(self.usart, false);
+			self.clocked = false;
+		}
+	}
+
+	// Ok(None) if nothing has arrived. A receive error is reported, and
+	// cleared, ahead of the byte it came with.
+	pub fn read_byte(&mut self) -> Result<Option<u8>, UartError> {
+		with_usart(self.usart, |uart| {
+			let isr = uart.isr.read();
+			let error = if isr.ore().bit_is_set() { Some(UartError::Overrun) }
+				else if isr.fe().bit_is_set() { Some(UartError::Framing) }
+				else if isr.pe().bit_is_set() { Some(UartError::Parity) }
+				else if isr.nf().bit_is_set() { Some(UartError::Noise) }
+				else { None };
+
+			if let Some(error) = error {
+				uart.icr.write(|w| w.orecf().set_bit().fecf().set_bit().pecf().set_bit().ncf().set_bit());
+				return Err(error);
+			}
+
+			Ok(if isr.rxne().bit_is_set() { Some(uart.rdr.read().rdr().bits() as u8) } else { None })
+		}).unwrap_or(Ok(None))
+	}
+
+	// True if read_byte() would report a receive error, without clearing it
+	pub fn has_rx_error(&self) -> bool {
+		with_usart(self.usart, |uart| {
+			let isr = uart.isr.read();
+			isr.ore().bit_is_set() || isr.fe().bit_is_set() || isr.pe().bit_is_set() || isr.nf().bit_is_set()
+		}).unwrap_or(false)
+	}
+
+	// False if the transmit register is still full
+	pub fn write_byte(&mut self, byte: u8) -> bool {
+		with_usart(self.usart, |uart| {
+			if uart.isr.read().txe().bit_is_clear() {
+				return false;
+			}
+
+			uart.tdr.write(|w| w.tdr().bits(byte as u16));
+			true
+		}).unwrap_or(false)
+	}
+
+	// True once the last byte has left the shift register
+	pub fn is_tx_complete(&self) -> bool {
+		with_usart(self.usart, |uart| uart.isr.read().tc().bit_is_set()).unwrap_or(true)
+	}
+
+	// Hands the pins back, in the order given to new(), disabling the USART
+	// first if it was left enabled
+	pub fn release(mut self) -> (UartPin, UartPin, Option<UartPin>, Option<UartPin>) {
+		if self.clocked {
+			self.disable();
+		}
+		(self.rx, self.tx, self.rts, self.cts)
+	}
+
+	// Pins, clock and BRR, with the USART left disabled. The clock is only
+	// taken the first time round, disable() gives it back.
+	fn configure(&mut self) -> Result<(), UartError> {
+		divider(kernel_clock(self.usart), self.baud)?;
+
+		// RX and CTS idle high, a floating line reads as a stream of breaks
+		let _ = self.tx.set_speed(gpio::OutputSpeed::Medium);
+		let _ = self.rx.set_pull(gpio::PinPull::PullUp);
+		if let Some(pin) = &mut self.rts {
+			let _ = pin.set_speed(gpio::OutputSpeed::Medium);
+		}
+		if let Some(pin) = &mut self.cts {
+			let _ = pin.set_pull(gpio::PinPull::PullUp);
+		}
+
+		if !self.clocked {
+			set_clock(self.usart, true);
+			self.clocked = true;
+		}
+
+		with_usart(self.usart, |uart| uart.cr1.write(|w| w.ue().clear_bit()));
+		self.apply()
+	}
+
+	// Flow control, DE and BRR, a running USART is briefly disabled. Nothing
+	// changes if the baud rate is out of range.
+	fn apply(&self) -> Result<(), UartError> {
+		let (brr, over8) = divider(kernel_clock(self.usart), self.baud)?;
+		let rs485 = self.rs485;
+		let rts = self.rts.is_some() && rs485.is_none();
+		let cts = self.cts.is_some();
+		with_usart(self.usart, |uart| {
+			let enabled = uart.cr1.read().ue().bit_is_set();
+			uart.cr1.modify(|_, w| w.ue().clear_bit());
+			uart.cr3.modify(|_, w| w
+				.rtse().bit(rts)
+				.ctse().bit(cts)
+				.dem().bit(rs485.is_some())
+				.dep().bit(rs485.is_some_and(|config| config.polarity == DePolarity::ActiveLow))
+			);
+			// DEAT and DEDT can only be written with UE clear
+			if let Some(config) = rs485 {
+				uart.cr1.modify(|_, w| w.deat().bits(config.assertion).dedt().bits(config.deassertion));
+			}
+			uart.brr.write(|w| w.brr().bits(brr));
+			uart.cr1.modify(|_, w| w.over8().bit(over8));
+			uart.cr1.modify(|_, w| w.ue().bit(enabled));
+		});
+		Ok(())
+	}
+
+	fn check_pins(&self) -> Result<(), PinmuxError> {
+		let [rx, tx, rts, cts] = match self.usart {
+			Usart::Usart1 => [Signal::Usart1Rx, Signal::Usart1Tx, Signal::Usart1Rts, Signal::Usart1Cts],
+			Usart::Usart2 => [Signal::Usart2Rx, Signal::Usart2Tx, Signal::Usart2Rts, Signal::Usart2Cts],
+			Usart::Usart3 => [Signal::Usart3Rx, Signal::Usart3Tx, Signal::Usart3Rts, Signal::Usart3Cts],
+		};
+
+		pinmux::check(self.rx.port(), self.rx.pin(), rx)?;
+		pinmux::check(self.tx.port(), self.tx.pin(), tx)?;
+		if let Some(pin) = &self.rts {
+			pinmux::check(pin.port(), pin.pin(), rts)?;
+		}
+		if let Some(pin) = &self.cts {
+			pinmux::check(pin.port(), pin.pin(), cts)?;
+		}
+		Ok(())
+	}
 }
 
 #[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UartError {
+	Overrun,
+	Framing,
+	Parity,
+	Noise,
+	NotStarted,
+	DmaBusy,
+	InvalidBuffer,
+	NoDePin,
+	InvalidDeTime,
+	InstanceTableFull,
+	StaleHandle,
+	TxBusy,
+	AutoBaudFailed,
+	AutoBaudPending,
+	InvalidTimeout,
+	BaudOutOfRange
+}
+
+// What the first character looks like, RM0316 29.5.6
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AutoBaudMode {
+	StartBit,
+	FallingEdge,
+	Frame7F,
+	Frame55
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DePolarity {
+	ActiveHigh,
+	ActiveLow
+}
+
+// Times are in sample times, up to DE_TIME_MAX
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rs485Config {
+	pub polarity: DePolarity,
+	pub assertion: u8,
+	pub deassertion: u8
+}
+
+// Called from the USART interrupt with each received frame. A frame that
+// wraps past the end of the DMA buffer comes in two parts, otherwise the
+// second part is empty.
+pub type UartFrameCallback = fn(Usart, &[u8], &[u8]);
+
+// Called from the DMA interrupt once write_dma() is done with its buffer, the
+// last bytes may still be in the USART
+pub type UartTxCallback = fn(Usart);
+
+// Called from the USART interrupt with the measured rate
+pub type UartAutoBaudCallback = fn(Usart, Result<u32, UartError>);
+
+// Called from the USART interrupt once the line has been quiet for the
+// receiver timeout
+pub type UartTimeoutCallback = fn(Usart);
+
+// Called from task_handler() with what the selected instance has received
+pub type UartRxCallback = fn(Usart, &[u8]);
+
+// Names one attach() until it is detached
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UartHandle {
+	usart: Usart,
+	slot: u8,
+	generation: u16
+}
+
+#[allow(dead_code)]
+impl UartHandle {
+	pub fn usart(&self) -> Usart {
+		self.usart
+	}
+}
+
+// Sticky record of receive problems since the last take_errors()
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UartErrors {
+	pub overrun: bool,
+	pub framing: bool,
+	pub parity: bool,
+	pub noise: bool,
+	pub buffer_full: bool,
+	pub dma: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Instance {
+	baud: u32,
+	rs485: Option<Rs485Config>,
+	callback: Option<UartRxCallback>,
+	generation: u16
+}
+
+#[derive(Clone, Copy)]
+struct AutoBaud {
+	mode: AutoBaudMode,
+	callback: UartAutoBaudCallback
+}
+
+// Work for the interrupt handler to finish outside the critical section
+#[derive(Default)]
+struct Pending {
+	frame: Option<RxFrame>,
+	auto_baud: Option<(UartAutoBaudCallback, Result<u32, UartError>)>,
+	timeout: Option<UartTimeoutCallback>
+}
+
+#[derive(Clone, Copy)]
+struct DmaRx {
+	buffer: usize,
+	len: usize,
+	read: usize,
+	callback: UartFrameCallback
+}
+
+#[derive(Clone, Copy)]
+struct DmaTx {
+	callback: Option<UartTxCallback>
+}
+
+// A span of the DMA RX buffer, handed over outside the critical section
+struct RxFrame {
+	rx: DmaRx,
+	start: usize,
+	end: usize
+}
+
+struct RingBuffer {
+	data: [u8; BUFFER_SIZE],
+	head: usize,
+	len: usize
+}
+
+impl RingBuffer {
+	const fn new() -> Self {
+		RingBuffer { data: [0; BUFFER_SIZE], head: 0, len: 0 }
+	}
+
+	fn push(&mut self, byte: u8) -> bool {
+		if self.len == BUFFER_SIZE {
+			return false;
+		}
+
+		self.data[(self.head + self.len) % BUFFER_SIZE] = byte;
+		self.len += 1;
+		true
+	}
+
+	fn pop(&mut self) -> Option<u8> {
+		if self.len == 0 {
+			return None;
+		}
+
+		let byte = self.data[self.head];
+		self.head = (self.head + 1) % BUFFER_SIZE;
+		self.len -= 1;
+		Some(byte)
+	}
+
+	fn clear(&mut self) {
+		self.head = 0;
+		self.len = 0;
+	}
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Usart{
 	Usart1,
 	Usart2,
@@ -48,6 +456,49 @@ static UART2_HANDLE: Mutex<RefCell<Option<stm32f303::USART2>>> =
 static UART3_HANDLE: Mutex<RefCell<Option<stm32f303::USART3>>> = 
 	Mutex::new(RefCell::new(None));
 
+const USART_COUNT: usize = 3;
+const BUFFER_SIZE: usize = 256;
+
+// RTO is 24 bits wide, in bit times
+#[allow(dead_code)]
+pub const RX_TIMEOUT_MAX: u32 = 0xFF_FFFF;
+
+// DEAT and DEDT are 5 bits wide
+#[allow(dead_code)]
+pub const DE_TIME_MAX: u8 = 31;
+
+// The Uarts handed to start(), which hold on to their pins
+static PORTS: Mutex<RefCell<[Option<Uart>; USART_COUNT]>> =
+	Mutex::new(RefCell::new([None, None, None]));
+
+static TX_BUFFERS: Mutex<RefCell<[RingBuffer; USART_COUNT]>> =
+	Mutex::new(RefCell::new([RingBuffer::new(), RingBuffer::new(), RingBuffer::new()]));
+static RX_BUFFERS: Mutex<RefCell<[RingBuffer; USART_COUNT]>> =
+	Mutex::new(RefCell::new([RingBuffer::new(), RingBuffer::new(), RingBuffer::new()]));
+
+static UART_ERRORS: Mutex<Cell<[UartErrors; USART_COUNT]>> = Mutex::new(Cell::new([UartErrors {
+	overrun: false,
+	framing: false,
+	parity: false,
+	noise: false,
+	buffer_full: false,
+	dma: false,
+}; USART_COUNT]));
+
+const INSTANCE_COUNT: usize = 4;
+const DISPATCH_CHUNK: usize = 32;
+
+static INSTANCES: Mutex<Cell<[[Option<Instance>; INSTANCE_COUNT]; USART_COUNT]>> =
+	Mutex::new(Cell::new([[None; INSTANCE_COUNT]; USART_COUNT]));
+static SELECTED: Mutex<Cell<[Option<UartHandle>; USART_COUNT]>> = Mutex::new(Cell::new([None; USART_COUNT]));
+static GENERATION: Mutex<Cell<u16>> = Mutex::new(Cell::new(0));
+
+static AUTO_BAUD: Mutex<Cell<[Option<AutoBaud>; USART_COUNT]>> = Mutex::new(Cell::new([None; USART_COUNT]));
+static RX_TIMEOUT: Mutex<Cell<[Option<UartTimeoutCallback>; USART_COUNT]>> = Mutex::new(Cell::new([None; USART_COUNT]));
+
+static DMA_RX: Mutex<Cell<[Option<DmaRx>; USART_COUNT]>> = Mutex::new(Cell::new([None; USART_COUNT]));
+static DMA_TX: Mutex<Cell<[Option<DmaTx>; USART_COUNT]>> = Mutex::new(Cell::new([None; USART_COUNT]));
+
 //==============================================================================
 // Public Functions
 //==============================================================================
@@ -59,16 +510,965 @@ pub fn init(
 	free(|cs| UART1_HANDLE.borrow(cs).replace(Some(uart1)));
 	free(|cs| UART2_HANDLE.borrow(cs).replace(Some(uart2)));
 	free(|cs| UART3_HANDLE.borrow(cs).replace(Some(uart3)));
+
+	// Only fails once the table is full, which init is too early for
+	let _ = clocks::register_clock_change_callback(update_baud);
+}
+
+// Runs the Uart from its interrupt, with both buffers emptied. A Uart already
+// started on the same USART is stopped and handed back. The selected
+// instance's settings replace the Uart's own, unless its RS-485 setting needs
+// an RTS pin the Uart lacks, in which case the instance is deselected. A baud
+// rate out of range hands the Uart back and leaves the running one alone.
+#[allow(dead_code)]
+pub fn start(mut uart: Uart) -> Result<Option<Uart>, (Uart, UartError)> {
+	let usart = uart.usart;
+
+	let selected = free(|cs| {
+		let handle = SELECTED.borrow(cs).get()[usart as usize]?;
+		instance(cs, handle).ok()
+	});
+	let selected = match selected {
+		Some(instance) if instance.rs485.is_none() || uart.rts.is_some() => Some(instance),
+		Some(_) => {
+			free(|cs| set_selected(cs, usart, None));
+			None
+		},
+		None => None,
+	};
+	let baud = selected.map_or(uart.baud, |instance| instance.baud);
+	if let Err(error) = divider(kernel_clock(usart), baud) {
+		return Err((uart, error));
+	}
+
+	if let Some(instance) = selected {
+		uart.baud = instance.baud;
+		uart.rs485 = instance.rs485;
+	}
+
+	let previous = stop(usart);
+	// Only a clock change since the check above can fail this
+	if let Err(error) = uart.configure() {
+		uart.disable();
+		return Err((uart, error));
+	}
+	free(|cs| {
+		TX_BUFFERS.borrow(cs).borrow_mut()[usart as usize].clear();
+		RX_BUFFERS.borrow(cs).borrow_mut()[usart as usize].clear();
+		set_errors(cs, usart, UartErrors::default());
+		PORTS.borrow(cs).borrow_mut()[usart as usize] = Some(uart);
+	});
+
+	with_usart(usart, |uart| {
+		uart.icr.write(|w| w.orecf().set_bit().fecf().set_bit().pecf().set_bit().ncf().set_bit());
+		uart.cr3.modify(|_, w| w.eie().set_bit());
+		uart.cr1.modify(|_, w| w.rxneie().set_bit().peie().set_bit().te().set_bit().re().set_bit().ue().set_bit());
+	});
+	unsafe { NVIC::unmask(interrupt(usart)) };
+	power::set_mode_limit(PowerClient::Uart, PowerMode::Sleep);
+
+	Ok(previous)
+}
+
+// Whatever is still buffered for TX is dropped, flush() first to keep it.
+// Stopping also ends DMA, stop_dma_rx() first to get the RX buffer back.
+#[allow(dead_code)]
+pub fn stop(usart: Usart) -> Option<Uart> {
+	let mut uart = free(|cs| PORTS.borrow(cs).borrow_mut()[usart as usize].take())?;
+
+	let _ = stop_dma_rx(usart);
+	if free(|cs| take_dma_tx(cs, usart)).is_some() {
+		dma::release(dma_channels(usart).0);
+	}
+
+	with_usart(usart, |uart| {
+		uart.cr1.modify(|_, w| w.ue().clear_bit().rxneie().clear_bit().txeie().clear_bit().peie().clear_bit().idleie().clear_bit().rtoie().clear_bit());
+		uart.cr2.modify(|_, w| w.abren().clear_bit().rtoen().clear_bit().linen().clear_bit().lbdie().clear_bit());
+		uart.cr3.modify(|_, w| w.eie().clear_bit().dmar().clear_bit().dmat().clear_bit());
+	});
+	free(|cs| {
+		TX_BUFFERS.borrow(cs).borrow_mut()[usart as usize].clear();
+		set_auto_baud(cs, usart, None);
+		set_rx_timeout_callback(cs, usart, None);
+		lin::clear(cs, usart);
+	});
+	uart.disable();
+
+	Some(uart)
+}
+
+#[allow(dead_code)]
+pub fn is_started(usart: Usart) -> bool {
+	free(|cs| PORTS.borrow(cs).borrow()[usart as usize].is_some())
+}
+
+// Queues as much of `bytes` as fits and returns how much that was
+#[allow(dead_code)]
+pub fn write(usart: Usart, bytes: &[u8]) -> usize {
+	if !is_started(usart) {
+		return 0;
+	}
+
+	let count = free(|cs| {
+		let mut buffers = TX_BUFFERS.borrow(cs).borrow_mut();
+		bytes.iter().take_while(|byte| buffers[usart as usize].push(**byte)).count()
+	});
+
+	if count > 0 {
+		power::set_mode_limit(PowerClient::Uart, PowerMode::Sleep);
+		free(|cs| kick_tx(cs, usart));
+	}
+
+	count
+}
+
+// Takes up to buffer.len() received bytes and returns how many
+#[allow(dead_code)]
+pub fn read(usart: Usart, buffer: &mut [u8]) -> usize {
+	free(|cs| {
+		let count = buffer.iter_mut()
+			.map_while(|slot| RX_BUFFERS.borrow(cs).borrow_mut()[usart as usize].pop().map(|byte| *slot = byte))
+			.count();
+
+		if count > 0 {
+			resume_rx(cs, usart);
+		}
+		count
+	})
+}
+
+#[allow(dead_code)]
+pub fn get_rx_count(usart: Usart) -> usize {
+	free(|cs| RX_BUFFERS.borrow(cs).borrow()[usart as usize].len)
+}
+
+#[allow(dead_code)]
+pub fn get_tx_space(usart: Usart) -> usize {
+	free(|cs| BUFFER_SIZE - TX_BUFFERS.borrow(cs).borrow()[usart as usize].len)
+}
+
+#[allow(dead_code)]
+pub fn discard_rx(usart: Usart) {
+	free(|cs| {
+		RX_BUFFERS.borrow(cs).borrow_mut()[usart as usize].clear();
+		resume_rx(cs, usart);
+	});
+}
+
+// True once the TX buffer is empty and the last stop bit has gone out
+#[allow(dead_code)]
+pub fn is_flushed(usart: Usart) -> bool {
+	let empty = free(|cs| TX_BUFFERS.borrow(cs).borrow()[usart as usize].len == 0);
+	empty && with_usart(usart, |uart| uart.isr.read().tc().bit_is_set()).unwrap_or(true)
+}
+
+// Waits for is_flushed(), the interrupt keeps draining the buffer meanwhile
+#[allow(dead_code)]
+pub fn flush(usart: Usart) {
+	while is_started(usart) && !is_flushed(usart) {}
+}
+
+// Switches reception of a started USART from the RX ring buffer to circular
+// DMA into `buffer`. On failure the buffer is handed back. Refused while an
+// auto-baud measurement is pending.
+#[allow(dead_code)]
+pub fn start_dma_rx(usart: Usart, buffer: &'static mut [u8], callback: UartFrameCallback) -> Result<(), (&'static mut [u8], UartError)> {
+	if !is_started(usart) {
+		return Err((buffer, UartError::NotStarted));
+	}
+	if free(|cs| AUTO_BAUD.borrow(cs).get()[usart as usize].is_some()) {
+		return Err((buffer, UartError::AutoBaudPending));
+	}
+	if buffer.is_empty() || buffer.len() > u16::MAX as usize {
+		return Err((buffer, UartError::InvalidBuffer));
+	}
+
+	let (_, channel) = dma_channels(usart);
+	if dma::claim(channel, dma_event).is_err() {
+		return Err((buffer, UartError::DmaBusy));
+	}
+
+	let rx = DmaRx { buffer: buffer.as_mut_ptr() as usize, len: buffer.len(), read: 0, callback };
+	free(|cs| {
+		let mut all = DMA_RX.borrow(cs).get();
+		all[usart as usize] = Some(rx);
+		DMA_RX.borrow(cs).set(all);
+	});
+
+	let rdr = with_usart(usart, |uart| {
+		uart.cr1.modify(|_, w| w.rxneie().clear_bit());
+		uart.icr.write(|w| w.idlecf().set_bit());
+		uart.cr1.modify(|r, w| w.idleie().bit(r.rtoie().bit_is_clear()));
+		uart.cr3.modify(|_, w| w.dmar().set_bit());
+		uart.rdr.as_ptr() as u32
+	}).unwrap_or(0);
+
+	// The buffer is ours until stop_dma_rx() hands it back
+	let _ = unsafe { dma::start(channel, &dma::DmaTransfer {
+		direction: dma::DmaDirection::PeripheralToMemory,
+		peripheral: rdr,
+		memory: rx.buffer as u32,
+		len: rx.len as u16,
+		circular: true,
+		half_transfer: false,
+	}) };
+
+	Ok(())
+}
+
+// Back to the RX ring buffer, returning the DMA buffer
+#[allow(dead_code)]
+pub fn stop_dma_rx(usart: Usart) -> Option<&'static mut [u8]> {
+	let rx = free(|cs| {
+		let mut all = DMA_RX.borrow(cs).get();
+		let rx = all[usart as usize].take();
+		DMA_RX.borrow(cs).set(all);
+		rx
+	})?;
+
+	dma::release(dma_channels(usart).1);
+	with_usart(usart, |uart| {
+		uart.cr3.modify(|_, w| w.dmar().clear_bit());
+		uart.cr1.modify(|r, w| w.idleie().clear_bit().rxneie().bit(r.ue().bit_is_set()));
+	});
+
+	Some(unsafe { core::slice::from_raw_parts_mut(rx.buffer as *mut u8, rx.len) })
+}
+
+// Sends `bytes` by DMA. TxBusy until the TX ring buffer has drained, and
+// write() only queues while the DMA runs, so the two never share TDR.
+#[allow(dead_code)]
+pub fn write_dma(usart: Usart, bytes: &'static [u8], callback: Option<UartTxCallback>) -> Result<(), UartError> {
+	if !is_started(usart) {
+		return Err(UartError::NotStarted);
+	}
+	if bytes.is_empty() || bytes.len() > u16::MAX as usize {
+		return Err(UartError::InvalidBuffer);
+	}
+
+	let (channel, _) = dma_channels(usart);
+	dma::claim(channel, dma_event).map_err(|_| UartError::DmaBusy)?;
+	let idle = free(|cs| {
+		let txeie = with_usart(usart, |uart| uart.cr1.read().txeie().bit_is_set()).unwrap_or(false);
+		if TX_BUFFERS.borrow(cs).borrow()[usart as usize].len > 0 || txeie {
+			return false;
+		}
+
+		let mut all = DMA_TX.borrow(cs).get();
+		all[usart as usize] = Some(DmaTx { callback });
+		DMA_TX.borrow(cs).set(all);
+		true
+	});
+	if !idle {
+		dma::release(channel);
+		return Err(UartError::TxBusy);
+	}
+	power::set_mode_limit(PowerClient::Uart, PowerMode::Sleep);
+
+	let tdr = with_usart(usart, |uart| {
+		uart.cr3.modify(|_, w| w.dmat().set_bit());
+		uart.tdr.as_ptr() as u32
+	}).unwrap_or(0);
+
+	// 'static and shared, nothing can change the bytes under the DMA
+	let _ = unsafe { dma::start(channel, &dma::DmaTransfer {
+		direction: dma::DmaDirection::MemoryToPeripheral,
+		peripheral: tdr,
+		memory: bytes.as_ptr() as u32,
+		len: bytes.len() as u16,
+		circular: false,
+		half_transfer: false,
+	}) };
+
+	Ok(())
+}
+
+#[allow(dead_code)]
+pub fn is_dma_tx_busy(usart: Usart) -> bool {
+	free(|cs| DMA_TX.borrow(cs).get()[usart as usize].is_some())
+}
+
+#[allow(dead_code)]
+pub fn take_errors(usart: Usart) -> UartErrors {
+	free(|cs| {
+		let errors = UART_ERRORS.borrow(cs).get()[usart as usize];
+		set_errors(cs, usart, UartErrors::default());
+		errors
+	})
+}
+
+// Lets a start bit wake the core from Stop. The kernel clock is moved to the
+// HSI, the only USART clock source still available in Stop on this board.
+// Fails without changing anything if the baud rate can't be kept on the HSI.
+#[allow(dead_code)]
+pub fn set_wakeup_from_stop(usart: Usart, enable: bool) -> Result<(), UartError> {
+	let line = match usart {
+		Usart::Usart1 => exti::LINE_USART1,
+		Usart::Usart2 => exti::LINE_USART2,
+		Usart::Usart3 => exti::LINE_USART3,
+	};
+
+	if enable {
+		if let Some(baud) = get_baud(usart) {
+			divider(clocks::HSI_FREQ, baud)?;
+		}
+		clocks::set_usart_clock_source(usart, clocks::UsartClock::Hsi);
+		if let Some(baud) = get_baud(usart) {
+			set_baud(usart, baud)?;
+		}
+	}
+
+	free(|cs| match usart {
+		Usart::Usart1 => if let Some(uart) = UART1_HANDLE.borrow(cs).borrow().as_ref() {
+			set_wakeup(uart, enable);
+		},
+		Usart::Usart2 => if let Some(uart) = UART2_HANDLE.borrow(cs).borrow().as_ref() {
+			set_wakeup(uart, enable);
+		},
+		Usart::Usart3 => if let Some(uart) = UART3_HANDLE.borrow(cs).borrow().as_ref() {
+			set_wakeup(uart, enable);
+		},
+	});
+
+	exti::set_interrupt_enable(line, enable);
+	if enable {
+		unsafe { NVIC::unmask(interrupt(usart)) };
+	}
+	Ok(())
+}
+
+// Adds a logical user of `usart`. Nothing changes on the wire until select().
+#[allow(dead_code)]
+pub fn attach(usart: Usart, baud: u32, callback: Option<UartRxCallback>) -> Result<UartHandle, UartError> {
+	divider(kernel_clock(usart), baud)?;
+
+	free(|cs| {
+		let mut all = INSTANCES.borrow(cs).get();
+		let slot = all[usart as usize].iter().position(|instance| instance.is_none())
+			.ok_or(UartError::InstanceTableFull)?;
+
+		let generation = GENERATION.borrow(cs).get().wrapping_add(1);
+		GENERATION.borrow(cs).set(generation);
+
+		all[usart as usize][slot] = Some(Instance { baud, rs485: None, callback, generation });
+		INSTANCES.borrow(cs).set(all);
+		Ok(UartHandle { usart, slot: slot as u8, generation })
+	})
+}
+
+// A selected instance is deselected, the USART keeps running with its settings
+#[allow(dead_code)]
+pub fn detach(handle: UartHandle) -> Result<(), UartError> {
+	free(|cs| {
+		instance(cs, handle)?;
+
+		let mut all = INSTANCES.borrow(cs).get();
+		all[handle.usart as usize][handle.slot as usize] = None;
+		INSTANCES.borrow(cs).set(all);
+
+		if SELECTED.borrow(cs).get()[handle.usart as usize] == Some(handle) {
+			set_selected(cs, handle.usart, None);
+		}
+		Ok(())
+	})
+}
+
+// Puts the instance's settings on the started USART. TX has to be flushed
+// first, a byte arriving during the switch may be lost.
+#[allow(dead_code)]
+pub fn select(handle: UartHandle) -> Result<(), UartError> {
+	let instance = free(|cs| instance(cs, handle))?;
+	apply_instance(handle.usart, &instance)?;
+	free(|cs| set_selected(cs, handle.usart, Some(handle)));
+	Ok(())
+}
+
+#[allow(dead_code)]
+pub fn get_selected(usart: Usart) -> Option<UartHandle> {
+	free(|cs| SELECTED.borrow(cs).get()[usart as usize])
+}
+
+// Applied straight away if the instance is selected
+#[allow(dead_code)]
+pub fn set_instance_baud(handle: UartHandle, baud: u32) -> Result<(), UartError> {
+	divider(kernel_clock(handle.usart), baud)?;
+
+	update_instance(handle, |instance| {
+		instance.baud = baud;
+		Ok(())
+	})
+}
+
+// Applied straight away if the instance is selected
+#[allow(dead_code)]
+pub fn set_instance_rs485(handle: UartHandle, rs485: Option<Rs485Config>) -> Result<(), UartError> {
+	update_instance(handle, |instance| {
+		if rs485.is_some_and(|config| config.assertion > DE_TIME_MAX || config.deassertion > DE_TIME_MAX) {
+			return Err(UartError::InvalidDeTime);
+		}
+		instance.rs485 = rs485;
+		Ok(())
+	})
+}
+
+// Measures the next character on a started USART. The callback gets the
+// rate, which also replaces the port's baud rate. DmaBusy while DMA RX runs,
+// the measurement is only seen from the RX interrupt.
+#[allow(dead_code)]
+pub fn start_auto_baud(usart: Usart, mode: AutoBaudMode, callback: UartAutoBaudCallback) -> Result<(), UartError> {
+	if !is_started(usart) {
+		return Err(UartError::NotStarted);
+	}
+	if free(|cs| DMA_RX.borrow(cs).get()[usart as usize].is_some()) {
+		return Err(UartError::DmaBusy);
+	}
+	if free(|cs| AUTO_BAUD.borrow(cs).get()[usart as usize].is_some()) {
+		return Err(UartError::AutoBaudPending);
+	}
+
+	free(|cs| set_auto_baud(cs, usart, Some(AutoBaud { mode, callback })));
+	with_usart(usart, |uart| {
+		let enabled = uart.cr1.read().ue().bit_is_set();
+		uart.cr1.modify(|_, w| w.ue().clear_bit());
+		uart.cr2.modify(|_, w| {
+			let w = w.abren().set_bit();
+			match mode {
+				AutoBaudMode::StartBit => w.abrmod().start(),
+				AutoBaudMode::FallingEdge => w.abrmod().edge(),
+				AutoBaudMode::Frame7F => w.abrmod().frame7f(),
+				AutoBaudMode::Frame55 => w.abrmod().frame55(),
+			}
+		});
+		uart.cr1.modify(|_, w| w.ue().bit(enabled));
+		// Also clears ABRF and ABRE from an earlier measurement
+		uart.rqr.write(|w| w.abrrq().request());
+	});
+
+	Ok(())
+}
+
+// BRR is left as measured so far
+#[allow(dead_code)]
+pub fn cancel_auto_baud(usart: Usart) {
+	free(|cs| set_auto_baud(cs, usart, None));
+	with_usart(usart, |uart| {
+		let enabled = uart.cr1.read().ue().bit_is_set();
+		uart.cr1.modify(|_, w| w.ue().clear_bit());
+		uart.cr2.modify(|_, w| w.abren().clear_bit());
+		uart.cr1.modify(|_, w| w.ue().bit(enabled));
+	});
+}
+
+// Counts from the end of each received character. Modbus RTU's 3.5 character
+// gap is 39 bit times with 11 bit characters.
+#[allow(dead_code)]
+pub fn set_rx_timeout(usart: Usart, bit_times: u32, callback: Option<UartTimeoutCallback>) -> Result<(), UartError> {
+	if !is_started(usart) {
+		return Err(UartError::NotStarted);
+	}
+	if bit_times == 0 || bit_times > RX_TIMEOUT_MAX {
+		return Err(UartError::InvalidTimeout);
+	}
+
+	free(|cs| set_rx_timeout_callback(cs, usart, callback));
+	with_usart(usart, |uart| {
+		let enabled = uart.cr1.read().ue().bit_is_set();
+		uart.cr1.modify(|_, w| w.ue().clear_bit());
+		uart.rtor.modify(|_, w| w.rto().bits(bit_times));
+		uart.cr2.modify(|_, w| w.rtoen().set_bit());
+		uart.icr.write(|w| w.rtocf().set_bit());
+		uart.cr1.modify(|_, w| w.idleie().clear_bit().rtoie().set_bit().ue().bit(enabled));
+	});
+
+	Ok(())
+}
+
+#[allow(dead_code)]
+pub fn clear_rx_timeout(usart: Usart) {
+	let dma_rx = free(|cs| {
+		set_rx_timeout_callback(cs, usart, None);
+		DMA_RX.borrow(cs).get()[usart as usize].is_some()
+	});
+
+	with_usart(usart, |uart| {
+		let enabled = uart.cr1.read().ue().bit_is_set();
+		uart.cr1.modify(|_, w| w.ue().clear_bit().rtoie().clear_bit());
+		uart.cr2.modify(|_, w| w.rtoen().clear_bit());
+		uart.icr.write(|w| w.idlecf().set_bit());
+		uart.cr1.modify(|_, w| w.idleie().bit(dma_rx).ue().bit(enabled));
+	});
 }
 
 //==============================================================================
 // Private Functions
 //==============================================================================
+// BRR can only be written with UE clear, RM0316 29.8.4
+fn set_clock(usart: Usart, enable: bool) {
+	match usart {
+		Usart::Usart1 => clocks::set_apb2_peripheral_clock_enable(clocks::Apb2Peripherals::USART1, enable),
+		Usart::Usart2 => clocks::set_apb1_peripheral_clock_enable(clocks::Apb1Peripherals::USART2, enable),
+		Usart::Usart3 => clocks::set_apb1_peripheral_clock_enable(clocks::Apb1Peripherals::USART3, enable),
+	}
+}
+
+fn kernel_clock(usart: Usart) -> u32 {
+	let clocks = clocks::get_clocks();
+	match usart {
+		Usart::Usart1 => clocks.usart1clk(),
+		Usart::Usart2 => clocks.usart2clk(),
+		Usart::Usart3 => clocks.usart3clk(),
+	}
+}
+
+// BRR and OVER8 for `baud`. USARTDIV has to be 16 to 0xFFFF in either
+// oversampling mode, RM0316 29.5.4.
+fn divider(kernel_clock: u32, baud: u32) -> Result<(u16, bool), UartError> {
+	if baud == 0 {
+		return Err(UartError::BaudOutOfRange);
+	}
+
+	// With OVER8 the low nibble of USARTDIV is shifted down one bit
+	let div = (kernel_clock as u64 + baud as u64 / 2) / baud as u64;
+	let (brr, over8) = if div >= 16 { (div, false) } else {
+		let div = (2 * kernel_clock as u64 + baud as u64 / 2) / baud as u64;
+		if div < 16 {
+			return Err(UartError::BaudOutOfRange);
+		}
+		((div & !0xF) | ((div & 0xF) >> 1), true)
+	};
+
+	if brr > u16::MAX as u64 {
+		return Err(UartError::BaudOutOfRange);
+	}
+	Ok((brr as u16, over8))
+}
+
+fn set_baud(usart: Usart, baud: u32) -> Result<(), UartError> {
+	let (brr, over8) = divider(kernel_clock(usart), baud)?;
+
+	with_usart(usart, |uart| {
+		let enabled = uart.cr1.read().ue().bit_is_set();
+		uart.cr1.modify(|_, w| w.ue().clear_bit());
+		uart.brr.write(|w| w.brr().bits(brr));
+		uart.cr1.modify(|_, w| w.over8().bit(over8));
+		uart.cr1.modify(|_, w| w.ue().bit(enabled));
+	});
+	Ok(())
+}
+
+// The rate BRR gives, undoing the OVER8 shift of the low nibble
+fn measured_baud(usart: Usart, uart: &stm32f303::usart1::RegisterBlock) -> u32 {
+	let brr = uart.brr.read().brr().bits() as u32;
+	if uart.cr1.read().over8().bit_is_set() {
+		2 * kernel_clock(usart) / ((brr & !0xF) | ((brr & 0x7) << 1)).max(1)
+	}
+	else {
+		kernel_clock(usart) / brr.max(1)
+	}
+}
+
+fn get_baud(usart: Usart) -> Option<u32> {
+	free(|cs| PORTS.borrow(cs).borrow()[usart as usize].as_ref().map(|uart| uart.baud))
+}
+
+fn update_baud(_clocks: &clocks::Clocks) {
+	for usart in [Usart::Usart1, Usart::Usart2, Usart::Usart3] {
+		// A rate the new clock can't make keeps the old BRR
+		if let Some(baud) = get_baud(usart) {
+			let _ = set_baud(usart, baud);
+		}
+	}
+}
+
+// The instance a handle names, as long as it has not been detached since
+fn instance(cs: &CriticalSection, handle: UartHandle) -> Result<Instance, UartError> {
+	INSTANCES.borrow(cs).get()[handle.usart as usize][handle.slot as usize]
+		.filter(|instance| instance.generation == handle.generation)
+		.ok_or(UartError::StaleHandle)
+}
+
+fn set_selected(cs: &CriticalSection, usart: Usart, handle: Option<UartHandle>) {
+	let mut selected = SELECTED.borrow(cs).get();
+	selected[usart as usize] = handle;
+	SELECTED.borrow(cs).set(selected);
+}
+
+// Changes are kept only if a selected instance's settings can be applied
+fn update_instance(handle: UartHandle, f: impl FnOnce(&mut Instance) -> Result<(), UartError>) -> Result<(), UartError> {
+	let (mut updated, selected) = free(|cs| Ok::<_, UartError>((
+		instance(cs, handle)?,
+		SELECTED.borrow(cs).get()[handle.usart as usize] == Some(handle)
+	)))?;
+	f(&mut updated)?;
+
+	if selected {
+		apply_instance(handle.usart, &updated)?;
+	}
+
+	free(|cs| {
+		instance(cs, handle)?;
+		let mut all = INSTANCES.borrow(cs).get();
+		all[handle.usart as usize][handle.slot as usize] = Some(updated);
+		INSTANCES.borrow(cs).set(all);
+		Ok(())
+	})
+}
+
+fn apply_instance(usart: Usart, instance: &Instance) -> Result<(), UartError> {
+	if !is_started(usart) {
+		return Err(UartError::NotStarted);
+	}
+	if !is_flushed(usart) || is_dma_tx_busy(usart) {
+		return Err(UartError::TxBusy);
+	}
+
+	free(|cs| {
+		let mut ports = PORTS.borrow(cs).borrow_mut();
+		let port = ports[usart as usize].as_mut().ok_or(UartError::NotStarted)?;
+		if instance.rs485.is_some() && port.rts.is_none() {
+			return Err(UartError::NoDePin);
+		}
+
+		let (baud, rs485) = (port.baud, port.rs485);
+		port.baud = instance.baud;
+		port.rs485 = instance.rs485;
+		port.apply().inspect_err(|_| {
+			port.baud = baud;
+			port.rs485 = rs485;
+		})
+	})
+}
+
+fn set_auto_baud(cs: &CriticalSection, usart: Usart, auto_baud: Option<AutoBaud>) {
+	let mut all = AUTO_BAUD.borrow(cs).get();
+	all[usart as usize] = auto_baud;
+	AUTO_BAUD.borrow(cs).set(all);
+}
+
+fn set_rx_timeout_callback(cs: &CriticalSection, usart: Usart, callback: Option<UartTimeoutCallback>) {
+	let mut all = RX_TIMEOUT.borrow(cs).get();
+	all[usart as usize] = callback;
+	RX_TIMEOUT.borrow(cs).set(all);
+}
+
+fn set_errors(cs: &CriticalSection, usart: Usart, errors: UartErrors) {
+	let mut all = UART_ERRORS.borrow(cs).get();
+	all[usart as usize] = errors;
+	UART_ERRORS.borrow(cs).set(all);
+}
+
+// (TX, RX), fixed by the DMA1 request mapping
+fn dma_channels(usart: Usart) -> (dma::DmaChannel, dma::DmaChannel) {
+	match usart {
+		Usart::Usart1 => (dma::DmaChannel::Ch4, dma::DmaChannel::Ch5),
+		Usart::Usart2 => (dma::DmaChannel::Ch7, dma::DmaChannel::Ch6),
+		Usart::Usart3 => (dma::DmaChannel::Ch2, dma::DmaChannel::Ch3),
+	}
+}
+
+fn take_dma_tx(cs: &CriticalSection, usart: Usart) -> Option<DmaTx> {
+	let mut all = DMA_TX.borrow(cs).get();
+	let tx = all[usart as usize].take();
+	DMA_TX.borrow(cs).set(all);
+	tx
+}
+
+// Hands queued bytes to the TX interrupt, unless a DMA TX has TDR
+fn kick_tx(cs: &CriticalSection, usart: Usart) {
+	if TX_BUFFERS.borrow(cs).borrow()[usart as usize].len > 0 && DMA_TX.borrow(cs).get()[usart as usize].is_none() {
+		with_usart(usart, |uart| uart.cr1.modify(|_, w| w.txeie().set_bit()));
+	}
+}
+
+// Circular RX only cares about errors, the frames are cut on IDLE
+fn dma_event(channel: dma::DmaChannel, event: dma::DmaEvent) {
+	let usart = match [Usart::Usart1, Usart::Usart2, Usart::Usart3].iter().find(|usart| {
+		let (tx, rx) = dma_channels(**usart);
+		channel == tx || channel == rx
+	}) {
+		Some(usart) => *usart,
+		None => return,
+	};
+	let is_tx = channel == dma_channels(usart).0;
+
+	if event == dma::DmaEvent::TransferError {
+		free(|cs| {
+			let mut errors = UART_ERRORS.borrow(cs).get()[usart as usize];
+			errors.dma = true;
+			set_errors(cs, usart, errors);
+		});
+	}
+
+	if is_tx && event != dma::DmaEvent::HalfTransfer {
+		let tx = free(|cs| take_dma_tx(cs, usart));
+		dma::release(channel);
+		with_usart(usart, |uart| uart.cr3.modify(|_, w| w.dmat().clear_bit()));
+		free(|cs| kick_tx(cs, usart));
+
+		if event == dma::DmaEvent::TransferComplete {
+			if let Some(callback) = tx.and_then(|tx| tx.callback) {
+				callback(usart);
+			}
+		}
+	}
+}
+
+fn deliver(usart: Usart, pending: Pending) {
+	if let Some((callback, result)) = pending.auto_baud {
+		callback(usart, result);
+	}
+	if let Some(callback) = pending.timeout {
+		callback(usart);
+	}
+
+	let frame = match pending.frame {
+		Some(frame) => frame,
+		None => return,
+	};
+
+	// DMA only writes ahead of `end`, the span handed over is settled
+	let buffer = unsafe { core::slice::from_raw_parts(frame.rx.buffer as *const u8, frame.rx.len) };
+	if frame.start < frame.end {
+		(frame.rx.callback)(usart, &buffer[frame.start..frame.end], &[]);
+	}
+	else {
+		(frame.rx.callback)(usart, &buffer[frame.start..], &buffer[..frame.end]);
+	}
+}
+
+fn interrupt(usart: Usart) -> stm32f303::Interrupt {
+	match usart {
+		Usart::Usart1 => stm32f303::Interrupt::USART1_EXTI25,
+		Usart::Usart2 => stm32f303::Interrupt::USART2_EXTI26,
+		Usart::Usart3 => stm32f303::Interrupt::USART3_EXTI28,
+	}
+}
+
+// Errors are latched with the byte they arrived with, which is still kept.
+// An idle line under DMA RX closes the frame that is returned.
+fn service(cs: &CriticalSection, usart: Usart, uart: &stm32f303::usart1::RegisterBlock) -> Pending {
+	let mut pending = Pending::default();
+	if PORTS.borrow(cs).borrow()[usart as usize].is_none() {
+		return pending;
+	}
+
+	let isr = uart.isr.read();
+	// RDR belongs to the DMA under DMA RX, and is left full on purpose while
+	// RTS holds the sender off
+	let rx_irq = uart.cr1.read().rxneie().bit_is_set() && uart.cr3.read().dmar().bit_is_clear();
+	let mut rxne = isr.rxne().bit_is_set() && rx_irq;
+	let mut errors = UART_ERRORS.borrow(cs).get()[usart as usize];
+
+	if isr.ore().bit_is_set() || isr.fe().bit_is_set() || isr.pe().bit_is_set() || isr.nf().bit_is_set() {
+		errors.overrun |= isr.ore().bit_is_set();
+		errors.framing |= isr.fe().bit_is_set();
+		errors.parity |= isr.pe().bit_is_set();
+		errors.noise |= isr.nf().bit_is_set();
+		uart.icr.write(|w| w.orecf().set_bit().fecf().set_bit().pecf().set_bit().ncf().set_bit());
+	}
+
+	if let Some(auto_baud) = AUTO_BAUD.borrow(cs).get()[usart as usize] {
+		if isr.abrf().bit_is_set() || isr.abre().bit_is_set() {
+			set_auto_baud(cs, usart, None);
+
+			let result = if isr.abre().bit_is_set() { Err(UartError::AutoBaudFailed) } else {
+				let baud = measured_baud(usart, uart);
+				if let Some(port) = PORTS.borrow(cs).borrow_mut()[usart as usize].as_mut() {
+					port.baud = baud;
+				}
+				Ok(baud)
+			};
+			pending.auto_baud = Some((auto_baud.callback, result));
+
+			// The sync character only carries the rate
+			if rxne && matches!(auto_baud.mode, AutoBaudMode::Frame7F | AutoBaudMode::Frame55) {
+				let _ = uart.rdr.read();
+				rxne = false;
+			}
+		}
+	}
+
+	if rxne {
+		let mut buffers = RX_BUFFERS.borrow(cs).borrow_mut();
+		if buffers[usart as usize].len == BUFFER_SIZE && uart.cr3.read().rtse().bit_is_set() {
+			// Leave the byte in RDR to hold RTS off until read() makes room
+			uart.cr1.modify(|_, w| w.rxneie().clear_bit());
+		} else if !buffers[usart as usize].push(uart.rdr.read().rdr().bits() as u8) {
+			errors.buffer_full = true;
+		}
+	}
+
+	if isr.txe().bit_is_set() && uart.cr1.read().txeie().bit_is_set() {
+		match TX_BUFFERS.borrow(cs).borrow_mut()[usart as usize].pop() {
+			Some(byte) => uart.tdr.write(|w| w.tdr().bits(byte as u16)),
+			None => uart.cr1.modify(|_, w| w.txeie().clear_bit()),
+		}
+	}
+
+	set_errors(cs, usart, errors);
+
+	if isr.lbdf().bit_is_set() && uart.cr2.read().lbdie().bit_is_set() {
+		uart.icr.write(|w| w.lbdcf().set_bit());
+		lin::on_break(cs, usart);
+	}
+
+	let cr1 = uart.cr1.read();
+	let idle = isr.idle().bit_is_set() && cr1.idleie().bit_is_set();
+	let timeout = isr.rtof().bit_is_set() && cr1.rtoie().bit_is_set();
+	if idle {
+		uart.icr.write(|w| w.idlecf().set_bit());
+	}
+	if timeout {
+		uart.icr.write(|w| w.rtocf().set_bit());
+		pending.timeout = RX_TIMEOUT.borrow(cs).get()[usart as usize];
+	}
+
+	if idle || timeout {
+		let mut all = DMA_RX.borrow(cs).get();
+		if let Some(rx) = all[usart as usize].as_mut() {
+			let end = (rx.len - dma::get_remaining(dma_channels(usart).1) as usize) % rx.len;
+			if end != rx.read {
+				pending.frame = Some(RxFrame { rx: *rx, start: rx.read, end });
+				rx.read = end;
+				DMA_RX.borrow(cs).set(all);
+			}
+		}
+	}
+
+	pending
+}
+
+// Turns RXNEIE back on after service() held RTS off, DMA RX keeps it off
+fn resume_rx(cs: &CriticalSection, usart: Usart) {
+	if PORTS.borrow(cs).borrow()[usart as usize].is_none() {
+		return;
+	}
+
+	with_usart(usart, |uart| {
+		let cr3 = uart.cr3.read();
+		if cr3.rtse().bit_is_set() && cr3.dmar().bit_is_clear() {
+			uart.cr1.modify(|_, w| w.rxneie().set_bit());
+		}
+	});
+}
+
+// All three share the USART1 register layout
+// Stop would cut off a frame on the wire and lose whatever arrives, unless
+// the USART can wake the core from Stop on a start bit
+fn mode_limit(usart: Usart) -> PowerMode {
+	if !is_started(usart) {
+		return PowerMode::Standby;
+	}
+	if !is_flushed(usart) || is_dma_tx_busy(usart) || lin::is_running(usart) {
+		return PowerMode::Sleep;
+	}
+
+	with_usart(usart, |uart| {
+		let cr1 = uart.cr1.read();
+		match (cr1.re().bit_is_set(), cr1.uesm().bit_is_set()) {
+			(false, _) => PowerMode::Standby,
+			(true, true) => PowerMode::Stop,
+			(true, false) => PowerMode::Sleep,
+		}
+	}).unwrap_or(PowerMode::Standby)
+}
+
+fn with_usart<R>(usart: Usart, f: impl FnOnce(&stm32f303::usart1::RegisterBlock) -> R) -> Option<R> {
+	free(|cs| match usart {
+		Usart::Usart1 => UART1_HANDLE.borrow(cs).borrow().as_ref().map(|uart| f(uart)),
+		Usart::Usart2 => UART2_HANDLE.borrow(cs).borrow().as_ref().map(|uart| f(uart)),
+		Usart::Usart3 => UART3_HANDLE.borrow(cs).borrow().as_ref().map(|uart| f(uart)),
+	})
+}
+
+fn set_wakeup(uart: &stm32f303::usart1::RegisterBlock, enable: bool) {
+	uart.cr3.modify(|_, w| w.wus().start().wufie().bit(enable));
+	uart.cr1.modify(|_, w| w.uesm().bit(enable));
+}
+
+fn clear_wakeup(uart: &stm32f303::usart1::RegisterBlock) {
+	if uart.isr.read().wuf().bit_is_set() {
+		uart.icr.write(|w| w.wucf().set_bit());
+	}
+}
+
+//==============================================================================
+// Interrupt Handlers
+//==============================================================================
+#[interrupt]
+fn USART1_EXTI25() {
+	let pending = free(|cs| UART1_HANDLE.borrow(cs).borrow().as_ref().map(|uart| {
+		clear_wakeup(uart);
+		service(cs, Usart::Usart1, uart)
+	}));
+
+	deliver(Usart::Usart1, pending.unwrap_or_default());
+}
+
+#[interrupt]
+fn USART2_EXTI26() {
+	let pending = free(|cs| UART2_HANDLE.borrow(cs).borrow().as_ref().map(|uart| {
+		clear_wakeup(uart);
+		service(cs, Usart::Usart2, uart)
+	}));
+
+	deliver(Usart::Usart2, pending.unwrap_or_default());
+}
+
+#[interrupt]
+fn USART3_EXTI28() {
+	let pending = free(|cs| UART3_HANDLE.borrow(cs).borrow().as_ref().map(|uart| {
+		clear_wakeup(uart);
+		service(cs, Usart::Usart3, uart)
+	}));
+
+	deliver(Usart::Usart3, pending.unwrap_or_default());
+}
 
 
 //==============================================================================
 // Task Handler
 //==============================================================================
+// Goes over every started USART: TX is kicked again if its buffer still holds
+// bytes, and the selected instance's callback, if any, gets what has been
+// received, unless LIN has the USART
 pub fn task_handler() {
+	lin::task_handler();
+
+	let started = free(|cs| {
+		let ports = PORTS.borrow(cs).borrow();
+		[Usart::Usart1, Usart::Usart2, Usart::Usart3].map(|usart| ports[usart as usize].as_ref().map(|_| usart))
+	});
+
+	for usart in started.iter().flatten().copied() {
+		free(|cs| kick_tx(cs, usart));
+
+		if lin::is_running(usart) {
+			continue;
+		}
+
+		let callback = free(|cs| {
+			let handle = SELECTED.borrow(cs).get()[usart as usize]?;
+			instance(cs, handle).ok()?.callback
+		});
+
+		if let Some(callback) = callback {
+			// At most one buffer's worth per pass, a fast sender can't starve the loop
+			let mut chunk = [0; DISPATCH_CHUNK];
+			for _ in 0..BUFFER_SIZE / DISPATCH_CHUNK {
+				let count = read(usart, &mut chunk);
+				if count == 0 {
+					break;
+				}
+				callback(usart, &chunk[..count]);
+			}
+		}
+	}
 
+	free(|_| {
+		let mode = [Usart::Usart1, Usart::Usart2, Usart::Usart3].iter()
+			.map(|&usart| mode_limit(usart))
+			.fold(PowerMode::Standby, |mode, limit| if limit < mode { limit } else { mode });
+		power::set_mode_limit(PowerClient::Uart, mode);
+	});
 }
\ No newline at end of file
@@ -0,0 +1,481 @@
+//==============================================================================
+// Notes
+//==============================================================================
+// mcu/uart/lin.rs
+
+/*
+ * LIN on a started USART, through a transceiver so every byte sent comes back
+ * on RX. The USART sends the breaks and flags each break it detects, the rest
+ * runs from task_handler(). A break restarts the frame parser, which takes
+ * the sync field and protected identifier (PID), then publishes the response
+ * or collects it. Master and slave share the parser, the master only adds a
+ * header at the start of each schedule slot.
+ *
+ * A publishing node checks the echo of its response. A subscribing node
+ * checks the checksum and hands the frame to the receive callback. Errors go
+ * to the same callback. A master subscribing to a frame nobody answered sees
+ * NoResponse when the slot ends. A slot has to cover the whole frame.
+ *
+ * Responses are sent from the task handler, so the main loop has to come round
+ * within the response space. LIN allows frames 40% over their nominal time.
+ * LIN takes over the RX buffer, read() and instance callbacks get nothing
+ * while it runs.
+ */
+
+//==============================================================================
+// Crates and Mods
+//==============================================================================
+use core::cell::{Cell, RefCell};
+use cortex_m::interrupt::{free, CriticalSection, Mutex};
+
+use crate::mcu::timer;
+use super::{Usart, USART_COUNT};
+
+//==============================================================================
+// Enums, Structs, and Types
+//==============================================================================
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinError {
+	NotStarted,
+	InvalidId,
+	InvalidLength,
+	EmptySchedule,
+	Sync,
+	Parity,
+	Checksum,
+	BitError,
+	NoResponse
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinBreakLength {
+	Bits10,
+	Bits11
+}
+
+// Classic covers the data only, enhanced adds the PID
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinChecksum {
+	Classic,
+	Enhanced
+}
+
+// From this node's side: it sends the response, or it listens for it
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinDirection {
+	Publish,
+	Subscribe
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinFrameDef {
+	pub id: u8,
+	pub len: u8,
+	pub checksum: LinChecksum,
+	pub direction: LinDirection
+}
+
+// The master sends the frame's header and waits out `slot_ms` before the next
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinSlot {
+	pub frame: LinFrameDef,
+	pub slot_ms: u32
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinFrame {
+	pub id: u8,
+	pub len: u8,
+	pub data: [u8; MAX_DATA]
+}
+
+#[allow(dead_code)]
+impl LinFrame {
+	pub fn data(&self) -> &[u8] {
+		&self.data[..self.len as usize]
+	}
+}
+
+// Fills in the response of a published frame, the slice has the frame's length
+pub type LinPublishCallback = fn(Usart, u8, &mut [u8]);
+
+// Gets each subscribed frame, and the errors seen on the bus
+pub type LinFrameCallback = fn(Usart, Result<LinFrame, LinError>);
+
+#[derive(Clone, Copy)]
+enum Role {
+	Master {
+		schedule: &'static [LinSlot],
+		index: usize,
+		next_tick: u32,
+		current: Option<LinFrameDef>
+	},
+	Slave {
+		frames: &'static [LinFrameDef]
+	}
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Step {
+	Wait,
+	Sync,
+	Pid,
+	Response {
+		frame: LinFrameDef,
+		pid: u8,
+		count: usize
+	}
+}
+
+#[derive(Clone, Copy)]
+struct LinNode {
+	role: Role,
+	step: Step,
+	publish: LinPublishCallback,
+	receive: LinFrameCallback,
+	sent: [u8; MAX_DATA + 1],
+	received: [u8; MAX_DATA + 1],
+	generation: u16
+}
+
+//==============================================================================
+// Variables
+//==============================================================================
+#[allow(dead_code)]
+pub const MAX_ID: u8 = 0x3F;
+pub const MAX_DATA: usize = 8;
+
+const SYNC: u8 = 0x55;
+// Diagnostic frames keep the classic checksum
+const DIAGNOSTIC_ID: u8 = 0x3C;
+
+static NODES: Mutex<RefCell<[Option<LinNode>; USART_COUNT]>> =
+	Mutex::new(RefCell::new([None, None, None]));
+static BREAKS: Mutex<Cell<[bool; USART_COUNT]>> = Mutex::new(Cell::new([false; USART_COUNT]));
+static NEXT_SCHEDULE: Mutex<Cell<[Option<&'static [LinSlot]>; USART_COUNT]>> = Mutex::new(Cell::new([None; USART_COUNT]));
+static GENERATION: Mutex<Cell<u16>> = Mutex::new(Cell::new(0));
+
+//==============================================================================
+// Public Functions
+//==============================================================================
+// Adds the parity bits, P0 in bit 6 and P1 in bit 7
+#[allow(dead_code)]
+pub fn pid(id: u8) -> Result<u8, LinError> {
+	if id > MAX_ID {
+		return Err(LinError::InvalidId);
+	}
+
+	let bit = |n: u8| (id >> n) & 1;
+	let p0 = bit(0) ^ bit(1) ^ bit(2) ^ bit(4);
+	let p1 = (bit(1) ^ bit(3) ^ bit(4) ^ bit(5)) ^ 1;
+	Ok(id | p0 << 6 | p1 << 7)
+}
+
+// Inverted sum with the carries added back in
+#[allow(dead_code)]
+pub fn checksum(kind: LinChecksum, pid: u8, data: &[u8]) -> u8 {
+	let mut sum: u16 = if kind == LinChecksum::Enhanced && (pid & MAX_ID) < DIAGNOSTIC_ID { pid as u16 } else { 0 };
+	for &byte in data {
+		sum += byte as u16;
+		if sum > 0xFF {
+			sum -= 0xFF;
+		}
+	}
+	!(sum as u8)
+}
+
+// Runs the schedule from the next task_handler() pass, round and round
+#[allow(dead_code)]
+pub fn start_master(usart: Usart, length: LinBreakLength, schedule: &'static [LinSlot], publish: LinPublishCallback, receive: LinFrameCallback) -> Result<(), LinError> {
+	check_schedule(schedule)?;
+	let role = Role::Master { schedule, index: 0, next_tick: timer::get_tick(), current: None };
+	start(usart, length, role, publish, receive)
+}
+
+// Answers the headers of published frames and collects subscribed ones, any
+// other frame is ignored
+#[allow(dead_code)]
+pub fn start_slave(usart: Usart, length: LinBreakLength, frames: &'static [LinFrameDef], publish: LinPublishCallback, receive: LinFrameCallback) -> Result<(), LinError> {
+	for frame in frames {
+		check_frame(frame)?;
+	}
+	start(usart, length, Role::Slave { frames }, publish, receive)
+}
+
+// Takes over from the next slot, starting at the top of the new table
+#[allow(dead_code)]
+pub fn set_schedule(usart: Usart, schedule: &'static [LinSlot]) -> Result<(), LinError> {
+	check_schedule(schedule)?;
+	free(|cs| match NODES.borrow(cs).borrow()[usart as usize].map(|node| node.role) {
+		Some(Role::Master { .. }) => {
+			let mut all = NEXT_SCHEDULE.borrow(cs).get();
+			all[usart as usize] = Some(schedule);
+			NEXT_SCHEDULE.borrow(cs).set(all);
+			Ok(())
+		},
+		_ => Err(LinError::NotStarted),
+	})
+}
+
+// The USART carries on as a plain UART
+#[allow(dead_code)]
+pub fn stop(usart: Usart) {
+	free(|cs| clear(cs, usart));
+	configure(usart, None);
+}
+
+#[allow(dead_code)]
+pub fn is_running(usart: Usart) -> bool {
+	free(|cs| NODES.borrow(cs).borrow()[usart as usize].is_some())
+}
+
+// From the USART interrupt, the bytes before the break belong to the last frame
+pub(super) fn on_break(cs: &CriticalSection, usart: Usart) {
+	let mut breaks = BREAKS.borrow(cs).get();
+	breaks[usart as usize] = true;
+	BREAKS.borrow(cs).set(breaks);
+	super::RX_BUFFERS.borrow(cs).borrow_mut()[usart as usize].clear();
+}
+
+// From uart::stop(), which has already turned LIN off in the USART
+pub(super) fn clear(cs: &CriticalSection, usart: Usart) {
+	NODES.borrow(cs).borrow_mut()[usart as usize] = None;
+	take_break(cs, usart);
+	take_next_schedule(cs, usart);
+}
+
+//==============================================================================
+// Private Functions
+//==============================================================================
+fn start(usart: Usart, length: LinBreakLength, role: Role, publish: LinPublishCallback, receive: LinFrameCallback) -> Result<(), LinError> {
+	if !super::is_started(usart) {
+		return Err(LinError::NotStarted);
+	}
+
+	configure(usart, Some(length));
+	free(|cs| {
+		take_break(cs, usart);
+		take_next_schedule(cs, usart);
+		let generation = GENERATION.borrow(cs).get().wrapping_add(1);
+		GENERATION.borrow(cs).set(generation);
+		NODES.borrow(cs).borrow_mut()[usart as usize] = Some(LinNode {
+			role,
+			step: Step::Wait,
+			publish,
+			receive,
+			sent: [0; MAX_DATA + 1],
+			received: [0; MAX_DATA + 1],
+			generation,
+		});
+	});
+	super::discard_rx(usart);
+	Ok(())
+}
+
+// LIN needs one stop bit and no clock, smartcard, half duplex or IrDA
+fn configure(usart: Usart, length: Option<LinBreakLength>) {
+	super::with_usart(usart, |uart| {
+		let enabled = uart.cr1.read().ue().bit_is_set();
+		uart.cr1.modify(|_, w| w.ue().clear_bit());
+		uart.cr2.modify(|_, w| {
+			let w = w.linen().bit(length.is_some()).lbdie().bit(length.is_some());
+			match length {
+				Some(_) => w.stop().stop1().clken().clear_bit(),
+				None => w,
+			}
+		});
+		uart.cr2.modify(|_, w| w.lbdl().bit(length == Some(LinBreakLength::Bits11)));
+		if length.is_some() {
+			uart.cr3.modify(|_, w| w.scen().clear_bit().hdsel().clear_bit().iren().clear_bit());
+		}
+		uart.icr.write(|w| w.lbdcf().set_bit());
+		uart.cr1.modify(|_, w| w.ue().bit(enabled));
+	});
+}
+
+fn check_frame(frame: &LinFrameDef) -> Result<(), LinError> {
+	if frame.id > MAX_ID {
+		return Err(LinError::InvalidId);
+	}
+	if frame.len == 0 || frame.len as usize > MAX_DATA {
+		return Err(LinError::InvalidLength);
+	}
+	Ok(())
+}
+
+fn check_schedule(schedule: &[LinSlot]) -> Result<(), LinError> {
+	if schedule.is_empty() {
+		return Err(LinError::EmptySchedule);
+	}
+	schedule.iter().try_for_each(|slot| check_frame(&slot.frame))
+}
+
+fn take_break(cs: &CriticalSection, usart: Usart) -> bool {
+	let mut breaks = BREAKS.borrow(cs).get();
+	let seen = breaks[usart as usize];
+	breaks[usart as usize] = false;
+	BREAKS.borrow(cs).set(breaks);
+	seen
+}
+
+fn take_next_schedule(cs: &CriticalSection, usart: Usart) -> Option<&'static [LinSlot]> {
+	let mut all = NEXT_SCHEDULE.borrow(cs).get();
+	let schedule = all[usart as usize].take();
+	NEXT_SCHEDULE.borrow(cs).set(all);
+	schedule
+}
+
+// Break, then the sync field and PID through the TX buffer
+fn send_header(usart: Usart, id: u8) {
+	super::discard_rx(usart);
+	super::with_usart(usart, |uart| uart.rqr.write(|w| w.sbkrq().break_()));
+	// check_schedule() let only valid IDs in
+	let _ = super::write(usart, &[SYNC, pid(id).unwrap_or(0)]);
+}
+
+// Header at the start of each slot, after closing out the last slot's frame
+fn run_schedule(usart: Usart, node: &mut LinNode, now: u32) {
+	let (schedule, index, next_tick, current) = match &mut node.role {
+		Role::Master { schedule, index, next_tick, current } => (schedule, index, next_tick, current),
+		Role::Slave { .. } => return,
+	};
+	if (now.wrapping_sub(*next_tick) as i32) < 0 {
+		return;
+	}
+
+	if current.is_some() {
+		let error = match node.step {
+			Step::Wait => None,
+			Step::Response { frame, count, .. } if frame.direction == LinDirection::Subscribe && count == 0 => Some(LinError::NoResponse),
+			_ => Some(LinError::BitError),
+		};
+		if let Some(error) = error {
+			(node.receive)(usart, Err(error));
+		}
+	}
+
+	if let Some(next) = free(|cs| take_next_schedule(cs, usart)) {
+		*schedule = next;
+		*index = 0;
+	}
+
+	let slot = schedule[*index];
+	*index = (*index + 1) % schedule.len();
+	// Fall back in step if the main loop was held up for more than a slot
+	*next_tick = if now.wrapping_sub(*next_tick) < slot.slot_ms { next_tick.wrapping_add(slot.slot_ms) } else { now.wrapping_add(slot.slot_ms) };
+	*current = Some(slot.frame);
+
+	node.step = Step::Wait;
+	send_header(usart, slot.frame.id);
+}
+
+fn parse(usart: Usart, node: &mut LinNode, byte: u8) {
+	node.step = match node.step {
+		Step::Wait => Step::Wait,
+		// The break itself comes in as a 0x00
+		Step::Sync => match byte {
+			0x00 => Step::Sync,
+			SYNC => Step::Pid,
+			_ => {
+				(node.receive)(usart, Err(LinError::Sync));
+				Step::Wait
+			},
+		},
+		Step::Pid => header(usart, node, byte),
+		Step::Response { frame, pid, count } => {
+			node.received[count] = byte;
+			if count < frame.len as usize {
+				Step::Response { frame, pid, count: count + 1 }
+			}
+			else {
+				response(usart, node, frame, pid);
+				Step::Wait
+			}
+		},
+	};
+}
+
+fn header(usart: Usart, node: &mut LinNode, byte: u8) -> Step {
+	let id = byte & MAX_ID;
+	if pid(id) != Ok(byte) {
+		(node.receive)(usart, Err(LinError::Parity));
+		return Step::Wait;
+	}
+
+	let frame = match node.role {
+		Role::Master { current, .. } => match current {
+			Some(frame) if frame.id == id => frame,
+			_ => {
+				(node.receive)(usart, Err(LinError::BitError));
+				return Step::Wait;
+			},
+		},
+		Role::Slave { frames } => match frames.iter().find(|frame| frame.id == id) {
+			Some(frame) => *frame,
+			None => return Step::Wait,
+		},
+	};
+
+	if frame.direction == LinDirection::Publish {
+		let len = frame.len as usize;
+		(node.publish)(usart, id, &mut node.sent[..len]);
+		node.sent[len] = checksum(frame.checksum, byte, &node.sent[..len]);
+		let _ = super::write(usart, &node.sent[..=len]);
+	}
+
+	Step::Response { frame, pid: byte, count: 0 }
+}
+
+fn response(usart: Usart, node: &mut LinNode, frame: LinFrameDef, pid: u8) {
+	let len = frame.len as usize;
+	let result = match frame.direction {
+		LinDirection::Publish if node.received[..=len] != node.sent[..=len] => Err(LinError::BitError),
+		LinDirection::Publish => return,
+		LinDirection::Subscribe if checksum(frame.checksum, pid, &node.received[..len]) != node.received[len] => Err(LinError::Checksum),
+		LinDirection::Subscribe => {
+			let mut data = [0; MAX_DATA];
+			data[..len].copy_from_slice(&node.received[..len]);
+			Ok(LinFrame { id: frame.id, len: frame.len, data })
+		},
+	};
+
+	(node.receive)(usart, result);
+}
+
+//==============================================================================
+// Task Handler
+//==============================================================================
+// The node is worked on outside the critical section so the callbacks can run,
+// and only put back if a callback didn't stop or restart it. Bytes received
+// so far close out the last frame before the next slot starts.
+pub fn task_handler() {
+	for usart in [Usart::Usart1, Usart::Usart2, Usart::Usart3] {
+		let (mut node, restart) = match free(|cs| NODES.borrow(cs).borrow()[usart as usize].map(|node| (node, take_break(cs, usart)))) {
+			Some(state) => state,
+			None => continue,
+		};
+
+		if restart {
+			node.step = Step::Sync;
+		}
+
+		let mut byte = [0];
+		while super::read(usart, &mut byte) == 1 {
+			parse(usart, &mut node, byte[0]);
+		}
+		run_schedule(usart, &mut node, timer::get_tick());
+
+		free(|cs| if let Some(slot) = NODES.borrow(cs).borrow_mut()[usart as usize].as_mut() {
+			if slot.generation == node.generation {
+				*slot = node;
+			}
+		});
+	}
+}